@@ -11,7 +11,8 @@
 use std::env;
 use std::fs::File;
 use std::io::Write;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -34,8 +35,84 @@ fn main() {
     println!("cargo:rustc-link-arg-bins=-Tlink-rp.x");
     println!("cargo:rustc-link-arg-bins=-Tdefmt.x");
 
-    ::capnpc::CompilerCommand::new()
-        .file("usb_messages.capnp")
-        .run()
-        .expect("compiling schema");
+    write_build_info(out);
+}
+
+// Version, git revision and features of this build, the firmware
+// reports them in the usb descriptor and in the getInfo reply.
+fn write_build_info(out: &PathBuf) {
+    let git = |args: &[&str]| {
+        Command::new("git")
+            .args(args)
+            .output()
+            .ok()
+            .filter(|o| o.status.success())
+            .map(|o| String::from_utf8_lossy(&o.stdout).trim().to_string())
+    };
+
+    let git_hash = git(&["rev-parse", "--short=8", "HEAD"]).unwrap_or("unknown".into());
+    // the firmware is built from this crate and the protocol crate
+    let dirty = git(&["status", "--porcelain", "--", ".", "../minibadge-protocol"])
+        .is_some_and(|s| !s.is_empty());
+
+    // a checkout changes HEAD, a commit moves the branch it points to, in its own file or
+    // in packed-refs, and staging a file changes the index. a missing file would rerun
+    // this script on every build
+    let mut git_files = vec!["HEAD".to_string(), "packed-refs".into(), "index".into()];
+    git_files.extend(git(&["symbolic-ref", "-q", "HEAD"]));
+    for file in git_files {
+        if let Some(path) = git(&["rev-parse", "--git-path", &file]) {
+            if Path::new(&path).exists() {
+                println!("cargo:rerun-if-changed={}", path);
+            }
+        }
+    }
+    // an edit makes the tree dirty before it is staged. memory.x above already turned off
+    // the default of looking at the whole crate, and the target dirs change on every build
+    let sources = [
+        "src",
+        "../minibadge-protocol/src",
+        "../minibadge-protocol/usb_messages.capnp",
+        "../minibadge-protocol/build.rs",
+        "../minibadge-protocol/Cargo.toml",
+    ];
+    for source in sources {
+        println!("cargo:rerun-if-changed={}", source);
+    }
+
+    let version = env::var("CARGO_PKG_VERSION").unwrap();
+    let part = |name: &str, max: u16| env::var(name).unwrap().parse::<u16>().unwrap().min(max);
+    // bcdDevice is 0xJJMN, two digits for the major version and one for minor and patch
+    let major = part("CARGO_PKG_VERSION_MAJOR", 99);
+    let device_release = (major / 10) << 12
+        | (major % 10) << 8
+        | part("CARGO_PKG_VERSION_MINOR", 9) << 4
+        | part("CARGO_PKG_VERSION_PATCH", 9);
+
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| Some(name.strip_prefix("CARGO_FEATURE_")?.to_lowercase()))
+        .collect();
+    features.sort();
+
+    let profile = env::var("PROFILE").unwrap();
+    let product = format!(
+        "Mini Badge {}-{}{}",
+        version,
+        git_hash,
+        if dirty { "+" } else { "" }
+    );
+
+    let mut file = File::create(out.join("build_info.rs")).unwrap();
+    writeln!(file, "pub const VERSION: &str = {:?};", version).unwrap();
+    writeln!(file, "pub const GIT_HASH: &str = {:?};", git_hash).unwrap();
+    writeln!(file, "pub const GIT_DIRTY: bool = {};", dirty).unwrap();
+    writeln!(file, "pub const PROFILE: &str = {:?};", profile).unwrap();
+    writeln!(file, "pub const FEATURES: &str = {:?};", features.join(",")).unwrap();
+    writeln!(
+        file,
+        "pub const DEVICE_RELEASE: u16 = {:#06x};",
+        device_release
+    )
+    .unwrap();
+    writeln!(file, "pub const PRODUCT: &str = {:?};", product).unwrap();
 }
@@ -0,0 +1,414 @@
+// messages between badges, over infrared
+//
+// a frame is a 6 ms mark and a 3 ms space, then the bytes lsb first: each bit is a 500 us mark
+// followed by 500 us of silence for a zero or 1500 us for a one, and a last mark ends the frame.
+// the bytes are the id of the sender (little endian), the id of the message, the hops left,
+// the kind of payload, the payload and a crc8 of all the rest. no remote protocol has such a header
+//
+// a badge in relay mode sends again the scenes, times and texts it receives, with its own id
+// as sender and one hop less, so they reach the badges out of sight. the id of the message stays,
+// and the badges skip the messages they already heard
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use embassy_rp::flash;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{Deque, String, Vec};
+use infrared::sender::ProtocolEncoder;
+use infrared::Protocol;
+use log::{debug, warn};
+use minibadge_protocol::MAX_BADGE_TEXT;
+use rand::rngs::SmallRng;
+use rand::Rng;
+
+use crate::storage::{self, BadgeFlash, Setting};
+use crate::{irecho, timesync};
+
+const HEADER_MARK: u32 = 6000;
+const HEADER_SPACE: u32 = 3000;
+const UNIT: u32 = 500;
+
+// sender, message id, hops, kind, the longest payload and the crc
+const MAX_FRAME: usize = 4 + 2 + 1 + 1 + MAX_BADGE_TEXT + 1;
+
+// the leading zero, the header, two pulses a bit and the last mark
+pub const MAX_PULSES: usize = 3 + MAX_FRAME * 16 + 1;
+
+// the link is busy until the receiver has been quiet this long, longer than any space of a frame
+const QUIET: Duration = Duration::from_millis(30);
+
+// the backoff waits a random number of slots, from a window that doubles while the link is busy
+const SLOT: Duration = Duration::from_millis(10);
+const FIRST_WINDOW: u32 = 4;
+const ATTEMPTS: u32 = 6;
+
+// hops of the messages of this badge, so a message crosses at most three relays
+const HOPS: u8 = 3;
+
+// messages heard this recently are not handled nor relayed again
+const RECENT: usize = 32;
+
+// from the unique id of the flash, see set_id
+static ID: AtomicU32 = AtomicU32::new(0);
+
+// messages sent by this badge, mixed with the id for the message ids
+static SENT: AtomicU32 = AtomicU32::new(0);
+
+static RECENT_MESSAGES: Mutex<CriticalSectionRawMutex, RefCell<Deque<u16, RECENT>>> =
+    Mutex::new(RefCell::new(Deque::new()));
+
+static RELAY: AtomicBool = AtomicBool::new(false);
+
+// uptime in milliseconds of the last edge of the receiver
+static LAST_HEARD: AtomicU32 = AtomicU32::new(0);
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Payload {
+    Hello,
+    Scene(u8),
+    Text(String<MAX_BADGE_TEXT>),
+    // animation time in milliseconds, and the badge that leads the time
+    Time(u32, u32),
+    // just the id of the sender, for the contacts
+    Beacon,
+}
+
+impl Payload {
+    // the backoff takes a while, the time is read again when the frame leaves
+    pub fn stamped(self) -> Self {
+        match self {
+            Payload::Time(_, leader) => Payload::Time(timesync::millis(), leader),
+            payload => payload,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    // the badge that sent the frame, a relay for the messages of others
+    pub badge: u32,
+    pub id: u16,
+    pub hops: u8,
+    pub payload: Payload,
+}
+
+impl Message {
+    // a message of this badge
+    pub fn new(payload: Payload) -> Self {
+        // only the blaster task makes messages, and the m0+ has no fetch_add
+        let sent = SENT.load(Ordering::Relaxed);
+        SENT.store(sent.wrapping_add(1), Ordering::Relaxed);
+        // scrambled, so the ids of two badges don't run into each other
+        let id = ((id() ^ sent).wrapping_mul(0x9e37_79b1) >> 16) as u16;
+        // the badges relaying it back are not heard as a new message
+        first_heard(id);
+
+        Self {
+            badge: self::id(),
+            id,
+            hops: HOPS,
+            payload,
+        }
+    }
+
+    // the message sent again by this badge, None when it can't go farther
+    pub fn relayed(&self) -> Option<Self> {
+        let relayable = matches!(
+            self.payload,
+            Payload::Scene(_) | Payload::Time(..) | Payload::Text(_)
+        );
+        if !relayable || self.hops == 0 {
+            return None;
+        }
+
+        Some(Self {
+            badge: id(),
+            hops: self.hops - 1,
+            ..self.clone()
+        })
+    }
+
+    // how long the frame takes to send, from the start of the header to the end of the last mark
+    pub fn airtime(&self) -> Duration {
+        let bytes = self.to_bytes();
+        let ones: u32 = bytes.iter().map(|b| b.count_ones()).sum();
+        let bits = bytes.len() as u32 * 8;
+        let us = HEADER_MARK + HEADER_SPACE + bits * 2 * UNIT + ones * 2 * UNIT + UNIT;
+
+        Duration::from_micros(us.into())
+    }
+
+    fn to_bytes(&self) -> Vec<u8, MAX_FRAME> {
+        let time;
+        let (kind, payload): (u8, &[u8]) = match &self.payload {
+            Payload::Hello => (0, &[]),
+            Payload::Scene(scene) => (1, core::slice::from_ref(scene)),
+            Payload::Text(text) => (2, text.as_bytes()),
+            Payload::Time(ms, leader) => {
+                time = [ms.to_le_bytes(), leader.to_le_bytes()];
+                (3, time.as_flattened())
+            }
+            Payload::Beacon => (4, &[]),
+        };
+
+        // the longest payload fits
+        let mut bytes = Vec::new();
+        let _ = bytes.extend_from_slice(&self.badge.to_le_bytes());
+        let _ = bytes.extend_from_slice(&self.id.to_le_bytes());
+        let _ = bytes.push(self.hops);
+        let _ = bytes.push(kind);
+        let _ = bytes.extend_from_slice(payload);
+        let _ = bytes.push(crc8(&bytes));
+
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let [frame @ .., crc] = bytes else {
+            return None;
+        };
+        if crc8(frame) != *crc {
+            return None;
+        }
+
+        let [a, b, c, d, id_low, id_high, hops, kind, payload @ ..] = frame else {
+            return None;
+        };
+
+        let payload = match (kind, payload) {
+            (0, []) => Payload::Hello,
+            (1, [scene]) => Payload::Scene(*scene),
+            (2, text) => {
+                let text = core::str::from_utf8(text).ok().filter(|t| t.is_ascii())?;
+                Payload::Text(String::try_from(text).ok()?)
+            }
+            (3, [a, b, c, d, e, f, g, h]) => Payload::Time(
+                u32::from_le_bytes([*a, *b, *c, *d]),
+                u32::from_le_bytes([*e, *f, *g, *h]),
+            ),
+            (4, []) => Payload::Beacon,
+            _ => return None,
+        };
+
+        Some(Self {
+            badge: u32::from_le_bytes([*a, *b, *c, *d]),
+            id: u16::from_le_bytes([*id_low, *id_high]),
+            hops: *hops,
+            payload,
+        })
+    }
+}
+
+// crc-8 with the 0x07 polynomial
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0, |crc, byte| {
+        (0..8).fold(crc ^ byte, |crc, _| {
+            if crc & 0x80 != 0 {
+                crc << 1 ^ 0x07
+            } else {
+                crc << 1
+            }
+        })
+    })
+}
+
+// the 8 bytes of the flash unique id, folded
+pub fn set_id(uid: &[u8; 8]) {
+    let (low, high) = uid.split_at(4);
+    let id =
+        u32::from_le_bytes(low.try_into().unwrap()) ^ u32::from_le_bytes(high.try_into().unwrap());
+
+    ID.store(id, Ordering::Relaxed);
+}
+
+pub fn id() -> u32 {
+    ID.load(Ordering::Relaxed)
+}
+
+// true the first time a message is heard, relayed copies come back with the same id
+pub fn first_heard(id: u16) -> bool {
+    RECENT_MESSAGES.lock(|r| {
+        let mut recent = r.borrow_mut();
+        if recent.iter().any(|&heard| heard == id) {
+            return false;
+        }
+
+        if recent.is_full() {
+            recent.pop_front();
+        }
+        let _ = recent.push_back(id);
+        true
+    })
+}
+
+pub fn set_relay(relay: bool) {
+    RELAY.store(relay, Ordering::Relaxed);
+    storage::request_save(Setting::Relay);
+}
+
+pub fn relaying() -> bool {
+    RELAY.load(Ordering::Relaxed)
+}
+
+pub fn load(flash: &mut BadgeFlash) {
+    let mut buf = [0; 1];
+    if let Some(1) = storage::load(flash, Setting::Relay, &mut buf) {
+        RELAY.store(buf[0] != 0, Ordering::Relaxed);
+    }
+}
+
+// called by the storage task
+pub fn save(flash: &mut BadgeFlash) -> Result<(), flash::Error> {
+    storage::save(flash, Setting::Relay, &[relaying() as u8])
+}
+
+// the receiver calls this at every edge, of any protocol
+pub fn heard() {
+    LAST_HEARD.store(Instant::now().as_millis() as u32, Ordering::Relaxed);
+}
+
+fn quiet() -> bool {
+    let now = Instant::now().as_millis() as u32;
+    now.wrapping_sub(LAST_HEARD.load(Ordering::Relaxed)) >= QUIET.as_millis() as u32
+}
+
+// waits for the link to be quiet, so two badges don't talk over each other
+// the receiver can't hear while we send, the random backoff keeps badges that heard the
+// same frame from all answering at the end of it
+pub async fn wait_clear(rng: &mut SmallRng) {
+    for attempt in 0..ATTEMPTS {
+        let slots = rng.gen_range(0..FIRST_WINDOW << attempt);
+        Timer::after(SLOT * slots).await;
+
+        if quiet() {
+            return;
+        }
+        debug!("The link is busy, backing off");
+    }
+
+    // a remote held down would keep us quiet forever
+    warn!("The link is still busy, sending anyway");
+}
+
+pub struct BadgeLink;
+
+impl Protocol for BadgeLink {
+    type Cmd = Message;
+}
+
+impl<const F: u32> ProtocolEncoder<F> for BadgeLink {
+    // unit, header mark and header space in ticks
+    type EncoderData = [u32; 3];
+    const DATA: [u32; 3] = [
+        UNIT * (F / 1000) / 1000,
+        HEADER_MARK * (F / 1000) / 1000,
+        HEADER_SPACE * (F / 1000) / 1000,
+    ];
+
+    fn encode(message: &Message, b: &mut [u32]) -> usize {
+        let [unit, header_mark, header_space] = <Self as ProtocolEncoder<F>>::DATA;
+
+        b[0] = 0;
+        b[1] = header_mark;
+        b[2] = header_space;
+        let mut i = 3;
+
+        for byte in message.to_bytes() {
+            for bit in 0..8 {
+                b[i] = unit;
+                b[i + 1] = if byte & (1 << bit) != 0 {
+                    3 * unit
+                } else {
+                    unit
+                };
+                i += 2;
+            }
+        }
+
+        // the length of the last space is only known with a mark after it
+        b[i] = unit;
+        i + 1
+    }
+}
+
+#[derive(Default)]
+pub struct Decoder {
+    bytes: [u8; MAX_FRAME],
+    // None after the header mark, until its space
+    bits: Option<usize>,
+    receiving: bool,
+    // a header was received, and the frame not decoded yet
+    started: bool,
+}
+
+impl Decoder {
+    // mark is true when the carrier starts, dt the microseconds since the previous edge
+    pub fn edge(&mut self, mark: bool, dt: u32) {
+        let unit = UNIT / 2..2 * UNIT;
+
+        match (mark, self.bits) {
+            // dt is a mark
+            (false, _) if (HEADER_MARK * 5 / 6..HEADER_MARK * 7 / 6).contains(&dt) => {
+                if self.started {
+                    irecho::dropped();
+                }
+                self.started = true;
+                self.receiving = true;
+                self.bits = None;
+            }
+            _ if !self.receiving => {}
+            (false, _) => self.receiving = unit.contains(&dt),
+            // dt is a space
+            (true, None) => {
+                self.receiving = (HEADER_SPACE * 2 / 3..HEADER_SPACE * 4 / 3).contains(&dt);
+                self.bytes = [0; MAX_FRAME];
+                self.bits = Some(0);
+            }
+            (true, Some(bits)) => {
+                let one = match dt {
+                    _ if unit.contains(&dt) => false,
+                    _ if (2 * UNIT..4 * UNIT).contains(&dt) => true,
+                    _ => {
+                        self.receiving = false;
+                        return;
+                    }
+                };
+
+                if bits == MAX_FRAME * 8 {
+                    self.receiving = false;
+                    return;
+                }
+                if one {
+                    self.bytes[bits / 8] |= 1 << (bits % 8);
+                }
+                self.bits = Some(bits + 1);
+            }
+        }
+    }
+
+    // a frame is still being received, call timeout when no edge comes within FRAME_END
+    pub fn pending(&self) -> bool {
+        self.receiving
+    }
+
+    pub fn timeout(&mut self) -> Option<Message> {
+        let receiving = core::mem::take(&mut self.receiving);
+        let started = core::mem::take(&mut self.started);
+
+        let message = match self.bits {
+            Some(bits) if receiving && bits % 8 == 0 => {
+                Message::from_bytes(&self.bytes[..bits / 8])
+            }
+            _ => None,
+        };
+
+        // cut, or garbled by another badge talking over it
+        if started && message.is_none() {
+            irecho::dropped();
+        }
+        message
+    }
+}
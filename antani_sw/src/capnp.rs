@@ -1,75 +1,232 @@
-use capnp::{message::ReaderOptions, serialize};
+use core::sync::atomic::{AtomicU16, Ordering};
+
+use capnp::{
+    message::{self, SingleSegmentAllocator},
+    serialize,
+};
+use minibadge_protocol::{
+    BadgeMessage, Command, Contact, IrCommand, MidiEvent, Rgb, MAX_CONTACTS, MAX_IR_RAW,
+    PROTOCOL_VERSION,
+};
 
 use crate::{
-    rgbeffects::{ColorPalette, RenderCommand},
+    badgelink::Payload,
+    build_info,
+    logging::{LogConfig, LogFormat},
+    midi::{MidiOutEvent, MidiOutKind, MidiOutMapping},
+    rgbeffects::{AudioLevels, ColorPalette, RenderCommand},
     usb_messages_capnp, LedPixel, RawFramebuffer, TaskCommand,
 };
 
-pub fn deserialize_message(data: &mut &[u8]) -> Result<TaskCommand, capnp::Error> {
-    log::info!("Deserializing message of length {}", data.len());
-
-    let reader = serialize::read_message_from_flat_slice_no_alloc(data, ReaderOptions::new())?;
-
-    let badgebound = reader.get_root::<usb_messages_capnp::badge_bound::Reader>()?;
-
-    match badgebound.which()? {
-        usb_messages_capnp::badge_bound::SetFrameBuffer(set_fb) => {
+// the command for the tasks, with no side effects: the settings kept in shared tables are
+// stored by the usb task before, see usb::task_command
+pub fn task_command(command: Command) -> TaskCommand {
+    match command {
+        Command::SetFrameBuffer(pixels) => {
             let mut ret: RawFramebuffer = RawFramebuffer::new();
 
-            let pixels = set_fb?.get_pixels()?;
-            for i in 0..9 {
-                let pixel = pixels.get(i);
-
-                let x = i % 3;
-                let y = i / 3;
-
-                ret.set_pixel(
-                    x as usize,
-                    y as usize,
-                    LedPixel {
-                        r: pixel.get_r(),
-                        g: pixel.get_g(),
-                        b: pixel.get_b(),
-                        ..Default::default()
-                    },
-                );
+            for (i, pixel) in pixels.iter().enumerate() {
+                ret.set_pixel(i % 3, i / 3, led_pixel(pixel));
             }
 
-            return Ok(TaskCommand::SetWorkingMode(
-                crate::WorkingMode::RawFramebuffer(ret),
-            ));
+            TaskCommand::SetWorkingMode(crate::WorkingMode::RawFramebuffer(ret))
         }
-        usb_messages_capnp::badge_bound::SetSolidColor(color) => {
-            let color = color?;
-
-            let color = LedPixel {
-                r: color.get_r(),
-                g: color.get_g(),
-                b: color.get_b(),
-                ..Default::default()
-            };
-
+        Command::SetSolidColor(color) => {
             let scene = RenderCommand {
-                color: ColorPalette::Solid(color),
+                color: ColorPalette::Solid(led_pixel(&color)),
                 ..Default::default()
             };
 
-            return Ok(TaskCommand::SetWorkingMode(crate::WorkingMode::Special(
-                scene,
-            )));
+            TaskCommand::SetWorkingMode(crate::WorkingMode::Special(scene))
         }
-        usb_messages_capnp::badge_bound::Which::SendNecCommand(command) => {
-            let command = command?;
-
-            let address = command.get_address();
-            let _command = command.get_command();
-            let repeat = command.get_repeat();
+        Command::SendNec {
+            address,
+            command,
+            repeat,
+        } => TaskCommand::SendIr(
+            IrCommand {
+                protocol: usb_messages_capnp::IrProtocol::Nec,
+                address: address.into(),
+                command: command.into(),
+                repeat,
+            },
+            None,
+        ),
+        Command::SetAudioLevels { bands, beat } => {
+            TaskCommand::SetAudioLevels(AudioLevels { bands, beat })
+        }
+        Command::SetMidiMapping {
+            short_press,
+            long_press,
+            ir_received,
+        } => TaskCommand::SetMidiMapping(MidiOutMapping {
+            short_press: midi_event(&short_press),
+            long_press: midi_event(&long_press),
+            ir_received: midi_event(&ir_received),
+        }),
+        Command::SetScene(scene) => TaskCommand::SetScene(scene),
+        Command::SetLogConfig(config) => TaskCommand::SetLogConfig(LogConfig {
+            level: log_level(config.level),
+            modules: config
+                .modules
+                .into_iter()
+                .map(|(module, level)| (module, log_level(level)))
+                .collect(),
+            format: match config.format {
+                usb_messages_capnp::LogFormat::Text => LogFormat::Text,
+                usb_messages_capnp::LogFormat::Json => LogFormat::Json,
+            },
+        }),
+        Command::RebootToBootloader => TaskCommand::RebootToBootloader,
+        Command::Reboot => TaskCommand::Reboot,
+        Command::GetInfo => TaskCommand::GetInfo,
+        Command::SetIrBindings(_) | Command::ResetIrBindings => TaskCommand::None,
+        Command::LearnIrBinding(action) => TaskCommand::LearnIr(action),
+        Command::SendIr(command, carrier) => TaskCommand::SendIr(command, carrier),
+        Command::CaptureIrRaw => TaskCommand::CaptureIrRaw,
+        // the frame waits in irprotocols
+        Command::SendIrRaw(_, carrier) => TaskCommand::SendIrRaw(carrier),
+        Command::SendBadgeMessage(message) => match message {
+            BadgeMessage::Hello => TaskCommand::SendBadge(Payload::Hello),
+            // main_tsk knows the scene
+            BadgeMessage::ShareScene => TaskCommand::ShareScene,
+            BadgeMessage::Text(text) => TaskCommand::SendBadge(Payload::Text(text)),
+            BadgeMessage::SyncTime => {
+                let time = Payload::Time(crate::timesync::millis(), crate::badgelink::id());
+                TaskCommand::SendBadge(time)
+            }
+        },
+        Command::GetContacts => TaskCommand::GetContacts,
+        Command::SetRelay(_) | Command::SetButtonAction(_) | Command::None => TaskCommand::None,
+    }
+}
 
-            return Ok(TaskCommand::SendIrNec(address, _command, repeat));
+// the last version that did not match ours, to warn once and not for every message
+static HOST_PROTOCOL_VERSION: AtomicU16 = AtomicU16::new(PROTOCOL_VERSION);
+
+pub fn check_protocol_version(version: u16) {
+    if version != HOST_PROTOCOL_VERSION.load(Ordering::Relaxed) {
+        HOST_PROTOCOL_VERSION.store(version, Ordering::Relaxed);
+        if version != PROTOCOL_VERSION {
+            log::warn!(
+                "The host speaks protocol version {}, the badge {}",
+                version,
+                PROTOCOL_VERSION
+            );
         }
+    }
+}
+
+// a contact takes two words in the list
+const CONTACTS_SIZE: usize = 16 * MAX_CONTACTS + 64;
+
+// room for the largest reply
+pub const REPLY_SIZE: usize = if CONTACTS_SIZE > 2 * MAX_IR_RAW + 64 {
+    CONTACTS_SIZE
+} else {
+    2 * MAX_IR_RAW + 64
+};
+
+// the builder wants its segment aligned to words
+#[repr(C, align(8))]
+struct Segment<const N: usize>([u8; N]);
+
+// writes the HostBound info reply in out, returns its length
+pub fn serialize_info(serial: &str, out: &mut [u8]) -> Result<usize, capnp::Error> {
+    let mut segment = Segment([0; 256]);
+    let mut builder = message::Builder::new(SingleSegmentAllocator::new(&mut segment.0));
+
+    let mut info = builder
+        .init_root::<usb_messages_capnp::host_bound::Builder>()
+        .init_info();
+    info.set_version(build_info::VERSION);
+    info.set_git_hash(build_info::GIT_HASH);
+    info.set_dirty(build_info::GIT_DIRTY);
+    info.set_profile(build_info::PROFILE);
+    info.set_features(build_info::FEATURES);
+    info.set_protocol_version(PROTOCOL_VERSION);
+    info.set_serial(serial);
+    let (echoes, dropped) = crate::irecho::counters();
+    info.set_ir_echoes(echoes);
+    info.set_ir_dropped(dropped);
+
+    let size = out.len();
+    let mut writer = &mut out[..];
+    serialize::write_message(&mut writer, &builder)?;
+
+    Ok(size - writer.len())
+}
+
+// writes the HostBound irRaw reply in out, returns its length
+pub fn serialize_ir_raw(durations: &[u16], out: &mut [u8]) -> Result<usize, capnp::Error> {
+    let mut segment = Segment([0; 2 * MAX_IR_RAW + 64]);
+    let mut builder = message::Builder::new(SingleSegmentAllocator::new(&mut segment.0));
+
+    let raw = builder
+        .init_root::<usb_messages_capnp::host_bound::Builder>()
+        .init_ir_raw();
+    minibadge_protocol::write_ir_raw(raw, durations);
+
+    let size = out.len();
+    let mut writer = &mut out[..];
+    serialize::write_message(&mut writer, &builder)?;
+
+    Ok(size - writer.len())
+}
+
+// writes the HostBound contacts reply in out, returns its length
+pub fn serialize_contacts(contacts: &[Contact], out: &mut [u8]) -> Result<usize, capnp::Error> {
+    let mut segment = Segment([0; CONTACTS_SIZE]);
+    let mut builder = message::Builder::new(SingleSegmentAllocator::new(&mut segment.0));
+
+    let list = builder
+        .init_root::<usb_messages_capnp::host_bound::Builder>()
+        .init_contacts();
+    minibadge_protocol::write_contacts(list, contacts);
 
-        usb_messages_capnp::badge_bound::Which::Null(_) => {}
+    let size = out.len();
+    let mut writer = &mut out[..];
+    serialize::write_message(&mut writer, &builder)?;
+
+    Ok(size - writer.len())
+}
+
+fn led_pixel(color: &Rgb) -> LedPixel {
+    LedPixel {
+        r: color.r,
+        g: color.g,
+        b: color.b,
+        ..Default::default()
     }
+}
 
-    Ok(TaskCommand::None)
+fn midi_event(event: &MidiEvent) -> MidiOutEvent {
+    use usb_messages_capnp::midi_event::Kind;
+
+    let kind = match event.kind {
+        Kind::None => MidiOutKind::None,
+        Kind::Note => MidiOutKind::Note,
+        Kind::ControlChange => MidiOutKind::ControlChange,
+        Kind::ProgramChange => MidiOutKind::ProgramChange,
+    };
+
+    MidiOutEvent {
+        kind,
+        channel: event.channel,
+        number: event.number,
+        value: event.value,
+    }
+}
+
+fn log_level(level: usb_messages_capnp::LogLevel) -> log::LevelFilter {
+    use usb_messages_capnp::LogLevel;
+
+    match level {
+        LogLevel::Off => log::LevelFilter::Off,
+        LogLevel::Error => log::LevelFilter::Error,
+        LogLevel::Warn => log::LevelFilter::Warn,
+        LogLevel::Info => log::LevelFilter::Info,
+        LogLevel::Debug => log::LevelFilter::Debug,
+        LogLevel::Trace => log::LevelFilter::Trace,
+    }
 }
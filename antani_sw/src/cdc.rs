@@ -0,0 +1,157 @@
+// the control port, a cdc acm serial port with a name
+//
+// the cdc class of embassy-usb leaves the interface strings empty, so the host can't tell its
+// ports apart but by number. the logger needs that class, so only the control port is built
+// here, with its name in the interface strings for minibadge-cli to find it
+
+use embassy_usb::control::{InResponse, OutResponse, Recipient, Request, RequestType};
+use embassy_usb::driver::{Driver, Endpoint, EndpointError, EndpointIn, EndpointOut};
+use embassy_usb::types::{InterfaceNumber, StringIndex};
+use embassy_usb::{Builder, Handler};
+
+// minibadge-cli looks for this name, keep them in sync
+pub const CONTROL_NAME: &str = "Minibadge control";
+
+const USB_CLASS_CDC: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const CDC_SUBCLASS_ACM: u8 = 0x02;
+const CDC_PROTOCOL_NONE: u8 = 0x00;
+
+const CS_INTERFACE: u8 = 0x24;
+const CDC_TYPE_HEADER: u8 = 0x00;
+const CDC_TYPE_ACM: u8 = 0x02;
+const CDC_TYPE_UNION: u8 = 0x06;
+
+const REQ_SEND_ENCAPSULATED_COMMAND: u8 = 0x00;
+const REQ_SET_LINE_CODING: u8 = 0x20;
+const REQ_GET_LINE_CODING: u8 = 0x21;
+const REQ_SET_CONTROL_LINE_STATE: u8 = 0x22;
+
+// 115200 8n1, the port is no uart and only gives back what the host set
+const LINE_CODING: [u8; 7] = [0x00, 0xc2, 0x01, 0x00, 0, 0, 8];
+
+pub struct State {
+    control: Option<Control>,
+}
+
+impl State {
+    pub const fn new() -> Self {
+        Self { control: None }
+    }
+}
+
+struct Control {
+    comm_if: InterfaceNumber,
+    name: StringIndex,
+    line_coding: [u8; 7],
+}
+
+impl Control {
+    fn is_ours(&self, req: &Request) -> bool {
+        (req.request_type, req.recipient, req.index)
+            == (
+                RequestType::Class,
+                Recipient::Interface,
+                self.comm_if.0 as u16,
+            )
+    }
+}
+
+impl Handler for Control {
+    fn reset(&mut self) {
+        self.line_coding = LINE_CODING;
+    }
+
+    fn control_out(&mut self, req: Request, data: &[u8]) -> Option<OutResponse> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        match req.request {
+            // accepted and ignored, like the cdc class does
+            REQ_SEND_ENCAPSULATED_COMMAND | REQ_SET_CONTROL_LINE_STATE => {
+                Some(OutResponse::Accepted)
+            }
+            REQ_SET_LINE_CODING if data.len() >= 7 => {
+                self.line_coding.copy_from_slice(&data[..7]);
+                Some(OutResponse::Accepted)
+            }
+            _ => Some(OutResponse::Rejected),
+        }
+    }
+
+    fn control_in<'a>(&'a mut self, req: Request, _buf: &'a mut [u8]) -> Option<InResponse<'a>> {
+        if !self.is_ours(&req) {
+            return None;
+        }
+
+        match req.request {
+            REQ_GET_LINE_CODING if req.length == 7 => Some(InResponse::Accepted(&self.line_coding)),
+            _ => Some(InResponse::Rejected),
+        }
+    }
+
+    fn get_string(&mut self, index: StringIndex, _lang_id: u16) -> Option<&str> {
+        (index == self.name).then_some(CONTROL_NAME)
+    }
+}
+
+// the packets follow the rules of the cdc class: reads of a whole packet, and a short
+// packet to end a transfer
+pub struct ControlPort<'d, D: Driver<'d>> {
+    _comm_ep: D::EndpointIn,
+    read_ep: D::EndpointOut,
+    write_ep: D::EndpointIn,
+}
+
+impl<'d, D: Driver<'d>> ControlPort<'d, D> {
+    pub fn new(builder: &mut Builder<'d, D>, state: &'d mut State, max_packet_size: u16) -> Self {
+        let name = builder.string();
+        let mut func = builder.function(USB_CLASS_CDC, CDC_SUBCLASS_ACM, CDC_PROTOCOL_NONE);
+
+        let mut iface = func.interface();
+        let comm_if = iface.interface_number();
+        let data_if = u8::from(comm_if) + 1;
+        let mut alt = iface.alt_setting(
+            USB_CLASS_CDC,
+            CDC_SUBCLASS_ACM,
+            CDC_PROTOCOL_NONE,
+            Some(name),
+        );
+        // cdc 1.10, and the acm requests: line coding, control line state and serial state
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_HEADER, 0x10, 0x01]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_ACM, 0x02]);
+        alt.descriptor(CS_INTERFACE, &[CDC_TYPE_UNION, comm_if.into(), data_if]);
+        let comm_ep = alt.endpoint_interrupt_in(8, 255);
+
+        let mut iface = func.interface();
+        let mut alt = iface.alt_setting(USB_CLASS_CDC_DATA, 0x00, CDC_PROTOCOL_NONE, Some(name));
+        let read_ep = alt.endpoint_bulk_out(max_packet_size);
+        let write_ep = alt.endpoint_bulk_in(max_packet_size);
+        drop(func);
+
+        builder.handler(state.control.insert(Control {
+            comm_if,
+            name,
+            line_coding: LINE_CODING,
+        }));
+
+        Self {
+            _comm_ep: comm_ep,
+            read_ep,
+            write_ep,
+        }
+    }
+
+    pub async fn wait_connection(&mut self) {
+        self.read_ep.wait_enabled().await;
+    }
+
+    pub async fn read_packet(&mut self, data: &mut [u8]) -> Result<usize, EndpointError> {
+        self.read_ep.read(data).await
+    }
+
+    pub async fn write_packet(&mut self, data: &[u8]) -> Result<(), EndpointError> {
+        self.write_ep.write(data).await
+    }
+}
@@ -0,0 +1,152 @@
+// the badges we met over infrared, kept in flash for the camp game
+//
+// every badge tells its id now and then, any message of another badge counts as meeting it
+
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_rp::flash;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use heapless::Vec;
+use log::{error, info};
+use minibadge_protocol::{Contact, MAX_CONTACTS};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::storage::{self, BadgeFlash, Setting};
+use crate::{badgelink, MegaPublisher, TaskCommand};
+
+// a badge heard again within this time is the same meeting
+const MEETING: Duration = Duration::from_secs(10 * 60);
+
+// how often we tell our id, plus up to the jitter so the badges don't stay in step
+const BEACON_PERIOD: Duration = Duration::from_secs(60);
+const BEACON_JITTER_MS: u64 = 10_000;
+
+// the counts of the badges we already know are written at most this often, to spare the flash
+const SAVE_PERIOD: Duration = Duration::from_secs(10 * 60);
+
+// id, first seen and count, little endian
+const RECORD_LEN: usize = 10;
+
+struct Entry {
+    contact: Contact,
+    // None until we hear it again after a restart
+    last_seen: Option<Instant>,
+}
+
+static CONTACTS: Mutex<CriticalSectionRawMutex, RefCell<Vec<Entry, MAX_CONTACTS>>> =
+    Mutex::new(RefCell::new(Vec::new()));
+
+// counts not saved yet
+static CHANGED: AtomicBool = AtomicBool::new(false);
+
+pub fn load(flash: &mut BadgeFlash) {
+    let mut buf = [0; MAX_CONTACTS * RECORD_LEN];
+    let Some(len) = storage::load(flash, Setting::Contacts, &mut buf) else {
+        return;
+    };
+
+    let contacts = buf[..len]
+        .chunks_exact(RECORD_LEN)
+        .map(|record| Entry {
+            contact: Contact {
+                badge: u32::from_le_bytes([record[0], record[1], record[2], record[3]]),
+                first_seen: u32::from_le_bytes([record[4], record[5], record[6], record[7]]),
+                count: u16::from_le_bytes([record[8], record[9]]),
+            },
+            last_seen: None,
+        })
+        .collect();
+
+    CONTACTS.lock(|c| *c.borrow_mut() = contacts);
+}
+
+// a message of another badge was received, true when we never met it
+pub fn met(badge: u32) -> bool {
+    let now = Instant::now();
+
+    // None when there is no room for a new badge
+    let new = CONTACTS.lock(|c| {
+        let mut contacts = c.borrow_mut();
+
+        if let Some(entry) = contacts.iter_mut().find(|e| e.contact.badge == badge) {
+            if entry.last_seen.map_or(true, |seen| now - seen >= MEETING) {
+                entry.contact.count = entry.contact.count.saturating_add(1);
+                CHANGED.store(true, Ordering::Relaxed);
+            }
+            entry.last_seen = Some(now);
+            return Some(false);
+        }
+
+        let entry = Entry {
+            contact: Contact {
+                badge,
+                first_seen: now.as_secs() as u32,
+                count: 1,
+            },
+            last_seen: Some(now),
+        };
+        contacts.push(entry).ok().map(|_| true)
+    });
+
+    match new {
+        Some(true) => {
+            info!("Met badge {:08x}", badge);
+            storage::request_save(Setting::Contacts);
+        }
+        Some(false) => {}
+        // a badge we can't remember would be new every time
+        None => error!("No room for badge {:08x} in the contacts", badge),
+    }
+
+    new == Some(true)
+}
+
+pub fn list() -> Vec<Contact, MAX_CONTACTS> {
+    CONTACTS.lock(|c| c.borrow().iter().map(|e| e.contact).collect())
+}
+
+// called by the storage task
+pub fn save(flash: &mut BadgeFlash) -> Result<(), flash::Error> {
+    CHANGED.store(false, Ordering::Relaxed);
+
+    let mut buf = [0; MAX_CONTACTS * RECORD_LEN];
+    let len = CONTACTS.lock(|c| {
+        let contacts = c.borrow();
+
+        for (record, entry) in buf.chunks_exact_mut(RECORD_LEN).zip(contacts.iter()) {
+            let contact = &entry.contact;
+            record[..4].copy_from_slice(&contact.badge.to_le_bytes());
+            record[4..8].copy_from_slice(&contact.first_seen.to_le_bytes());
+            record[8..].copy_from_slice(&contact.count.to_le_bytes());
+        }
+
+        contacts.len() * RECORD_LEN
+    });
+
+    storage::save(flash, Setting::Contacts, &buf[..len])
+}
+
+// tells our id to the badges around, and saves the counts now and then
+#[embassy_executor::task]
+pub async fn beacon_task(publisher: MegaPublisher) {
+    let mut rng = SmallRng::seed_from_u64(u64::from(badgelink::id()) << 32);
+    let mut last_save = Instant::now();
+
+    loop {
+        let jitter = Duration::from_millis(rng.gen_range(0..BEACON_JITTER_MS));
+        Timer::after(BEACON_PERIOD + jitter).await;
+
+        publisher
+            .publish(TaskCommand::SendBadge(badgelink::Payload::Beacon))
+            .await;
+
+        if CHANGED.load(Ordering::Relaxed) && last_save.elapsed() >= SAVE_PERIOD {
+            storage::request_save(Setting::Contacts);
+            last_save = Instant::now();
+        }
+    }
+}
@@ -0,0 +1,119 @@
+// the badge as a usb keyboard and media remote
+//
+// one hid interface with two reports: a boot-like keyboard report with id 1 and a consumer
+// control report with id 2, for the media keys the hosts don't take from a keyboard
+
+use embassy_rp::usb::{Driver, Instance};
+use embassy_time::{Duration, Timer};
+use embassy_usb::class::hid::HidWriter;
+use embassy_usb::driver::EndpointError;
+use heapless::{String, Vec};
+use minibadge_protocol::{ascii_key, CHORD_LEN, MACRO_LEN, MAX_HID_TEXT};
+
+const KEYBOARD_REPORT: u8 = 1;
+const CONSUMER_REPORT: u8 = 2;
+
+// report id, modifiers, reserved and six keys
+pub const REPORT_LEN: usize = 9;
+
+// how long a key stays pressed, and released before the next one
+const KEY_TIME: Duration = Duration::from_millis(30);
+
+#[rustfmt::skip]
+pub const REPORT_DESCRIPTOR: &[u8] = &[
+    0x05, 0x01,         // usage page (generic desktop)
+    0x09, 0x06,         // usage (keyboard)
+    0xa1, 0x01,         // collection (application)
+    0x85, KEYBOARD_REPORT, //   report id
+    0x05, 0x07,         //   usage page (keyboard)
+    0x19, 0xe0,         //   usage minimum (left control)
+    0x29, 0xe7,         //   usage maximum (right gui)
+    0x15, 0x00,         //   logical minimum (0)
+    0x25, 0x01,         //   logical maximum (1)
+    0x75, 0x01,         //   report size (1)
+    0x95, 0x08,         //   report count (8)
+    0x81, 0x02,         //   input (data, variable, absolute), the modifiers
+    0x75, 0x08,         //   report size (8)
+    0x95, 0x01,         //   report count (1)
+    0x81, 0x01,         //   input (constant), reserved
+    0x19, 0x00,         //   usage minimum (0)
+    0x2a, 0xff, 0x00,   //   usage maximum (255)
+    0x26, 0xff, 0x00,   //   logical maximum (255)
+    0x95, 0x06,         //   report count (6)
+    0x81, 0x00,         //   input (data, array, absolute), the keys
+    0xc0,               // end collection
+    0x05, 0x0c,         // usage page (consumer)
+    0x09, 0x01,         // usage (consumer control)
+    0xa1, 0x01,         // collection (application)
+    0x85, CONSUMER_REPORT, //   report id
+    0x19, 0x00,         //   usage minimum (0)
+    0x2a, 0xff, 0x03,   //   usage maximum (0x3ff)
+    0x15, 0x00,         //   logical minimum (0)
+    0x26, 0xff, 0x03,   //   logical maximum (0x3ff)
+    0x75, 0x10,         //   report size (16)
+    0x95, 0x01,         //   report count (1)
+    0x81, 0x00,         //   input (data, array, absolute), the key
+    0xc0,               // end collection
+];
+
+#[derive(Clone, Debug)]
+pub enum HidAction {
+    Keys(Vec<u8, MACRO_LEN>),      // typed in order
+    Chord(u8, Vec<u8, CHORD_LEN>), // modifiers and keys held together
+    Media(u16),                    // consumer usage id
+    Text(String<MAX_HID_TEXT>),    // ascii, US layout
+}
+
+type Writer<'d, T> = HidWriter<'d, Driver<'d, T>, REPORT_LEN>;
+
+pub async fn send<'d, T: Instance + 'd>(
+    writer: &mut Writer<'d, T>,
+    action: &HidAction,
+) -> Result<(), EndpointError> {
+    match action {
+        HidAction::Keys(keys) => {
+            for &key in keys {
+                press(writer, 0, &[key]).await?;
+            }
+        }
+        HidAction::Chord(modifiers, keys) => press(writer, *modifiers, keys).await?,
+        HidAction::Media(usage) => {
+            let [lo, hi] = usage.to_le_bytes();
+            writer.write(&[CONSUMER_REPORT, lo, hi]).await?;
+            Timer::after(KEY_TIME).await;
+            writer.write(&[CONSUMER_REPORT, 0, 0]).await?;
+            Timer::after(KEY_TIME).await;
+        }
+        HidAction::Text(text) => {
+            // the rest of the text still makes sense without an odd character
+            for (modifiers, key) in text.bytes().filter_map(ascii_key) {
+                press(writer, modifiers, &[key]).await?;
+            }
+        }
+    }
+    Ok(())
+}
+
+// presses the keys together and releases them
+async fn press<'d, T: Instance + 'd>(
+    writer: &mut Writer<'d, T>,
+    modifiers: u8,
+    keys: &[u8],
+) -> Result<(), EndpointError> {
+    let mut report = [0; REPORT_LEN];
+    report[0] = KEYBOARD_REPORT;
+    report[1] = modifiers;
+    for (slot, key) in report[3..].iter_mut().zip(keys) {
+        *slot = *key;
+    }
+
+    writer.write(&report).await?;
+    Timer::after(KEY_TIME).await;
+    // the release too, or the host misses a key typed twice
+    writer
+        .write(&[KEYBOARD_REPORT, 0, 0, 0, 0, 0, 0, 0, 0])
+        .await?;
+    Timer::after(KEY_TIME).await;
+
+    Ok(())
+}
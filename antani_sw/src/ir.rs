@@ -0,0 +1,223 @@
+use core::cell::RefCell;
+
+use embassy_rp::flash;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use log::{error, info};
+use minibadge_protocol::usb_messages_capnp::{IrProtocol, RepeatPolicy};
+use minibadge_protocol::{IrAction, IrBinding, IrBindings, IrCommand, IR_BINDINGS_SIZE};
+use usbd_hid::descriptor::KeyboardUsage;
+
+use crate::storage::{self, Aligned, BadgeFlash, Setting};
+
+struct Table {
+    bindings: IrBindings,
+    // uploaded over usb, the default ones are not saved
+    custom: bool,
+}
+
+static TABLE: Mutex<CriticalSectionRawMutex, RefCell<Table>> = Mutex::new(RefCell::new(Table {
+    bindings: IrBindings::new(),
+    custom: false,
+}));
+
+// what a short press of the button of the badge does
+static BUTTON: Mutex<CriticalSectionRawMutex, RefCell<IrAction>> =
+    Mutex::new(RefCell::new(IrAction::NextScene));
+
+// the remotes the badge knows out of the box
+fn default_bindings() -> IrBindings {
+    let binding = |protocol, address, command, action| IrBinding {
+        protocol,
+        address,
+        command,
+        repeat: RepeatPolicy::Ignore,
+        action,
+    };
+    let nec = |address, command, action| binding(IrProtocol::Nec, address, command, action);
+    // samsung tv remote
+    let key = |command, key: KeyboardUsage| {
+        binding(IrProtocol::Samsung, 7, command, IrAction::HidKey(key as u8))
+    };
+    // consumer usage ids, the keyboard volume keys are ignored by most hosts
+    let media =
+        |command, usage| binding(IrProtocol::Samsung, 7, command, IrAction::MediaKey(usage));
+
+    [
+        // chinese ir rgb remote
+        nec(0, 70, IrAction::DecreaseBrightness),
+        nec(0, 69, IrAction::IncreaseBrightness),
+        // off
+        nec(0, 71, IrAction::None),
+        // on, used to sync clocks between multiple devices
+        nec(0, 67, IrAction::ResetTime),
+        // animations
+        nec(0, 68, IrAction::NextScene),
+        // startup ir command of the badges with an older firmware, say hi to them
+        nec(0, 66, IrAction::GreetBadge),
+        media(7, 0xe9),
+        media(11, 0xea),
+        key(98, KeyboardUsage::KeyboardRightArrow),
+        key(101, KeyboardUsage::KeyboardLeftArrow),
+        key(96, KeyboardUsage::KeyboardUpArrow),
+        key(97, KeyboardUsage::KeyboardDownArrow),
+        key(102, KeyboardUsage::KeyboardEscape),
+        key(104, KeyboardUsage::KeyboardEnter),
+        key(4, KeyboardUsage::Keyboard1Exclamation),
+        key(5, KeyboardUsage::Keyboard2At),
+        key(6, KeyboardUsage::Keyboard3Hash),
+        key(8, KeyboardUsage::Keyboard4Dollar),
+        key(9, KeyboardUsage::Keyboard5Percent),
+        key(10, KeyboardUsage::Keyboard6Caret),
+        key(12, KeyboardUsage::Keyboard7Ampersand),
+        key(13, KeyboardUsage::Keyboard8Asterisk),
+        key(14, KeyboardUsage::Keyboard9OpenParens),
+        media(15, 0xe2),
+    ]
+    .into_iter()
+    .collect()
+}
+
+// the bindings saved in flash, or the default ones
+pub fn load(flash: &mut BadgeFlash) {
+    let mut buf = Aligned([0; IR_BINDINGS_SIZE]);
+
+    let saved = storage::load(flash, Setting::IrBindings, &mut buf.0)
+        .and_then(|len| minibadge_protocol::deserialize_ir_bindings(&mut &buf.0[..len]).ok());
+
+    let table = match saved {
+        Some(bindings) => Table {
+            bindings,
+            custom: true,
+        },
+        None => Table {
+            bindings: default_bindings(),
+            custom: false,
+        },
+    };
+
+    TABLE.lock(|t| *t.borrow_mut() = table);
+
+    let saved = storage::load(flash, Setting::ButtonAction, &mut buf.0)
+        .and_then(|len| minibadge_protocol::deserialize_ir_action(&mut &buf.0[..len]).ok());
+    if let Some(action) = saved {
+        BUTTON.lock(|b| *b.borrow_mut() = action);
+    }
+}
+
+pub fn set(bindings: IrBindings) {
+    info!("{} new IR bindings", bindings.len());
+
+    TABLE.lock(|t| {
+        *t.borrow_mut() = Table {
+            bindings,
+            custom: true,
+        }
+    });
+    storage::request_save(Setting::IrBindings);
+}
+
+pub fn reset() {
+    info!("Default IR bindings");
+
+    TABLE.lock(|t| {
+        *t.borrow_mut() = Table {
+            bindings: default_bindings(),
+            custom: false,
+        }
+    });
+    storage::request_save(Setting::IrBindings);
+}
+
+// binds a button, in place of its old binding if any, false when the table is full
+pub fn learn(command: &IrCommand, action: IrAction) -> bool {
+    let binding = IrBinding {
+        protocol: command.protocol,
+        address: command.address,
+        command: command.command,
+        repeat: RepeatPolicy::Ignore,
+        action,
+    };
+
+    let learned = TABLE.lock(|t| {
+        let mut t = t.borrow_mut();
+
+        let old = t.bindings.iter().position(|b| b.button(command));
+        let learned = match old {
+            Some(i) => {
+                t.bindings[i] = binding;
+                true
+            }
+            None => t.bindings.push(binding).is_ok(),
+        };
+        t.custom |= learned;
+
+        learned
+    });
+
+    if learned {
+        info!("Learned IR {:?}", command);
+        storage::request_save(Setting::IrBindings);
+    } else {
+        error!("No room for more IR bindings");
+    }
+
+    learned
+}
+
+// what to do for a received command, the first matching binding wins
+pub fn action(command: &IrCommand) -> Option<IrAction> {
+    TABLE.lock(|t| {
+        t.borrow()
+            .bindings
+            .iter()
+            .find(|b| b.matches(command))
+            .map(|b| b.action.clone())
+    })
+}
+
+// called by the storage task, the default bindings just erase the sector
+pub fn save(flash: &mut BadgeFlash) -> Result<(), flash::Error> {
+    let (bindings, custom) = TABLE.lock(|t| {
+        let t = t.borrow();
+        (t.bindings.clone(), t.custom)
+    });
+
+    if !custom {
+        return storage::erase(flash, Setting::IrBindings);
+    }
+
+    let mut buf = [0; IR_BINDINGS_SIZE];
+    match minibadge_protocol::serialize_ir_bindings(&bindings, &mut buf) {
+        Ok(len) => storage::save(flash, Setting::IrBindings, &buf[..len]),
+        Err(e) => {
+            error!("Error serializing the IR bindings: {:?}", e.kind);
+            Ok(())
+        }
+    }
+}
+
+pub fn set_button(action: IrAction) {
+    info!("Button bound to {:?}", action);
+
+    BUTTON.lock(|b| *b.borrow_mut() = action);
+    storage::request_save(Setting::ButtonAction);
+}
+
+pub fn button() -> IrAction {
+    BUTTON.lock(|b| b.borrow().clone())
+}
+
+// called by the storage task, next scene is saved too so it replaces an older action
+pub fn save_button(flash: &mut BadgeFlash) -> Result<(), flash::Error> {
+    let action = button();
+
+    let mut buf = [0; IR_BINDINGS_SIZE];
+    match minibadge_protocol::serialize_ir_action(&action, &mut buf) {
+        Ok(len) => storage::save(flash, Setting::ButtonAction, &buf[..len]),
+        Err(e) => {
+            error!("Error serializing the button action: {:?}", e.kind);
+            Ok(())
+        }
+    }
+}
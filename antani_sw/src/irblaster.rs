@@ -0,0 +1,127 @@
+// the infrared led, modulated by a pio state machine
+//
+// the state machine takes a mark and a space word at a time from the tx fifo, fed by dma:
+// a mark is a number of carrier periods, with the high and low cycles of a period,
+// a space is a number of cycles with the led off.
+// the led is side set low whenever the state machine waits for a word, so it stays off when idle
+
+use embassy_rp::dma;
+use embassy_rp::gpio::Level;
+use embassy_rp::pio::{
+    Common, Config, Direction, FifoJoin, Instance, PioPin, ShiftConfig, ShiftDirection,
+    StateMachine,
+};
+use embassy_rp::{clocks, into_ref, Peripheral, PeripheralRef};
+use embassy_time::{Duration, Timer};
+use fixed::types::U24F8;
+use heapless::Vec;
+use minibadge_protocol::IrCarrier;
+
+use crate::irprotocols::SENDER_LEN;
+
+// state machine cycles in a carrier period, so the duty cycle goes by 1%
+const CYCLES: u32 = 100;
+
+// cycles spent outside the counting loops, see the program
+const HIGH_OVERHEAD: u32 = 2;
+const LOW_OVERHEAD: u32 = 3;
+const SPACE_OVERHEAD: u32 = 6;
+
+// how often the end of a frame is checked, once the dma is done
+const POLL: Duration = Duration::from_millis(1);
+
+pub struct IrBlaster<'d, P: Instance, const S: usize> {
+    dma: PeripheralRef<'d, dma::AnyChannel>,
+    sm: StateMachine<'d, P, S>,
+}
+
+impl<'d, P: Instance, const S: usize> IrBlaster<'d, P, S> {
+    pub fn new(
+        pio: &mut Common<'d, P>,
+        mut sm: StateMachine<'d, P, S>,
+        dma: impl Peripheral<P = impl dma::Channel> + 'd,
+        pin: impl PioPin,
+    ) -> Self {
+        into_ref!(dma);
+
+        // mark word: carrier periods - 1, high cycles - 2, low cycles - 3, 16, 8 and 8 bits
+        // space word: cycles - 6
+        let prg = pio_proc::pio_asm!(
+            ".side_set 1 opt"
+            ".wrap_target"
+                "pull block      side 0"
+                "out x, 16"
+                "out isr, 8"
+            "period:"
+                "mov y, isr      side 1"
+            "high:"
+                "jmp y-- high"
+                "mov y, osr      side 0"
+            "low:"
+                "jmp y-- low"
+                "jmp x-- period"
+                "pull block"
+                "mov x, osr"
+            "space:"
+                "jmp x-- space"
+            ".wrap"
+        );
+
+        let out_pin = pio.make_pio_pin(pin);
+        sm.set_pins(Level::Low, &[&out_pin]);
+        sm.set_pin_dirs(Direction::Out, &[&out_pin]);
+
+        let mut cfg = Config::default();
+        cfg.use_program(&pio.load_program(&prg.program), &[&out_pin]);
+        cfg.fifo_join = FifoJoin::TxOnly;
+        cfg.shift_out = ShiftConfig {
+            auto_fill: false,
+            threshold: 32,
+            direction: ShiftDirection::Right,
+        };
+
+        sm.set_config(&cfg);
+        sm.set_enable(true);
+
+        Self {
+            dma: dma.map_into(),
+            sm,
+        }
+    }
+
+    // sends the pulses of a sender loaded in microseconds, and waits for the led to go off
+    pub async fn send(&mut self, carrier: IrCarrier, pulses: &[u32]) {
+        // the clock divider is 16.8 fixed point, and the system clock doesn't fit a U24F8
+        let divider =
+            (u64::from(clocks::clk_sys_freq()) << 8) / u64::from(carrier.frequency * CYCLES);
+        self.sm.set_clock_divider(U24F8::from_bits(divider as u32));
+        self.sm.clkdiv_restart();
+
+        let duty = u32::from(carrier.duty_cycle).clamp(HIGH_OVERHEAD, CYCLES - LOW_OVERHEAD);
+        let high = duty - HIGH_OVERHEAD;
+        let low = CYCLES - duty - LOW_OVERHEAD;
+
+        // the first pulse is the silence before the frame, always empty
+        let mut words: Vec<u32, SENDER_LEN> = Vec::new();
+        for pair in pulses.get(1..).unwrap_or_default().chunks(2) {
+            let periods = (u64::from(pair[0]) * u64::from(carrier.frequency) / 1_000_000)
+                .clamp(1, 1 << 16) as u32;
+            // a frame ending with a mark gets an empty space, so the next one starts with a mark
+            let space = u64::from(pair.get(1).copied().unwrap_or_default())
+                * u64::from(carrier.frequency * CYCLES)
+                / 1_000_000;
+
+            // the words of the longest sender fit
+            let _ = words.push((periods - 1) | high << 16 | low << 24);
+            let _ = words.push((space as u32).saturating_sub(SPACE_OVERHEAD));
+        }
+
+        self.sm.tx().dma_push(self.dma.reborrow(), &words).await;
+
+        // the fifo still has a few words, the state machine stalls once it has sent them
+        let _ = self.sm.tx().stalled(); // clears the stall flag from before the frame
+        while !(self.sm.tx().empty() && self.sm.tx().stalled()) {
+            Timer::after(POLL).await;
+        }
+    }
+}
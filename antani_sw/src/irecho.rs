@@ -0,0 +1,102 @@
+// what the ir led just sent, so the receiver drops our own frames and nothing else
+//
+// the receiver sees the led of the badge itself: a command we send comes back decoded,
+// during the frame or right after it. badge frames carry our id, they need no bookkeeping
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant};
+use minibadge_protocol::IrCommand;
+
+// the receiver decodes a frame after its end and the silence of FRAME_END
+const ECHO_WINDOW: Duration = Duration::from_millis(100);
+
+#[derive(Clone, Copy)]
+enum Sent {
+    Command(IrCommand),
+    // the decoders may find any command in a raw frame
+    Raw,
+}
+
+#[derive(Clone, Copy)]
+struct Transmission {
+    sent: Sent,
+    // None while sending
+    end: Option<Instant>,
+}
+
+static LAST: Mutex<CriticalSectionRawMutex, Cell<Option<Transmission>>> =
+    Mutex::new(Cell::new(None));
+
+// frames of our own we dropped, and badge frames that started but could not be decoded
+// only the receiver task counts, and the m0+ has no fetch_add
+static ECHOES: AtomicU32 = AtomicU32::new(0);
+static DROPPED: AtomicU32 = AtomicU32::new(0);
+
+fn count(counter: &AtomicU32) {
+    counter.store(
+        counter.load(Ordering::Relaxed).wrapping_add(1),
+        Ordering::Relaxed,
+    );
+}
+
+// the blaster calls this before a command or a raw frame, None for a raw frame
+pub fn sending(command: Option<IrCommand>) {
+    let sent = command.map_or(Sent::Raw, Sent::Command);
+    LAST.lock(|l| l.set(Some(Transmission { sent, end: None })));
+}
+
+// and this once the led is off
+pub fn sent() {
+    LAST.lock(|l| {
+        if let Some(transmission) = l.get() {
+            l.set(Some(Transmission {
+                end: Some(Instant::now()),
+                ..transmission
+            }));
+        }
+    });
+}
+
+// true when the command is the one we are sending, or just sent
+pub fn echo(command: &IrCommand) -> bool {
+    let echo = LAST.lock(|l| match l.get() {
+        Some(Transmission { sent, end }) if end.map_or(true, |e| e.elapsed() < ECHO_WINDOW) => {
+            match sent {
+                Sent::Raw => true,
+                // the repeat frames of a command come back too
+                Sent::Command(sent) => {
+                    sent.protocol == command.protocol
+                        && sent.address == command.address
+                        && sent.command == command.command
+                }
+            }
+        }
+        _ => false,
+    });
+
+    if echo {
+        count(&ECHOES);
+    }
+    echo
+}
+
+// a badge frame with our id came back
+pub fn badge_echo() {
+    count(&ECHOES);
+}
+
+pub fn dropped() {
+    count(&DROPPED);
+}
+
+// echoes and dropped frames since the start
+pub fn counters() -> (u32, u32) {
+    (
+        ECHOES.load(Ordering::Relaxed),
+        DROPPED.load(Ordering::Relaxed),
+    )
+}
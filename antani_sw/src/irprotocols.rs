@@ -0,0 +1,447 @@
+// the remote protocols: the receiver edges go through one decoder each,
+// the commands to send are encoded in pulses for the blaster
+use core::cell::RefCell;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Instant};
+use infrared::protocol::nec::{NecCommand, NecDebugCmd, SamsungNecCommand};
+use infrared::protocol::rc5::Rc5Command;
+use infrared::protocol::rc6::Rc6Command;
+use infrared::protocol::{AppleNec, Nec, NecDebug, Rc5, Rc6, SamsungNec};
+use infrared::sender::{ProtocolEncoder, PulsedataSender};
+use infrared::{Protocol, Receiver};
+use minibadge_protocol::usb_messages_capnp::IrProtocol;
+use minibadge_protocol::{IrCarrier, IrCommand, IrRaw, MAX_IR_RAW};
+
+use crate::badgelink;
+
+// the edges are timed in microseconds
+const RESOLUTION: u32 = 1_000_000;
+
+// the sender works in microseconds, the blaster turns them into carrier periods
+pub const SEND_FREQUENCY: u32 = 1_000_000;
+
+// room for the longest raw frame or badge message, and the leading zero
+pub const SENDER_LEN: usize = if MAX_IR_RAW + 1 > badgelink::MAX_PULSES {
+    MAX_IR_RAW + 1
+} else {
+    badgelink::MAX_PULSES
+};
+
+pub type Sender = PulsedataSender<SENDER_LEN>;
+
+// a sirc frame has no stop bit, it ends when the receiver is quiet for this long
+pub const FRAME_END: Duration = Duration::from_millis(5);
+
+// start to start, for the protocols sending a frame more than once
+pub const FRAME_PERIOD: Duration = Duration::from_millis(45);
+
+// the frames of a held button come closer than this
+const REPEAT_INTERVAL: Duration = Duration::from_millis(250);
+
+// longer than the spaces inside the frames of any remote, shorter than the gap between frames
+pub const RAW_FRAME_END: Duration = Duration::from_millis(20);
+
+// how long the host waits for a raw frame
+const RAW_CAPTURE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// fewer edges are noise, not a remote
+const RAW_MIN_LEN: usize = 8;
+
+// asked by the host, filled by the receiver
+static CAPTURING: AtomicBool = AtomicBool::new(false);
+static CAPTURED: Signal<CriticalSectionRawMutex, IrRaw> = Signal::new();
+
+// the raw frame to send, too large for the channel
+static RAW_FRAME: Mutex<CriticalSectionRawMutex, RefCell<IrRaw>> =
+    Mutex::new(RefCell::new(IrRaw::new()));
+
+const APPLE_VENDOR_ID: u32 = 0x43f;
+
+pub struct Decoders {
+    nec: Receiver<Nec>,
+    samsung: Receiver<SamsungNec>,
+    apple: Receiver<AppleNec>,
+    rc5: Receiver<Rc5>,
+    rc6: Receiver<Rc6>,
+    sirc: SircDecoder,
+    // the last command without nec repeat codes, to tell when a button is held
+    last: Option<(IrCommand, bool, Instant)>,
+}
+
+impl Decoders {
+    pub fn new() -> Self {
+        Self {
+            nec: Receiver::new(RESOLUTION),
+            samsung: Receiver::new(RESOLUTION),
+            apple: Receiver::new(RESOLUTION),
+            rc5: Receiver::new(RESOLUTION),
+            rc6: Receiver::new(RESOLUTION),
+            sirc: SircDecoder::default(),
+            last: None,
+        }
+    }
+
+    // mark is true when the carrier starts, dt the microseconds since the previous edge
+    pub fn edge(&mut self, mark: bool, dt: u32) -> Option<IrCommand> {
+        let command = |protocol, address: u8, command: u8, repeat| IrCommand {
+            protocol,
+            address: address.into(),
+            command: command.into(),
+            repeat,
+        };
+
+        // every decoder sees every edge, at most one of them makes sense of a frame
+        let nec = self.nec.event(dt, mark).ok().flatten();
+        let samsung = self.samsung.event(dt, mark).ok().flatten();
+        let apple = self.apple.event(dt, mark).ok().flatten();
+        let rc5 = self.rc5.event(dt, mark).ok().flatten();
+        let rc6 = self.rc6.event(dt, mark).ok().flatten();
+        let sirc = self.sirc.event(mark, dt);
+
+        // the rc6 leader looks like a sirc header
+        if nec.is_some() || samsung.is_some() || apple.is_some() || rc5.is_some() || rc6.is_some() {
+            self.sirc.receiving = false;
+        }
+
+        if let Some(c) = nec {
+            return Some(command(IrProtocol::Nec, c.addr, c.cmd, c.repeat));
+        }
+        if let Some(c) = samsung {
+            return Some(command(IrProtocol::Samsung, c.addr, c.cmd, c.repeat));
+        }
+        if let Some(c) = apple {
+            return Some(IrCommand {
+                protocol: IrProtocol::AppleNec,
+                address: c.device_id.into(),
+                command: u16::from(c.command_page) << 7 | u16::from(c.command),
+                repeat: c.repeat,
+            });
+        }
+        if let Some(c) = rc5 {
+            // the second start bit is the inverted bit 6 of the command
+            let extended = if c.start & 1 == 0 { 0x40 } else { 0 };
+            return Some(self.toggled(
+                command(IrProtocol::Rc5, c.addr, c.cmd | extended, false),
+                c.toggle,
+            ));
+        }
+        if let Some(c) = rc6 {
+            return Some(self.toggled(command(IrProtocol::Rc6, c.addr, c.cmd, false), c.toggle));
+        }
+
+        sirc.map(|frame| self.toggled(frame.command(), false))
+    }
+
+    // a frame is still being received, call timeout when no edge comes within FRAME_END
+    pub fn pending(&self) -> bool {
+        self.sirc.receiving
+    }
+
+    pub fn timeout(&mut self) -> Option<IrCommand> {
+        self.sirc
+            .end()
+            .map(|frame| self.toggled(frame.command(), false))
+    }
+
+    // rc5 and rc6 flip the toggle bit at every press, sirc sends the same frame while held
+    fn toggled(&mut self, mut command: IrCommand, toggle: bool) -> IrCommand {
+        let now = Instant::now();
+
+        if let Some((last, last_toggle, at)) = self.last {
+            command.repeat = last.protocol == command.protocol
+                && last.address == command.address
+                && last.command == command.command
+                && last_toggle == toggle
+                && now - at < REPEAT_INTERVAL;
+        }
+        self.last = Some((command, toggle, now));
+
+        command
+    }
+}
+
+// records the timings of the next frame, for the remotes no decoder knows
+#[derive(Default)]
+pub struct RawRecorder {
+    frame: Option<IrRaw>,
+}
+
+impl RawRecorder {
+    pub fn edge(&mut self, mark: bool, dt: u32) {
+        match &mut self.frame {
+            // the space before the first mark is not part of the frame
+            None if mark && CAPTURING.load(Ordering::Relaxed) => self.frame = Some(IrRaw::new()),
+            None => {}
+            Some(frame) => {
+                if frame.push(dt.min(u16::MAX as u32) as u16).is_err() {
+                    self.end();
+                }
+            }
+        }
+    }
+
+    pub fn pending(&self) -> bool {
+        self.frame.is_some()
+    }
+
+    // the receiver has been quiet for RAW_FRAME_END, the frame is over
+    pub fn end(&mut self) {
+        let Some(mut frame) = self.frame.take() else {
+            return;
+        };
+
+        // a frame ends with a mark, the space after it is the silence
+        if frame.len() % 2 == 0 {
+            frame.pop();
+        }
+
+        if frame.len() >= RAW_MIN_LEN {
+            CAPTURING.store(false, Ordering::Relaxed);
+            CAPTURED.signal(frame);
+        }
+    }
+}
+
+// waits for the receiver to record a frame, None when no remote is used in time
+pub async fn capture_raw() -> Option<IrRaw> {
+    CAPTURED.reset();
+    CAPTURING.store(true, Ordering::Relaxed);
+
+    let frame = with_timeout(RAW_CAPTURE_TIMEOUT, CAPTURED.wait())
+        .await
+        .ok();
+    CAPTURING.store(false, Ordering::Relaxed);
+
+    frame
+}
+
+// keeps the raw frame for ir_blaster_tsk, that is told with TaskCommand::SendIrRaw
+pub fn set_raw(frame: IrRaw) {
+    RAW_FRAME.lock(|f| *f.borrow_mut() = frame);
+}
+
+pub fn load_raw(sender: &mut Sender) {
+    RAW_FRAME.lock(|f| sender.load_command::<Raw, SEND_FREQUENCY>(&f.borrow()));
+}
+
+// the usual duty cycle of remotes, unless the host asks for another
+const DUTY_CYCLE: u8 = 50;
+
+// the receiver can't tell the carrier of a raw frame, most remotes use this
+pub const RAW_CARRIER: IrCarrier = IrCarrier {
+    frequency: 38_000,
+    duty_cycle: DUTY_CYCLE,
+};
+
+// how many times a command is sent, sony devices want to see the frame thrice
+pub fn frames(protocol: IrProtocol) -> usize {
+    match protocol {
+        IrProtocol::Sirc => 3,
+        _ => 1,
+    }
+}
+
+pub fn carrier(protocol: IrProtocol) -> IrCarrier {
+    let frequency = match protocol {
+        IrProtocol::Rc5 | IrProtocol::Rc6 => 36_000,
+        IrProtocol::Sirc => 40_000,
+        IrProtocol::Nec | IrProtocol::Samsung | IrProtocol::AppleNec => 38_000,
+    };
+
+    IrCarrier {
+        frequency,
+        duty_cycle: DUTY_CYCLE,
+    }
+}
+
+// fills the sender with the pulses of one frame
+pub fn load(sender: &mut Sender, command: &IrCommand, toggle: bool) {
+    const F: u32 = SEND_FREQUENCY;
+
+    let address = command.address as u8;
+    let cmd = command.command as u8;
+
+    match command.protocol {
+        IrProtocol::Nec => sender.load_command::<Nec, F>(&NecCommand {
+            addr: address,
+            cmd,
+            repeat: command.repeat,
+        }),
+        IrProtocol::Samsung => sender.load_command::<SamsungNec, F>(&SamsungNecCommand {
+            addr: address,
+            cmd,
+            repeat: command.repeat,
+        }),
+        // the infrared crate can't pack apple commands
+        IrProtocol::AppleNec => sender.load_command::<NecDebug, F>(&NecDebugCmd {
+            bits: apple_bits(command),
+        }),
+        IrProtocol::Rc5 => sender.load_command::<Rc5, F>(&Rc5Command {
+            addr: address & 0x1f,
+            cmd: cmd & 0x3f,
+            start: if cmd & 0x40 != 0 { 0b10 } else { 0b11 },
+            toggle,
+        }),
+        IrProtocol::Rc6 => sender.load_command::<Rc6, F>(&Rc6Command {
+            addr: address,
+            cmd,
+            toggle,
+        }),
+        IrProtocol::Sirc => sender.load_command::<Sirc, F>(&SircFrame::new(command)),
+    }
+}
+
+fn apple_bits(command: &IrCommand) -> u32 {
+    let command_page = u32::from(command.command >> 7) & 0x1f;
+    let bits = command_page
+        | APPLE_VENDOR_ID << 5
+        | (u32::from(command.command) & 0x7f) << 17
+        | (u32::from(command.address) & 0xff) << 24;
+
+    // bit 16 makes the parity odd
+    if bits.count_ones() % 2 == 0 {
+        bits | 1 << 16
+    } else {
+        bits
+    }
+}
+
+// Sony SIRC, the infrared crate does not have it
+// 40 kHz, a 2.4 ms header, then the bits lsb first: 600 us of carrier for a zero, 1200 for a one,
+// each followed by 600 us of silence. 7 bits of command, then 5, 8 or 13 bits of address
+const SIRC_UNIT: u32 = 600;
+
+pub struct Sirc;
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SircFrame {
+    bits: u32,
+    len: u8,
+}
+
+impl SircFrame {
+    // the shortest frame that fits the address
+    fn new(command: &IrCommand) -> Self {
+        let len = match command.address {
+            0..=0x1f => 12,
+            0x20..=0xff => 15,
+            _ => 20,
+        };
+
+        Self {
+            bits: u32::from(command.command & 0x7f) | (u32::from(command.address) & 0x1fff) << 7,
+            len,
+        }
+    }
+
+    fn command(&self) -> IrCommand {
+        IrCommand {
+            protocol: IrProtocol::Sirc,
+            address: (self.bits >> 7) as u16,
+            command: (self.bits & 0x7f) as u16,
+            repeat: false,
+        }
+    }
+}
+
+impl Protocol for Sirc {
+    type Cmd = SircFrame;
+}
+
+impl<const F: u32> ProtocolEncoder<F> for Sirc {
+    // unit and header in ticks
+    type EncoderData = [u32; 2];
+    const DATA: [u32; 2] = [SIRC_UNIT * F / 1_000_000, 4 * SIRC_UNIT * F / 1_000_000];
+
+    fn encode(frame: &SircFrame, b: &mut [u32]) -> usize {
+        let [unit, header] = <Self as ProtocolEncoder<F>>::DATA;
+
+        // the sender toggles the carrier after each duration
+        b[0] = 0;
+        b[1] = header;
+        let mut i = 2;
+
+        for bit in 0..frame.len {
+            b[i] = unit;
+            b[i + 1] = if frame.bits & (1 << bit) != 0 {
+                2 * unit
+            } else {
+                unit
+            };
+            i += 2;
+        }
+
+        i
+    }
+}
+
+// the timings are sent as they are
+pub struct Raw;
+
+impl Protocol for Raw {
+    type Cmd = IrRaw;
+}
+
+impl<const F: u32> ProtocolEncoder<F> for Raw {
+    type EncoderData = ();
+    const DATA: () = ();
+
+    fn encode(frame: &IrRaw, b: &mut [u32]) -> usize {
+        b[0] = 0;
+
+        for (i, us) in frame.iter().enumerate() {
+            // at least a tick, or the sender would skip the edge
+            b[i + 1] = ((u32::from(*us) * (F / 1000) + 500) / 1000).max(1);
+        }
+
+        frame.len() + 1
+    }
+}
+
+#[derive(Default)]
+struct SircDecoder {
+    frame: SircFrame,
+    receiving: bool,
+}
+
+impl SircDecoder {
+    fn event(&mut self, mark: bool, dt: u32) -> Option<SircFrame> {
+        // only the length of the marks matters
+        if mark {
+            return None;
+        }
+
+        let unit = SIRC_UNIT;
+        match dt {
+            _ if (3 * unit..5 * unit).contains(&dt) => {
+                self.frame = SircFrame::default();
+                self.receiving = true;
+            }
+            _ if !self.receiving => {}
+            _ if (unit / 2..3 * unit / 2).contains(&dt) => self.frame.len += 1,
+            _ if (3 * unit / 2..3 * unit).contains(&dt) => {
+                self.frame.bits |= 1 << self.frame.len;
+                self.frame.len += 1;
+            }
+            _ => self.receiving = false,
+        }
+
+        // the longest frame does not wait for the timeout
+        if self.frame.len == 20 {
+            return self.end();
+        }
+
+        None
+    }
+
+    fn end(&mut self) -> Option<SircFrame> {
+        let complete = self.receiving && matches!(self.frame.len, 12 | 15 | 20);
+        self.receiving = false;
+
+        complete.then_some(self.frame)
+    }
+}
@@ -0,0 +1,179 @@
+use core::cell::RefCell;
+use core::fmt::{self, Write};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use log::kv::{self, Key, Value, VisitSource};
+use log::{LevelFilter, Record};
+// the same limits as the decoder, the config is moved in as it is
+use minibadge_protocol::{MAX_MODULE_LEVELS, MODULE_NAME_LEN};
+
+pub const DEFAULT_LEVEL: LevelFilter = LevelFilter::Info;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Debug)]
+pub struct LogConfig {
+    pub level: LevelFilter,
+    // module path prefix and its level, the longest matching prefix wins
+    pub modules: Vec<(String<MODULE_NAME_LEN>, LevelFilter), MAX_MODULE_LEVELS>,
+    pub format: LogFormat,
+}
+
+impl LogConfig {
+    const fn new() -> Self {
+        Self {
+            level: DEFAULT_LEVEL,
+            modules: Vec::new(),
+            format: LogFormat::Text,
+        }
+    }
+
+    fn level_for(&self, module: &str) -> LevelFilter {
+        self.modules
+            .iter()
+            .filter(|(prefix, _)| module.starts_with(prefix.as_str()))
+            .max_by_key(|(prefix, _)| prefix.len())
+            .map(|(_, level)| *level)
+            .unwrap_or(self.level)
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+static CONFIG: Mutex<CriticalSectionRawMutex, RefCell<LogConfig>> =
+    Mutex::new(RefCell::new(LogConfig::new()));
+
+pub fn configure(config: LogConfig) {
+    // the log macros check the global max level before anything else,
+    // it has to let through the records of the most verbose module
+    let max_level = config
+        .modules
+        .iter()
+        .map(|(_, level)| *level)
+        .fold(config.level, Ord::max);
+
+    // log::set_max_level needs the atomics that thumbv6m doesn't have. the racy one is sound
+    // when no other store races it: the critical section holds off the interrupts and the
+    // other core, and this is the only place that changes the level
+    critical_section::with(|_| unsafe { log::set_max_level_racy(max_level) });
+
+    CONFIG.lock(|c| *c.borrow_mut() = config);
+}
+
+// called by the usb logger for every record, writes nothing if the module is filtered out
+pub fn write_record(record: &Record, writer: &mut impl Write) {
+    let module = record.module_path().unwrap_or("");
+
+    let format = CONFIG.lock(|c| {
+        let config = c.borrow();
+        (record.level() <= config.level_for(module)).then_some(config.format)
+    });
+
+    // the logger drops what does not fit in its buffer, nothing to do on errors
+    let _ = match format {
+        Some(LogFormat::Text) => write_text(record, writer),
+        Some(LogFormat::Json) => write_json(record, module, writer),
+        None => Ok(()),
+    };
+}
+
+// the key/value fields of a record follow the message, as key=value
+fn write_text(record: &Record, writer: &mut impl Write) -> fmt::Result {
+    write!(writer, "[{}] {}", record.level(), record.args())?;
+    record
+        .key_values()
+        .visit(&mut TextFields(writer))
+        .map_err(|_| fmt::Error)?;
+    writer.write_str("\r\n")
+}
+
+// the key/value fields of a record are fields of the json object, and the target is only
+// there when it isn't the module, for the records with a target of their own
+fn write_json(record: &Record, module: &str, writer: &mut impl Write) -> fmt::Result {
+    write!(
+        writer,
+        "{{\"t\":{},\"level\":\"{}\",\"module\":\"{}\",",
+        Instant::now().as_millis(),
+        record.level(),
+        module
+    )?;
+    if record.target() != module {
+        write!(writer, "\"target\":\"{}\",", record.target())?;
+    }
+    writer.write_str("\"msg\":\"")?;
+    write!(JsonEscape(writer), "{}", record.args())?;
+    writer.write_str("\"")?;
+    record
+        .key_values()
+        .visit(&mut JsonFields(writer))
+        .map_err(|_| fmt::Error)?;
+    writer.write_str("}\r\n")
+}
+
+struct TextFields<'a, W: Write>(&'a mut W);
+
+impl<'kvs, W: Write> VisitSource<'kvs> for TextFields<'_, W> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        Ok(write!(self.0, " {}={}", key, value)?)
+    }
+}
+
+// numbers and booleans as they are, everything else as a string
+struct JsonFields<'a, W: Write>(&'a mut W);
+
+impl<'kvs, W: Write> VisitSource<'kvs> for JsonFields<'_, W> {
+    fn visit_pair(&mut self, key: Key<'kvs>, value: Value<'kvs>) -> Result<(), kv::Error> {
+        write!(self.0, ",\"{}\":", key)?;
+        if let Some(n) = value.to_u64() {
+            write!(self.0, "{}", n)?;
+        } else if let Some(n) = value.to_i64() {
+            write!(self.0, "{}", n)?;
+        } else if let Some(b) = value.to_bool() {
+            write!(self.0, "{}", b)?;
+        } else {
+            self.0.write_str("\"")?;
+            write!(JsonEscape(self.0), "{}", value)?;
+            self.0.write_str("\"")?;
+        }
+        Ok(())
+    }
+}
+
+// escapes the quotes and the control characters of a json string
+struct JsonEscape<'a, W: Write>(&'a mut W);
+
+impl<W: Write> Write for JsonEscape<'_, W> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut start = 0;
+
+        for (i, c) in s.char_indices() {
+            if c != '"' && c != '\\' && !c.is_control() {
+                continue;
+            }
+
+            self.0.write_str(&s[start..i])?;
+            match c {
+                '"' => self.0.write_str("\\\"")?,
+                '\\' => self.0.write_str("\\\\")?,
+                '\n' => self.0.write_str("\\n")?,
+                '\r' => self.0.write_str("\\r")?,
+                c => write!(self.0, "\\u{:04x}", c as u32)?,
+            }
+            start = i + c.len_utf8();
+        }
+
+        self.0.write_str(&s[start..])
+    }
+}
@@ -7,20 +7,19 @@ use defmt::println;
 use defmt::unwrap;
 use embassy_executor::Executor;
 use embassy_rp::adc;
+use embassy_rp::flash::{Blocking, Flash};
 use embassy_rp::gpio::Input;
 use embassy_rp::gpio::Output;
-use embassy_rp::gpio::Pin;
 use embassy_rp::gpio::Pull;
 use embassy_rp::multicore::spawn_core1;
 use embassy_rp::multicore::Stack;
 use embassy_sync::pubsub::PubSubChannel;
 use embassy_sync::pubsub::Publisher;
 use embassy_sync::signal::Signal;
-use log::{info, warn};
+use log::{debug, info, warn};
 
 use embassy_rp::peripherals::PIO0;
 use embassy_rp::pio::{InterruptHandler, Pio};
-use embassy_rp::pwm;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
 
 use embassy_time::with_timeout;
@@ -29,26 +28,43 @@ use embassy_time::{Duration, Ticker, Timer};
 
 use embassy_rp::bind_interrupts;
 use heapless::Vec;
-use infrared::{protocol::Nec, protocol::SamsungNec, Receiver};
+use minibadge_protocol::{IrAction, IrCarrier, IrCommand};
 use panic_probe as _;
 
+mod badgelink;
 mod capnp;
+mod cdc;
+mod contacts;
+mod hid;
+mod ir;
+mod irblaster;
+mod irecho;
+mod irprotocols;
+mod logging;
+mod midi;
 mod rgbeffects;
 mod scenes;
+mod storage;
+mod timesync;
 mod usb;
 mod ws2812;
 
-pub mod usb_messages_capnp {
-    include!(concat!(env!("OUT_DIR"), "/usb_messages_capnp.rs"));
+mod build_info {
+    include!(concat!(env!("OUT_DIR"), "/build_info.rs"));
 }
 
+pub use minibadge_protocol::usb_messages_capnp;
+
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
     ADC_IRQ_FIFO => adc::InterruptHandler;
 });
 
+use hid::HidAction;
+use irblaster::IrBlaster;
 use rand::rngs::SmallRng;
 use rand::SeedableRng;
+use rgbeffects::AudioLevels;
 use rgbeffects::ColorPalette;
 use rgbeffects::FragmentShader;
 use rgbeffects::Pattern;
@@ -64,6 +80,14 @@ const LED_MATRIX_HEIGHT: usize = 3;
 const LED_MATRIX_SIZE: usize = LED_MATRIX_WIDTH * LED_MATRIX_HEIGHT;
 /// set to true if RGBW leds, false if RGB
 pub const HAS_WHITE_LED: bool = false;
+// size of the flash chip, see memory.x
+const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
+// how long the badge waits for a remote button in ir learning mode
+const IR_LEARNING_TIMEOUT: Duration = Duration::from_secs(10);
+
+// letters per second of the text beamed by another badge
+const BADGE_TEXT_SPEED: f32 = 2.0;
 
 #[derive(Clone, Copy, Default, Debug, PartialEq)]
 struct LedPixel {
@@ -228,20 +252,38 @@ impl LedMatrix {
 #[derive(Clone, Debug)]
 enum TaskCommand {
     ThermalThrottleMultiplier(f32), // 1.0 = no throttle, 0.0 = full throttle
-    ReceivedIrNec(u8, u8, bool),    // add, cmd, repeat
+    ReceivedIr(IrCommand),
     ShortButtonPress,
     LongButtonPress,
-    MidiSetPixel(u8, u8, u8, u8), // x y channel (0=r 1=g 2=b) value
+    VeryLongButtonPress, // held for 4 seconds instead of a long press, learns an ir binding
+    MidiNote(u8, u8),    // note, velocity (0 = note off)
+    MidiHue(u8),
     SetWorkingMode(WorkingMode),
-    SendIrNec(u8, u8, bool),
-    IrTxDone,
+    SendIr(IrCommand, Option<IrCarrier>), // None for the carrier of the protocol
+    SendIrRaw(Option<IrCarrier>), // the frame is kept by irprotocols, too large for the channel
+    SendBadge(badgelink::Payload), // to the badges in sight
+    RelayBadge(badgelink::Message), // received from another badge, sent again
+    ReceivedBadge(badgelink::Message, Instant), // and the end of the frame
+    ShareScene,                   // tells the current scene to the other badges
     NextPattern,
     IncreaseBrightness,
     DecreaseBrightness,
     SetBrightness(OutputPower),
+    SetGain(f32),      // 0.0 - 1.0, on top of the output power
+    SetTimeScale(f32), // 1.0 = normal speed
+    SetScene(u8),
     ResetTime,
     UsbActivity,
-    SendHidKeyboard(usbd_hid::descriptor::KeyboardUsage),
+    SendHid(hid::HidAction),
+    LearnIr(IrAction), // bind the next received ir command to the action
+    SetAudioLevels(AudioLevels),
+    SetMidiMapping(midi::MidiOutMapping),
+    SetLogConfig(logging::LogConfig),
+    RebootToBootloader,
+    Reboot,
+    GetInfo,
+    CaptureIrRaw, // answered by the usb task
+    GetContacts,  // answered by the usb task
     Error,
     None,
 }
@@ -303,6 +345,18 @@ static EXECUTOR1: StaticCell<Executor> = StaticCell::new();
 fn main() -> ! {
     let p = embassy_rp::init(Default::default());
 
+    // read the flash unique id before core1 starts, it's the usb serial number
+    let mut flash = Flash::<_, Blocking, FLASH_SIZE>::new_blocking(p.FLASH);
+    let mut uid = [0; 8];
+    unwrap!(flash.blocking_unique_id(&mut uid));
+    let serial = usb::serial_number(&uid);
+    badgelink::set_id(&uid);
+
+    // the settings too, then the flash belongs to the storage task
+    ir::load(&mut flash);
+    contacts::load(&mut flash);
+    badgelink::load(&mut flash);
+
     let executor0 = EXECUTOR0.init(Executor::new());
 
     // ADC / temperature sensor
@@ -318,17 +372,18 @@ fn main() -> ! {
 
     // infrared stuff
     let _ir_sens_0 = Input::new(p.PIN_9, Pull::None);
+    let ir_sensor = Input::new(p.PIN_10, Pull::None);
 
-    let mut pwm_cfg: pwm::Config = Default::default();
-    pwm_cfg.enable = false;
-    let ir_blaster = pwm::Pwm::new_output_b(p.PWM_SLICE5, p.PIN_11, pwm_cfg);
-
-    // leds
+    // leds, and the ir led on the other state machine
     let Pio {
-        mut common, sm0, ..
+        mut common,
+        sm0,
+        sm1,
+        ..
     } = Pio::new(p.PIO0, Irqs);
 
     let ws2812: Ws2812<'_, PIO0, 0, 9> = Ws2812::new(&mut common, sm0, p.DMA_CH0, p.PIN_19);
+    let ir_blaster: IrBlaster<'_, PIO0, 1> = IrBlaster::new(&mut common, sm1, p.DMA_CH1, p.PIN_11);
 
     // scenes
     let scenes = scenes::scenes();
@@ -350,27 +405,31 @@ fn main() -> ! {
         unwrap!(spawner.spawn(temperature(adc, ts, MEGA_CHANNEL.publisher().unwrap())));
         unwrap!(spawner.spawn(usb::usb_main(
             p.USB,
+            serial,
             MEGA_CHANNEL.publisher().unwrap(),
             MEGA_CHANNEL.subscriber().unwrap()
         )));
         unwrap!(spawner.spawn(button_tsk(user_btn, MEGA_CHANNEL.publisher().unwrap())));
         unwrap!(spawner.spawn(white_led_task(white_led)));
-        unwrap!(spawner.spawn(ir_receiver(
-            p.PIN_10.pin(),
-            MEGA_CHANNEL.publisher().unwrap()
-        )));
+        unwrap!(spawner.spawn(storage::storage_task(flash)));
+        unwrap!(spawner.spawn(ir_receiver(ir_sensor, MEGA_CHANNEL.publisher().unwrap())));
+        unwrap!(spawner.spawn(contacts::beacon_task(MEGA_CHANNEL.publisher().unwrap())));
+        unwrap!(spawner.spawn(timesync::sync_task(MEGA_CHANNEL.publisher().unwrap())));
 
         unwrap!(spawner.spawn(ir_blaster_tsk(
             ir_blaster,
-            MEGA_CHANNEL.subscriber().unwrap(),
-            MEGA_CHANNEL.publisher().unwrap()
+            MEGA_CHANNEL.subscriber().unwrap()
         )));
     });
 }
 
 #[embassy_executor::task]
 async fn main_tsk(mut ws2812: Ws2812<'static, PIO0, 0, 9>, scenes: &'static Scenes) {
-    info!("Program start");
+    info!(
+        "Program start, version {}-{}",
+        build_info::VERSION,
+        build_info::GIT_HASH
+    );
     println!("Program start");
 
     let mut midi_framebuffer = RawFramebuffer::new();
@@ -397,8 +456,17 @@ async fn main_tsk(mut ws2812: Ws2812<'static, PIO0, 0, 9>, scenes: &'static Scen
 
     let mut scene_id = 0;
     let mut out_power = OutputPower::High;
-
-    let mut is_transmitting = false;
+    let mut user_gain = 1.0;
+    let mut midi_hue = 0.0;
+
+    // the action to bind and until when to wait for the remote
+    let mut ir_learning: Option<(IrAction, Instant)> = None;
+    let ir_learning_animation = |color: (u8, u8, u8), shaders: &[FragmentShader]| RenderCommand {
+        effect: Pattern::Simple(patterns.all_on),
+        color: ColorPalette::Solid(color.into()),
+        pattern_shaders: Vec::from_slice(shaders).unwrap(),
+        ..Default::default()
+    };
 
     let mega_publisher = match MEGA_CHANNEL.publisher() {
         Ok(p) => p,
@@ -418,24 +486,23 @@ async fn main_tsk(mut ws2812: Ws2812<'static, PIO0, 0, 9>, scenes: &'static Scen
 
     info!("Starting loop");
     mega_publisher
-        .publish(TaskCommand::SendIrNec(0, 66, false))
+        .publish(TaskCommand::SendBadge(badgelink::Payload::Hello))
         .await;
 
     let mut ticker = Ticker::every(Duration::from_hz(100));
 
-    let mut timer_offset = 0.0;
     loop {
-        let t = Instant::now().as_micros() as f64 / 1_000_000.0 - timer_offset;
+        let t = timesync::now();
 
         match out_power {
-            OutputPower::High => renderman.mtrx.set_gain(1.0),
-            OutputPower::Medium => renderman.mtrx.set_gain(0.7),
-            OutputPower::Low => renderman.mtrx.set_gain(0.5),
-            OutputPower::NighMode => renderman.mtrx.set_gain(0.25),
+            OutputPower::High => renderman.mtrx.set_gain(user_gain),
+            OutputPower::Medium => renderman.mtrx.set_gain(0.7 * user_gain),
+            OutputPower::Low => renderman.mtrx.set_gain(0.5 * user_gain),
+            OutputPower::NighMode => renderman.mtrx.set_gain(0.25 * user_gain),
         }
 
         if let Some(message) = mega_subscriber.try_next_message_pure() {
-            info!("Handling message: {:?}", message);
+            debug!("Handling message: {:?}", message);
             match message {
                 TaskCommand::ThermalThrottleMultiplier(gain) => {
                     renderman.mtrx.set_raw_gain(gain);
@@ -443,235 +510,151 @@ async fn main_tsk(mut ws2812: Ws2812<'static, PIO0, 0, 9>, scenes: &'static Scen
                         warn!("Thermal throttling! {}", gain);
                     }
                 }
-                TaskCommand::ReceivedIrNec(addr, cmd, repeat) => {
-                    if is_transmitting {
-                        warn!("Ignoring IR command, we are transmitting");
-                        continue;
-                    }
-
-                    match (addr, cmd, repeat) {
-                        // all those are commands of the chinese ir rgb remote
-                        (0, 70, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::DecreaseBrightness)
-                                .await;
-                        }
-                        (0, 69, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::IncreaseBrightness)
-                                .await;
-                        }
-
-                        (0, 71, false) => { // off
-                        }
-
-                        (0, 67, false) => {
-                            // on
-                            // this is used to sync clocks between multiple devices
-                            mega_publisher.publish(TaskCommand::ResetTime).await;
-                        }
-
-                        (0, 68, false) => {
-                            // animations
-                            mega_publisher.publish(TaskCommand::NextPattern).await;
-                        }
-                        // END of ir command from the chinese remote
-
-                        // startup ir command sent by another badge
-                        // say hi to the other badge
-                        (0, 66, false) => {
-                            // we do this so the animation starts in the correct time
-                            mega_publisher.publish(TaskCommand::ResetTime).await;
-
-                            mega_publisher
-                                .publish(TaskCommand::SetWorkingMode(WorkingMode::SpecialTimeout(
-                                    boot_animation.clone(),
-                                    0.5,
-                                )))
-                                .await;
-                        }
-
-                        // samsung tv remote
-                        // volume up
-                        (7, 7, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardVolumeUp,
-                                ))
-                                .await;
-                        }
-                        // volume down
-                        (7, 11, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardVolumeDown,
-                                ))
-                                .await;
-                        }
-                        //arrow right
-                        (7, 98, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardRightArrow,
-                                ))
-                                .await;
-                        }
-                        // left
-                        (7, 101, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardLeftArrow,
-                                ))
-                                .await;
-                        }
-                        // up
-                        (7, 96, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardUpArrow,
-                                ))
-                                .await;
-                        }
-                        // down
-                        (7, 97, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardDownArrow,
-                                ))
-                                .await;
-                        }
-                        // exit
-                        (7, 102, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardEscape,
-                                ))
-                                .await;
-                        }
-                        // enter
-                        (7, 104, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardEnter,
-                                ))
-                                .await;
-                        }
-                        // 1
-                        (7, 4, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard1Exclamation,
-                                ))
-                                .await;
+                TaskCommand::ReceivedIr(command) => {
+                    match ir_learning.take() {
+                        // the repeat frames of a held button are not a new button
+                        Some((action, _)) if !command.repeat => {
+                            // green when done, blinking red when the table is full
+                            let (color, shaders) = if ir::learn(&command, action) {
+                                ((0, 255, 0), &[][..])
+                            } else {
+                                ((255, 0, 0), &[FragmentShader::Blinking(4.0)][..])
+                            };
+                            working_mode = WorkingMode::SpecialTimeout(
+                                ir_learning_animation(color, shaders),
+                                t + 1.0,
+                            );
                         }
-                        // 2
-                        (7, 5, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard2At,
-                                ))
-                                .await;
+                        learning => {
+                            ir_learning = learning;
+                            if let Some(action) = ir::action(&command) {
+                                let scene = matches!(
+                                    action,
+                                    IrAction::NextScene | IrAction::SelectScene(_)
+                                );
+                                ir_action(action, &mega_publisher, &boot_animation).await;
+
+                                // the badges out of sight of the remote follow the relays
+                                if scene && badgelink::relaying() && !command.repeat {
+                                    mega_publisher.publish(TaskCommand::ShareScene).await;
+                                }
+                            }
                         }
-                        // 3
-                        (7, 6, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard3Hash,
-                                ))
-                                .await;
-                        }
-                        // 4
-                        (7, 8, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard4Dollar,
-                                ))
-                                .await;
-                        }
-                        // 5
-                        (7, 9, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard5Percent,
-                                ))
-                                .await;
-                        }
-                        // 6
-                        (7, 10, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard6Caret,
-                                ))
-                                .await;
-                        }
-                        // 7
-                        (7, 12, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard7Ampersand,
-                                ))
-                                .await;
-                        }
-                        // 8
-                        (7, 13, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard8Asterisk,
-                                ))
-                                .await;
-                        }
-                        // 9
-                        (7, 14, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::Keyboard9OpenParens,
-                                ))
-                                .await;
-                        }
-                        // mute
-                        (7, 15, false) => {
-                            mega_publisher
-                                .publish(TaskCommand::SendHidKeyboard(
-                                    usbd_hid::descriptor::KeyboardUsage::KeyboardMute,
-                                ))
-                                .await;
-                        }
-
-                        _ => {}
                     }
                     WHITE_LED_SIGNAL.signal(WhiteLedCommand::Communication);
                 }
                 TaskCommand::ShortButtonPress => {
-                    mega_publisher.publish(TaskCommand::NextPattern).await;
+                    // a press while learning gives up
+                    if ir_learning.take().is_some() {
+                        info!("IR learning cancelled");
+                        working_mode = WorkingMode::Normal;
+                    } else {
+                        ir_action(ir::button(), &mega_publisher, &boot_animation).await;
+                    }
                 }
                 TaskCommand::LongButtonPress => {
                     mega_publisher
                         .publish(TaskCommand::DecreaseBrightness)
                         .await;
                 }
+                TaskCommand::VeryLongButtonPress => {
+                    // a button of the remote selects the current scene
+                    mega_publisher
+                        .publish(TaskCommand::LearnIr(IrAction::SelectScene(scene_id as u8)))
+                        .await;
+                }
+                TaskCommand::LearnIr(action) => {
+                    info!("Press a button of the remote to bind it to {:?}", action);
+                    ir_learning = Some((action, Instant::now() + IR_LEARNING_TIMEOUT));
+                    working_mode = WorkingMode::Special(ir_learning_animation(
+                        (0, 0, 255),
+                        &[FragmentShader::Breathing(1.0)],
+                    ));
+                }
 
-                TaskCommand::MidiSetPixel(x, y, channel, value) => {
-                    let px = midi_framebuffer.get_pixel(x as usize, y as usize);
+                TaskCommand::MidiNote(note, velocity) => {
+                    let pixel = note as usize % LED_MATRIX_SIZE;
 
-                    let rgb = match channel {
-                        0 => (value, px.g, px.b).into(),
-                        1 => (px.r, value, px.b).into(),
-                        2 => (px.r, px.g, value).into(),
-                        _ => px,
-                    };
+                    // the (0,0) should be the top left pixel
+                    let x = LED_MATRIX_WIDTH - pixel % LED_MATRIX_WIDTH - 1;
+                    let y = pixel / LED_MATRIX_WIDTH;
+
+                    // velocity is the brightness, hue is set with a control change
+                    let rgb = rgbeffects::hsl2rgb(midi_hue, 1.0, 0.5 * velocity as f64 / 127.0);
 
-                    midi_framebuffer.set_pixel(x as usize, y as usize, rgb);
+                    midi_framebuffer.set_pixel(x, y, rgb);
 
                     working_mode = WorkingMode::RawFramebuffer(midi_framebuffer);
                     WHITE_LED_SIGNAL.signal(WhiteLedCommand::Communication);
                 }
 
-                TaskCommand::SendIrNec(_, _, _) => {
-                    is_transmitting = true;
+                TaskCommand::MidiHue(hue) => {
+                    midi_hue = hue as f64 / 128.0;
                 }
 
-                TaskCommand::IrTxDone => {
-                    is_transmitting = false;
+                TaskCommand::ReceivedBadge(message, end) => {
+                    // a badge we never met gets a celebration instead of the greeting,
+                    // the relays are in sight too so they count as met
+                    let new_badge = contacts::met(message.badge);
+                    if new_badge {
+                        working_mode = WorkingMode::SpecialTimeout(
+                            RenderCommand {
+                                effect: Pattern::Simple(patterns.all_on),
+                                pattern_shaders: Vec::from_slice(&[
+                                    FragmentShader::Rainbow2D(2.0),
+                                    FragmentShader::Blinking(4.0),
+                                ])
+                                .unwrap(),
+                                ..Default::default()
+                            },
+                            t + 2.0,
+                        );
+                    }
+
+                    // a message comes again from the relays, and from our own relaying
+                    if !badgelink::first_heard(message.id) {
+                        continue;
+                    }
+                    let mut relay = message.relayed().filter(|_| badgelink::relaying());
+
+                    match message.payload {
+                        badgelink::Payload::Hello if !new_badge => {
+                            ir_action(IrAction::GreetBadge, &mega_publisher, &boot_animation).await;
+                        }
+                        badgelink::Payload::Hello | badgelink::Payload::Beacon => {}
+                        badgelink::Payload::Scene(scene) => {
+                            mega_publisher.publish(TaskCommand::SetScene(scene)).await;
+                        }
+                        badgelink::Payload::Text(text) => {
+                            // scrolled twice
+                            let duration = 2.0 * text.len() as f64 / BADGE_TEXT_SPEED as f64;
+                            working_mode = WorkingMode::SpecialTimeout(
+                                RenderCommand {
+                                    effect: Pattern::Message(text, BADGE_TEXT_SPEED),
+                                    color: ColorPalette::Rainbow(0.5),
+                                    ..Default::default()
+                                },
+                                t + duration,
+                            );
+                        }
+                        badgelink::Payload::Time(ms, leader) => {
+                            // the time was read when the frame started
+                            let latency = message.airtime() + end.elapsed();
+                            // we only tell the time we follow
+                            if !timesync::received(leader, ms, latency) {
+                                relay = None;
+                            }
+                        }
+                    }
+                    if let Some(relay) = relay {
+                        mega_publisher.publish(TaskCommand::RelayBadge(relay)).await;
+                    }
+                    WHITE_LED_SIGNAL.signal(WhiteLedCommand::Communication);
+                }
+
+                TaskCommand::ShareScene => {
+                    let scene = badgelink::Payload::Scene(scene_id as u8);
+                    mega_publisher.publish(TaskCommand::SendBadge(scene)).await;
                 }
 
                 TaskCommand::NextPattern => {
@@ -714,22 +697,63 @@ async fn main_tsk(mut ws2812: Ws2812<'static, PIO0, 0, 9>, scenes: &'static Scen
                 }
 
                 TaskCommand::ResetTime => {
-                    timer_offset = Instant::now().as_micros() as f64 / 1_000_000.0;
+                    timesync::reset();
+                }
+
+                TaskCommand::SetTimeScale(scale) => {
+                    // a scale of zero would freeze the time forever
+                    timesync::set_scale((scale as f64).max(0.05));
                 }
 
                 TaskCommand::SetBrightness(b) => {
                     out_power = b;
                 }
 
+                TaskCommand::SetGain(gain) => {
+                    user_gain = gain.clamp(0.0, 1.0);
+                }
+
+                TaskCommand::SetScene(scene) => {
+                    scene_id = scene as usize % scenes.len();
+                    working_mode = WorkingMode::Normal;
+                }
+
                 TaskCommand::UsbActivity => {
                     WHITE_LED_SIGNAL.signal(WhiteLedCommand::Communication);
                 }
 
+                TaskCommand::SetAudioLevels(levels) => {
+                    renderman.persistent_data.audio = levels;
+                    if levels.beat {
+                        renderman.persistent_data.last_beat = t;
+                    }
+                }
+
                 TaskCommand::Error => {
                     WHITE_LED_SIGNAL.signal(WhiteLedCommand::Error);
                 }
 
-                TaskCommand::None | TaskCommand::SendHidKeyboard(_) => {}
+                TaskCommand::None
+                | TaskCommand::SendHid(_)
+                | TaskCommand::SetMidiMapping(_)
+                | TaskCommand::SetLogConfig(_)
+                | TaskCommand::RebootToBootloader
+                | TaskCommand::Reboot
+                | TaskCommand::GetInfo
+                | TaskCommand::CaptureIrRaw
+                | TaskCommand::GetContacts
+                | TaskCommand::SendIr(_, _)
+                | TaskCommand::SendIrRaw(_)
+                | TaskCommand::SendBadge(_)
+                | TaskCommand::RelayBadge(_) => {}
+            }
+        }
+
+        if let Some((_, deadline)) = ir_learning {
+            if Instant::now() > deadline {
+                warn!("No IR command received, nothing learned");
+                ir_learning = None;
+                working_mode = WorkingMode::Normal;
             }
         }
 
@@ -758,111 +782,181 @@ async fn main_tsk(mut ws2812: Ws2812<'static, PIO0, 0, 9>, scenes: &'static Scen
     }
 }
 
+// runs the action bound to a remote button
+async fn ir_action(action: IrAction, publisher: &MegaPublisher, boot_animation: &RenderCommand) {
+    let command = match action {
+        IrAction::None => return,
+        IrAction::NextScene => TaskCommand::NextPattern,
+        IrAction::IncreaseBrightness => TaskCommand::IncreaseBrightness,
+        IrAction::DecreaseBrightness => TaskCommand::DecreaseBrightness,
+        IrAction::SelectScene(scene) => TaskCommand::SetScene(scene),
+        IrAction::ResetTime => TaskCommand::ResetTime,
+        IrAction::GreetBadge => {
+            // we do this so the animation starts in the correct time
+            publisher.publish(TaskCommand::ResetTime).await;
+
+            TaskCommand::SetWorkingMode(WorkingMode::SpecialTimeout(boot_animation.clone(), 0.5))
+        }
+        IrAction::HidKey(key) => {
+            TaskCommand::SendHid(HidAction::Keys(Vec::from_slice(&[key]).unwrap()))
+        }
+        IrAction::Macro(keys) => TaskCommand::SendHid(HidAction::Keys(keys)),
+        IrAction::Chord(modifiers, keys) => TaskCommand::SendHid(HidAction::Chord(modifiers, keys)),
+        IrAction::MediaKey(usage) => TaskCommand::SendHid(HidAction::Media(usage)),
+        IrAction::TypeText(text) => TaskCommand::SendHid(HidAction::Text(text)),
+        IrAction::ShareScene => TaskCommand::ShareScene,
+    };
+
+    publisher.publish(command).await;
+}
+
 #[embassy_executor::task]
-async fn ir_receiver(ir_sensor: u8, publisher: MegaPublisher) {
-    // this is a mega hack to support the reception of two different IR protocols
-    // we unsafely use the same pin for both receivers
-
-    let mut nec_receiver: Receiver<Nec, embassy_rp::gpio::Input> = Receiver::builder()
-        .rc5()
-        .frequency(1_000_000)
-        .pin(Input::new(
-            unsafe { embassy_rp::gpio::AnyPin::steal(ir_sensor) },
-            Pull::None,
-        ))
-        .protocol()
-        .build();
-
-    let mut samsung_receiver: Receiver<SamsungNec, embassy_rp::gpio::Input> = Receiver::builder()
-        .rc5()
-        .frequency(1_000_000)
-        .pin(Input::new(
-            unsafe { embassy_rp::gpio::AnyPin::steal(ir_sensor) },
-            Pull::None,
-        ))
-        .protocol()
-        .build();
+async fn ir_receiver(mut ir_sensor: Input<'static>, publisher: MegaPublisher) {
+    let mut decoders = irprotocols::Decoders::new();
+    let mut recorder = irprotocols::RawRecorder::default();
+    let mut link = badgelink::Decoder::default();
+    let mut last_edge = Instant::now();
 
     loop {
-        samsung_receiver.pin_mut().wait_for_any_edge().await;
-        let now = Instant::now().as_ticks() as u32;
+        let timeout = if decoders.pending() || link.pending() {
+            Some(irprotocols::FRAME_END)
+        } else if recorder.pending() {
+            Some(irprotocols::RAW_FRAME_END)
+        } else {
+            None
+        };
 
-        if let Ok(Some(cmd)) = samsung_receiver.event_instant(now) {
-            publisher
-                .publish(TaskCommand::ReceivedIrNec(cmd.addr, cmd.cmd, cmd.repeat))
-                .await;
-        }
+        let edge = match timeout {
+            Some(timeout) => with_timeout(timeout, ir_sensor.wait_for_any_edge())
+                .await
+                .is_ok(),
+            None => {
+                ir_sensor.wait_for_any_edge().await;
+                true
+            }
+        };
 
-        if let Ok(Some(cmd)) = nec_receiver.event_instant(now) {
-            publisher
-                .publish(TaskCommand::ReceivedIrNec(cmd.addr, cmd.cmd, cmd.repeat))
-                .await;
+        let received = if edge {
+            let now = Instant::now();
+            let dt = (now - last_edge).as_micros().min(u32::MAX as u64) as u32;
+            last_edge = now;
+
+            // the sensor output is low while it sees the carrier
+            let mark = ir_sensor.is_low();
+            badgelink::heard();
+            recorder.edge(mark, dt);
+            link.edge(mark, dt);
+            decoders.edge(mark, dt)
+        } else {
+            if last_edge.elapsed() >= irprotocols::RAW_FRAME_END {
+                recorder.end();
+            }
+            match link.timeout() {
+                // our own frames come back to the receiver, the other badges are never us
+                Some(message) if message.badge == badgelink::id() => irecho::badge_echo(),
+                Some(message) => {
+                    info!("Received {:?}", message);
+                    publisher
+                        .publish(TaskCommand::ReceivedBadge(message, last_edge))
+                        .await;
+                }
+                None => {}
+            }
+            decoders.timeout()
+        };
+
+        if let Some(command) = received {
+            if irecho::echo(&command) {
+                debug!("Dropped the echo of {:?}", command);
+                continue;
+            }
+
+            // a record of its own, minibadge-cli logs --ir picks it out of the json log
+            info!(
+                target: "ir",
+                protocol:? = command.protocol,
+                address = command.address,
+                command = command.command,
+                repeat = command.repeat;
+                "Received"
+            );
+            publisher.publish(TaskCommand::ReceivedIr(command)).await;
         }
     }
 }
 
 #[embassy_executor::task]
 async fn ir_blaster_tsk(
-    mut ir_blaster: pwm::Pwm<'static>,
+    mut ir_blaster: IrBlaster<'static, PIO0, 1>,
     mut subscriber: MegaSubscriber,
-    publisher: MegaPublisher,
 ) {
-    use infrared::sender::Status;
-
-    fn enable_pwm(pwm: &mut pwm::Pwm, pwm_cfg: &mut pwm::Config, enable: bool) {
-        pwm_cfg.enable = enable;
-        pwm.set_config(pwm_cfg);
-
-        // why the hell does the pwm pin stay high when we disable the pwm?
-        unsafe {
-            *((0x40014000 + 11 * 8 + 0x04) as *mut u32) = if enable { 4 } else { 0x1f };
-        }
+    enum Frame {
+        Command(IrCommand),
+        Raw,
+        Badge(badgelink::Message),
     }
 
+    // rc5 and rc6 receivers tell a new press from a held button by this bit
+    let mut toggle = false;
+    // for the backoff of the badge messages, different on every badge
+    let mut rng = SmallRng::seed_from_u64(badgelink::id().into());
+
     loop {
-        if let TaskCommand::SendIrNec(addr, cmd, repeat) = subscriber.next_message_pure().await {
-            const FREQUENCY: u32 = 20000;
-
-            let mut buffer: infrared::sender::PulsedataSender<128> =
-                infrared::sender::PulsedataSender::new();
-
-            let cmd = infrared::protocol::nec::NecCommand { addr, cmd, repeat };
-            buffer.load_command::<Nec, FREQUENCY>(&cmd);
-            let mut counter = 0;
-
-            let mut pwm_cfg: pwm::Config = Default::default();
-            pwm_cfg.enable = false;
-            // system clock is 125MHz
-            // we need to do 38khz, so 125_000_000 / 38_000 = 3289
-            pwm_cfg.top = (125_000_000 / 38_000) as u16;
-            pwm_cfg.compare_b = pwm_cfg.top / 2;
-
-            let mut ticker = Ticker::every(Duration::from_hz(FREQUENCY as u64));
-            loop {
-                let status: infrared::sender::Status = buffer.tick(counter);
-                counter = counter.wrapping_add(1);
-
-                match status {
-                    Status::Transmit(v) => {
-                        enable_pwm(&mut ir_blaster, &mut pwm_cfg, v);
-                    }
-                    Status::Idle => {
-                        enable_pwm(&mut ir_blaster, &mut pwm_cfg, false);
-                        break;
-                    }
-                    Status::Error => {
-                        log::error!("Error in IR blaster");
-                        enable_pwm(&mut ir_blaster, &mut pwm_cfg, false);
-                        publisher.publish(crate::TaskCommand::Error).await;
-                        break;
-                    }
+        let (frame, carrier, frames) = match subscriber.next_message_pure().await {
+            TaskCommand::SendIr(command, carrier) => {
+                if !command.repeat {
+                    toggle = !toggle;
+                }
+                let protocol = command.protocol;
+                (
+                    Frame::Command(command),
+                    carrier.unwrap_or(irprotocols::carrier(protocol)),
+                    irprotocols::frames(protocol),
+                )
+            }
+            // a raw frame is sent once
+            TaskCommand::SendIrRaw(carrier) => {
+                (Frame::Raw, carrier.unwrap_or(irprotocols::RAW_CARRIER), 1)
+            }
+            TaskCommand::SendBadge(payload) => {
+                badgelink::wait_clear(&mut rng).await;
+                let message = badgelink::Message::new(payload.stamped());
+                (Frame::Badge(message), irprotocols::RAW_CARRIER, 1)
+            }
+            TaskCommand::RelayBadge(message) => {
+                badgelink::wait_clear(&mut rng).await;
+                let message = badgelink::Message {
+                    payload: message.payload.stamped(),
+                    ..message
                 };
+                (Frame::Badge(message), irprotocols::RAW_CARRIER, 1)
+            }
+            _ => continue,
+        };
+
+        match &frame {
+            Frame::Command(command) => irecho::sending(Some(*command)),
+            Frame::Raw => irecho::sending(None),
+            Frame::Badge(_) => {}
+        }
+
+        for _ in 0..frames {
+            let frame_start = Instant::now();
 
-                ticker.next().await;
+            let mut buffer = irprotocols::Sender::new();
+            match &frame {
+                Frame::Command(command) => irprotocols::load(&mut buffer, command, toggle),
+                Frame::Raw => irprotocols::load_raw(&mut buffer),
+                Frame::Badge(message) => buffer
+                    .load_command::<badgelink::BadgeLink, { irprotocols::SEND_FREQUENCY }>(message),
             }
-            log::info!("tx done");
-            enable_pwm(&mut ir_blaster, &mut pwm_cfg, false);
-            publisher.publish(TaskCommand::IrTxDone).await;
+
+            ir_blaster.send(carrier, buffer.buffer()).await;
+
+            Timer::at(frame_start + irprotocols::FRAME_PERIOD).await;
+        }
+        if !matches!(frame, Frame::Badge(_)) {
+            irecho::sent();
         }
     }
 }
@@ -958,9 +1052,17 @@ async fn button_tsk(mut button: Input<'static>, publisher: MegaPublisher) {
             // no timeout
             Ok(_) => {}
             // timeout
+            // the long press is only known on release, a very long one must not go through it
             Err(_) => {
-                publisher.publish(TaskCommand::LongButtonPress).await;
-                button.wait_for_high().await;
+                if with_timeout(Duration::from_millis(3000), button.wait_for_high())
+                    .await
+                    .is_ok()
+                {
+                    publisher.publish(TaskCommand::LongButtonPress).await;
+                } else {
+                    publisher.publish(TaskCommand::VeryLongButtonPress).await;
+                    button.wait_for_high().await;
+                }
             }
         }
 
@@ -0,0 +1,329 @@
+use core::iter;
+
+use embassy_time::Instant;
+use heapless::Vec;
+use minibadge_protocol::{Message, MessageBuffer, IR_BINDINGS_SIZE};
+
+// control change numbers understood by the badge
+pub const CC_BRIGHTNESS: u8 = 7; // channel volume
+pub const CC_HUE: u8 = 16; // general purpose 1
+pub const CC_SPEED: u8 = 17; // general purpose 2
+
+// midi clock sends 24 pulses per quarter note
+const CLOCKS_PER_BEAT: u8 = 24;
+
+// every scene is designed to look right at this tempo
+pub const REFERENCE_BPM: f32 = 120.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MidiMessage {
+    NoteOn { channel: u8, note: u8, velocity: u8 },
+    NoteOff { channel: u8, note: u8 },
+    ControlChange { channel: u8, control: u8, value: u8 },
+    ProgramChange { channel: u8, program: u8 },
+    Clock,
+    Start,
+    Continue,
+    Stop,
+}
+
+impl MidiMessage {
+    // parses a 4 byte USB-MIDI event packet
+    // the first byte is the cable number and the code index number (CIN),
+    // the other three are a normal midi message, padded with zeros
+    pub fn parse(packet: &[u8; 4]) -> Option<Self> {
+        let [header, status, data1, data2] = *packet;
+
+        let cin = header & 0x0f;
+        let channel = status & 0x0f;
+        let data1 = data1 & 0x7f;
+        let data2 = data2 & 0x7f;
+
+        match cin {
+            0x8 => Some(MidiMessage::NoteOff {
+                channel,
+                note: data1,
+            }),
+            // a note on with zero velocity is a note off
+            0x9 if data2 == 0 => Some(MidiMessage::NoteOff {
+                channel,
+                note: data1,
+            }),
+            0x9 => Some(MidiMessage::NoteOn {
+                channel,
+                note: data1,
+                velocity: data2,
+            }),
+            0xb => Some(MidiMessage::ControlChange {
+                channel,
+                control: data1,
+                value: data2,
+            }),
+            0xc => Some(MidiMessage::ProgramChange {
+                channel,
+                program: data1,
+            }),
+            // single byte messages, real time messages end up here
+            0xf => match status {
+                0xf8 => Some(MidiMessage::Clock),
+                0xfa => Some(MidiMessage::Start),
+                0xfb => Some(MidiMessage::Continue),
+                0xfc => Some(MidiMessage::Stop),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+
+    // encodes the message as a 4 byte USB-MIDI event packet on cable 0
+    pub fn to_packet(&self) -> [u8; 4] {
+        match *self {
+            MidiMessage::NoteOn {
+                channel,
+                note,
+                velocity,
+            } => [0x09, 0x90 | channel, note, velocity],
+            MidiMessage::NoteOff { channel, note } => [0x08, 0x80 | channel, note, 0],
+            MidiMessage::ControlChange {
+                channel,
+                control,
+                value,
+            } => [0x0b, 0xb0 | channel, control, value],
+            MidiMessage::ProgramChange { channel, program } => [0x0c, 0xc0 | channel, program, 0],
+            MidiMessage::Clock => [0x0f, 0xf8, 0, 0],
+            MidiMessage::Start => [0x0f, 0xfa, 0, 0],
+            MidiMessage::Continue => [0x0f, 0xfb, 0, 0],
+            MidiMessage::Stop => [0x0f, 0xfc, 0, 0],
+        }
+    }
+}
+
+// measures the tempo of the incoming midi clock
+#[derive(Default)]
+pub struct MidiClock {
+    beat_start: Option<Instant>,
+    clocks: u8,
+}
+
+impl MidiClock {
+    // restart counting from the beginning of a beat
+    pub fn start(&mut self) {
+        self.beat_start = Some(Instant::now());
+        self.clocks = 0;
+    }
+
+    // returns the tempo in bpm at the end of every beat
+    pub fn tick(&mut self) -> Option<f32> {
+        let Some(beat_start) = self.beat_start else {
+            self.start();
+            return None;
+        };
+
+        self.clocks += 1;
+        if self.clocks < CLOCKS_PER_BEAT {
+            return None;
+        }
+
+        let now = Instant::now();
+        let beat = (now - beat_start).as_micros() as f32 / 1_000_000.0;
+        self.beat_start = Some(now);
+        self.clocks = 0;
+
+        if beat > 0.0 {
+            Some(60.0 / beat)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MidiOutKind {
+    #[default]
+    None,
+    Note,
+    ControlChange,
+    ProgramChange,
+}
+
+// what the badge sends to the host when something happens
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MidiOutEvent {
+    pub kind: MidiOutKind,
+    pub channel: u8,
+    pub number: u8, // note, controller or program
+    pub value: u8,  // velocity or controller value
+}
+
+impl MidiOutEvent {
+    // offset is added to the number, so every IR command gets its own note
+    pub fn message(&self, offset: u8) -> Option<MidiMessage> {
+        let channel = self.channel & 0x0f;
+        let number = self.number.wrapping_add(offset) & 0x7f;
+        let value = self.value & 0x7f;
+
+        match self.kind {
+            MidiOutKind::None => None,
+            MidiOutKind::Note => Some(MidiMessage::NoteOn {
+                channel,
+                note: number,
+                velocity: value,
+            }),
+            MidiOutKind::ControlChange => Some(MidiMessage::ControlChange {
+                channel,
+                control: number,
+                value,
+            }),
+            MidiOutKind::ProgramChange => Some(MidiMessage::ProgramChange {
+                channel,
+                program: number,
+            }),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct MidiOutMapping {
+    pub short_press: MidiOutEvent,
+    pub long_press: MidiOutEvent,
+    pub ir_received: MidiOutEvent,
+}
+
+impl Default for MidiOutMapping {
+    fn default() -> Self {
+        Self {
+            // middle C and the D above it on channel 1
+            short_press: MidiOutEvent {
+                kind: MidiOutKind::Note,
+                channel: 0,
+                number: 60,
+                value: 100,
+            },
+            long_press: MidiOutEvent {
+                kind: MidiOutKind::Note,
+                channel: 0,
+                number: 62,
+                value: 100,
+            },
+            // IR commands are notes on channel 2
+            ir_received: MidiOutEvent {
+                kind: MidiOutKind::Note,
+                channel: 1,
+                number: 0,
+                value: 100,
+            },
+        }
+    }
+}
+
+// sysex messages tunnel the capnp control messages, for hosts that can only talk midi
+// F0 <manufacturer id> <capnp message packed in 7 bit bytes> F7
+pub const SYSEX_MANUFACTURER_ID: u8 = 0x7d; // non-commercial use
+
+// room for the largest message, a whole table of ir bindings, packed
+pub const SYSEX_MAX_LEN: usize = 3 + IR_BINDINGS_SIZE.div_ceil(7) * 8;
+
+#[derive(Default)]
+pub struct SysexReceiver {
+    buf: Vec<u8, SYSEX_MAX_LEN>,
+    overflow: bool,
+}
+
+impl SysexReceiver {
+    // feeds a USB-MIDI event packet, returns the 7 bit payload when a sysex for us is complete
+    pub fn push(&mut self, packet: &[u8; 4]) -> Option<&[u8]> {
+        let data = match packet[0] & 0x0f {
+            // sysex starts or continues
+            0x4 => &packet[1..4],
+            // sysex ends with 1, 2 or 3 bytes
+            0x5 => &packet[1..2],
+            0x6 => &packet[1..3],
+            0x7 => &packet[1..4],
+            _ => return None,
+        };
+
+        if data[0] == 0xf0 {
+            self.buf.clear();
+            self.overflow = false;
+        }
+
+        if self.buf.extend_from_slice(data).is_err() {
+            self.overflow = true;
+        }
+
+        if data.last() != Some(&0xf7) {
+            return None;
+        }
+
+        let complete = !self.overflow
+            && self.buf.len() >= 3
+            && self.buf[0] == 0xf0
+            && self.buf[1] == SYSEX_MANUFACTURER_ID;
+
+        if !complete {
+            self.buf.clear();
+            return None;
+        }
+
+        Some(&self.buf[2..self.buf.len() - 1])
+    }
+}
+
+// every group of 7 bytes is sent as 8 bytes,
+// the first one holds the most significant bits of the other 7
+// a sysex holds one whole message, nothing before or after it
+pub fn decode_7bit<const N: usize>(
+    input: &[u8],
+    message: &mut MessageBuffer<N>,
+) -> Result<Message, capnp::Error> {
+    let failed = || capnp::Error::from_kind(capnp::ErrorKind::Failed);
+
+    message.clear();
+    let mut result = None;
+    for (msbs, bytes) in input.chunks(8).filter_map(|group| group.split_first()) {
+        let mut decoded = [0; 7];
+        for (i, (out, byte)) in decoded.iter_mut().zip(bytes).enumerate() {
+            *out = byte | (((msbs >> i) & 1) << 7);
+        }
+
+        for decoded in message.push(&decoded[..bytes.len()]) {
+            if result.replace(decoded).is_some() {
+                return Err(failed());
+            }
+        }
+    }
+
+    match result {
+        Some(result) if message.is_empty() => result,
+        _ => Err(failed()),
+    }
+}
+
+// the usb-midi event packets of a sysex holding data, on cable 0
+pub fn sysex_events(data: &[u8]) -> impl Iterator<Item = [u8; 4]> + '_ {
+    let packed = data.chunks(7).flat_map(|group| {
+        let msbs = group
+            .iter()
+            .enumerate()
+            .fold(0, |msbs, (i, byte)| msbs | ((byte >> 7) << i));
+
+        iter::once(msbs).chain(group.iter().map(|byte| byte & 0x7f))
+    });
+    let mut bytes = [0xf0, SYSEX_MANUFACTURER_ID]
+        .into_iter()
+        .chain(packed)
+        .chain(iter::once(0xf7));
+
+    // 3 bytes each, the last one tells how many it holds
+    iter::from_fn(move || {
+        let mut event = [0x4, 0, 0, 0];
+        for n in 1..4 {
+            event[n] = bytes.next()?;
+            if event[n] == 0xf7 {
+                event[0] = 0x4 + n as u8;
+                break;
+            }
+        }
+        Some(event)
+    })
+}
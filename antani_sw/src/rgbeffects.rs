@@ -1,9 +1,12 @@
 use core::f64;
-use heapless::Vec;
+use heapless::{String, Vec};
+use minibadge_protocol::MAX_BADGE_TEXT;
 use num_traits::real::Real;
 use rand::{rngs::SmallRng, Rng};
 
-use crate::{scenes::PATTERNS, LedMatrix, LedPixel, RawFramebuffer};
+use crate::{
+    scenes::PATTERNS, LedMatrix, LedPixel, RawFramebuffer, LED_MATRIX_SIZE, LED_MATRIX_WIDTH,
+};
 
 pub type LedPattern = u16;
 
@@ -16,10 +19,25 @@ pub struct RenderCommand {
     pub time_offset: f64,
 }
 
+// latest spectrum data sent by the host, one band per led
+#[derive(Clone, Copy, Default, Debug)]
+pub struct AudioLevels {
+    pub bands: [u8; LED_MATRIX_SIZE],
+    pub beat: bool,
+}
+
+impl AudioLevels {
+    fn average(&self) -> f64 {
+        self.bands.iter().map(|b| *b as f64).sum::<f64>() / self.bands.len() as f64
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct ShaderPersistentData {
     pub frame_counter: u32,
     pub lowpass: RawFramebuffer,
+    pub audio: AudioLevels,
+    pub last_beat: f64,
 }
 
 pub struct RenderManager {
@@ -75,7 +93,7 @@ impl RenderManager {
     }
 }
 
-fn hsl2rgb(h: f64, s: f64, l: f64) -> LedPixel {
+pub fn hsl2rgb(h: f64, s: f64, l: f64) -> LedPixel {
     let h = h * 360.0;
     let c = (1.0 - (2.0 * l - 1.0).abs()) * s;
     let x = c * (1.0 - ((h / 60.0) % 2.0 - 1.0).abs());
@@ -105,6 +123,8 @@ pub enum FragmentShader {
     LowPass(f32),         // tau
     LowPassWithPeak(f32), // tau
     Rainbow2D(f32),       // speed
+    BeatFlash(f32),       // decay
+    AudioBands,
 }
 
 impl FragmentShader {
@@ -177,6 +197,24 @@ impl FragmentShader {
                 let h = (x as f64 + y as f64) / 16.0 + t;
                 hsl2rgb(h % 1.0, 1.0, 0.5)
             }
+
+            FragmentShader::BeatFlash(decay) => {
+                // full brightness on the beat, then fade out
+
+                let since_beat = (t - renderman.persistent_data.last_beat).max(0.0);
+                let l = (-since_beat * *decay as f64).exp();
+                let c = (color.r as f64 * l, color.g as f64 * l, color.b as f64 * l);
+                (c.0 as u8, c.1 as u8, c.2 as u8).into()
+            }
+
+            FragmentShader::AudioBands => {
+                // every led shows the level of its own frequency band
+
+                let band = renderman.persistent_data.audio.bands[y * LED_MATRIX_WIDTH + x];
+                let l = band as f64 / 255.0;
+                let c = (color.r as f64 * l, color.g as f64 * l, color.b as f64 * l);
+                (c.0 as u8, c.1 as u8, c.2 as u8).into()
+            }
         }
     }
 }
@@ -211,9 +249,11 @@ impl ColorPalette {
 pub enum Pattern {
     Simple(LedPattern),
     Text(&'static str, f32),                      // text, speed
+    Message(String<MAX_BADGE_TEXT>, f32),         // text received from another badge, speed
     Animation(&'static [LedPattern], f32),        // pattern, speed
     AnimationReverse(&'static [LedPattern], f32), // pattern, speed
     AnimationRandom(&'static [LedPattern], u16),  // pattern, decimation
+    VuMeter,
 }
 
 impl Default for Pattern {
@@ -222,18 +262,23 @@ impl Default for Pattern {
     }
 }
 
+// the letter of the text at time t, the font only has letters
+fn scroll(text: &str, t: f64) -> LedPattern {
+    if text.is_empty() {
+        return 0;
+    }
+
+    let char = text.as_bytes()[t as usize % text.len()].to_ascii_uppercase();
+    let index = char.wrapping_sub(b'A') as usize;
+    *PATTERNS.get().font.get(index).unwrap_or(&0)
+}
+
 impl Pattern {
     fn render(&self, t: f64, renderman: &mut RenderManager) -> LedPattern {
         match self {
             Pattern::Simple(pattern) => *pattern,
-            Pattern::Text(text, speed) => {
-                let idx = (t * *speed as f64) as usize % text.len();
-                let char = text.as_bytes()[idx] as char;
-                let char = char.to_ascii_uppercase();
-                let index = char as usize - 'A' as usize;
-                let pattern = PATTERNS.get().font.get(index).unwrap_or(&0);
-                *pattern
-            }
+            Pattern::Text(text, speed) => scroll(text, t * *speed as f64),
+            Pattern::Message(text, speed) => scroll(text, t * *speed as f64),
             Pattern::Animation(pattern, speed) => {
                 let idx = (t * *speed as f64) as usize % pattern.len();
                 let pattern = &pattern[idx];
@@ -258,6 +303,12 @@ impl Pattern {
                     0
                 }
             }
+            Pattern::VuMeter => {
+                // light up as many leds as the average audio level
+                let level = renderman.persistent_data.audio.average();
+                let lit = (level * (LED_MATRIX_SIZE + 1) as f64 / 256.0) as usize;
+                ((1u32 << lit.min(LED_MATRIX_SIZE)) - 1) as LedPattern
+            }
         }
     }
 }
@@ -235,6 +235,33 @@ pub fn scenes() -> Scenes {
             ..Default::default()
         }])
         .unwrap(),
+        // audio vu meter
+        Vec::from_slice(&[RenderCommand {
+            effect: Pattern::VuMeter,
+            pattern_shaders: Vec::from_slice(&[FragmentShader::Rainbow2D(0.5)]).unwrap(),
+            ..Default::default()
+        }])
+        .unwrap(),
+        // audio spectrum, one band per led
+        Vec::from_slice(&[RenderCommand {
+            effect: Pattern::Simple(patterns.all_on),
+            pattern_shaders: Vec::from_slice(&[
+                FragmentShader::Rainbow2D(0.5),
+                FragmentShader::AudioBands,
+                FragmentShader::LowPassWithPeak(10.0),
+            ])
+            .unwrap(),
+            ..Default::default()
+        }])
+        .unwrap(),
+        // audio beat flash
+        Vec::from_slice(&[RenderCommand {
+            effect: Pattern::Simple(patterns.all_on),
+            color: ColorPalette::Rainbow(0.1),
+            pattern_shaders: Vec::from_slice(&[FragmentShader::BeatFlash(4.0)]).unwrap(),
+            ..Default::default()
+        }])
+        .unwrap(),
         // off
         Vec::from_slice(&[RenderCommand {
             effect: Pattern::Simple(0),
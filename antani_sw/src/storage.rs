@@ -0,0 +1,94 @@
+use embassy_rp::flash::{self, Blocking, Flash, ERASE_SIZE};
+use embassy_rp::peripherals::FLASH;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use log::{error, info};
+
+use crate::{badgelink, contacts, ir, FLASH_SIZE};
+
+pub type BadgeFlash = Flash<'static, FLASH, Blocking, FLASH_SIZE>;
+
+// the last sectors of the flash, memory.x keeps the firmware out of them
+const SECTORS: usize = 4;
+
+// what is kept in flash, one sector each
+#[derive(Clone, Copy, Debug)]
+pub enum Setting {
+    IrBindings = 0,
+    Contacts = 1,
+    Relay = 2,
+    ButtonAction = 3,
+}
+
+// a sector starts with the magic and the length of the data,
+// so an erased sector is not taken for settings
+const MAGIC: u32 = 0x3153_424d; // "MBS1"
+const HEADER_LEN: usize = 8;
+
+// the capnp reader wants the data aligned
+#[repr(C, align(8))]
+pub struct Aligned<const N: usize>(pub [u8; N]);
+
+static SAVE: Channel<CriticalSectionRawMutex, Setting, 4> = Channel::new();
+
+fn offset(setting: Setting) -> u32 {
+    (FLASH_SIZE - (SECTORS - setting as usize) * ERASE_SIZE) as u32
+}
+
+// reads the data of a setting in buf, returns its length
+pub fn load(flash: &mut BadgeFlash, setting: Setting, buf: &mut [u8]) -> Option<usize> {
+    let mut header = [0; HEADER_LEN];
+    flash.blocking_read(offset(setting), &mut header).ok()?;
+
+    let magic = u32::from_le_bytes([header[0], header[1], header[2], header[3]]);
+    let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+    if magic != MAGIC || len > buf.len() {
+        return None;
+    }
+
+    flash
+        .blocking_read(offset(setting) + HEADER_LEN as u32, &mut buf[..len])
+        .ok()?;
+
+    Some(len)
+}
+
+pub fn save(flash: &mut BadgeFlash, setting: Setting, data: &[u8]) -> Result<(), flash::Error> {
+    erase(flash, setting)?;
+
+    let mut header = [0; HEADER_LEN];
+    header[..4].copy_from_slice(&MAGIC.to_le_bytes());
+    header[4..].copy_from_slice(&(data.len() as u32).to_le_bytes());
+
+    flash.blocking_write(offset(setting), &header)?;
+    flash.blocking_write(offset(setting) + HEADER_LEN as u32, data)
+}
+
+pub fn erase(flash: &mut BadgeFlash, setting: Setting) -> Result<(), flash::Error> {
+    flash.blocking_erase(offset(setting), offset(setting) + ERASE_SIZE as u32)
+}
+
+// asks the storage task to write the setting, the flash is too slow for the caller to wait
+pub fn request_save(setting: Setting) {
+    // a save already queued writes the latest data anyway
+    let _ = SAVE.try_send(setting);
+}
+
+#[embassy_executor::task]
+pub async fn storage_task(mut flash: BadgeFlash) {
+    loop {
+        let setting = SAVE.receive().await;
+
+        let result = match setting {
+            Setting::IrBindings => ir::save(&mut flash),
+            Setting::Contacts => contacts::save(&mut flash),
+            Setting::Relay => badgelink::save(&mut flash),
+            Setting::ButtonAction => ir::save_button(&mut flash),
+        };
+
+        match result {
+            Ok(()) => info!("Saved {:?}", setting),
+            Err(e) => error!("Error saving {:?}: {:?}", setting, e),
+        }
+    }
+}
@@ -0,0 +1,167 @@
+// the animation time, kept in step with the other badges over infrared
+//
+// the badge with the lowest id leads: it tells its time every SYNC_PERIOD, and stops as soon
+// as it hears the time of a lower id, from the badge itself or a relay. the others move their
+// clock towards the time of the leader slowly, so the scenes don't jump, counting the time the
+// frame took to arrive
+
+use core::cell::Cell;
+use core::sync::atomic::{AtomicU32, Ordering};
+
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex;
+use embassy_time::{Duration, Instant, Timer};
+use log::{debug, info};
+use rand::rngs::SmallRng;
+use rand::{Rng, SeedableRng};
+
+use crate::{badgelink, MegaPublisher, TaskCommand};
+
+// how often the leader tells its time, plus up to the jitter
+const SYNC_PERIOD: Duration = Duration::from_secs(20);
+const SYNC_JITTER_MS: u64 = 2_000;
+
+// a leader that missed three syncs is gone, the next lowest id takes over
+const LEADER_TIMEOUT: Duration = Duration::from_secs(70);
+
+// seconds of correction for a second of time, the scenes run up to 10% faster or slower
+const SLEW_RATE: f64 = 0.1;
+// errors larger than this are corrected at once, after a restart or a reset of the time
+const MAX_SLEW: f64 = 1.0;
+
+#[derive(Clone, Copy)]
+struct Clock {
+    // uptime in seconds at animation time zero, and the speed of the animations
+    offset: f64,
+    scale: f64,
+    // correction still to apply to the animation time, and the uptime it was last applied
+    slew: f64,
+    slewed: f64,
+}
+
+impl Clock {
+    fn time(&self, uptime: f64) -> f64 {
+        (uptime - self.offset) * self.scale
+    }
+
+    fn set(&mut self, uptime: f64, t: f64) {
+        self.offset = uptime - t / self.scale;
+        self.slew = 0.0;
+    }
+
+    fn advance(&mut self, uptime: f64) {
+        let step = (SLEW_RATE * (uptime - self.slewed)).min(self.slew.abs());
+        let step = step.copysign(self.slew);
+
+        self.offset -= step / self.scale;
+        self.slew -= step;
+        self.slewed = uptime;
+    }
+}
+
+static CLOCK: Mutex<CriticalSectionRawMutex, Cell<Clock>> = Mutex::new(Cell::new(Clock {
+    offset: 0.0,
+    scale: 1.0,
+    slew: 0.0,
+    slewed: 0.0,
+}));
+
+// the badge we follow, u32::MAX for none, and the uptime in milliseconds we last heard it
+static LEADER: AtomicU32 = AtomicU32::new(u32::MAX);
+static LEADER_HEARD: AtomicU32 = AtomicU32::new(0);
+
+fn uptime() -> f64 {
+    Instant::now().as_micros() as f64 / 1_000_000.0
+}
+
+fn update<R>(f: impl FnOnce(&mut Clock, f64) -> R) -> R {
+    CLOCK.lock(|c| {
+        let mut clock = c.get();
+        let uptime = uptime();
+        clock.advance(uptime);
+        let result = f(&mut clock, uptime);
+        c.set(clock);
+        result
+    })
+}
+
+// the animation time in seconds
+pub fn now() -> f64 {
+    update(|clock, uptime| clock.time(uptime))
+}
+
+// the animation time in milliseconds, as told to the other badges
+// without the slowness of the slew, it's where our clock is going
+pub fn millis() -> u32 {
+    update(|clock, uptime| ((clock.time(uptime) + clock.slew) * 1000.0) as u32)
+}
+
+pub fn reset() {
+    update(|clock, uptime| clock.set(uptime, 0.0));
+}
+
+pub fn set_scale(scale: f64) {
+    // move the offset so the time does not jump when changing speed
+    update(|clock, uptime| {
+        let t = clock.time(uptime);
+        clock.scale = scale;
+        clock.set(uptime, t);
+    });
+}
+
+fn leader() -> Option<u32> {
+    let now = Instant::now().as_millis() as u32;
+    let heard = now.wrapping_sub(LEADER_HEARD.load(Ordering::Relaxed));
+
+    Some(LEADER.load(Ordering::Relaxed))
+        .filter(|_| heard < LEADER_TIMEOUT.as_millis() as u32)
+        .filter(|&leader| leader != u32::MAX)
+}
+
+// the time of the leader badge, latency the time since the frame was sent
+// true when we follow it
+pub fn received(badge: u32, ms: u32, latency: Duration) -> bool {
+    // only the lowest id is followed, so two leaders don't pull us back and forth,
+    // and our own time relayed back is not news
+    if badge >= badgelink::id() || leader().is_some_and(|leader| badge > leader) {
+        debug!("Not following the time of badge {:08x}", badge);
+        return false;
+    }
+    LEADER.store(badge, Ordering::Relaxed);
+    LEADER_HEARD.store(Instant::now().as_millis() as u32, Ordering::Relaxed);
+
+    let error = update(|clock, uptime| {
+        let t = ms as f64 / 1000.0 + latency.as_micros() as f64 / 1_000_000.0 * clock.scale;
+        let error = t - clock.time(uptime);
+
+        if error.abs() > MAX_SLEW {
+            clock.set(uptime, t);
+        } else {
+            clock.slew = error;
+        }
+        error
+    });
+
+    if error.abs() > MAX_SLEW {
+        info!("Took the time of badge {:08x}, {} s off", badge, error);
+    } else {
+        debug!("Following the time of badge {:08x}, {} s off", badge, error);
+    }
+    true
+}
+
+// tells our time while no lower id is in sight
+#[embassy_executor::task]
+pub async fn sync_task(publisher: MegaPublisher) {
+    let mut rng = SmallRng::seed_from_u64(u64::from(badgelink::id()) << 16);
+
+    loop {
+        let jitter = Duration::from_millis(rng.gen_range(0..SYNC_JITTER_MS));
+        Timer::after(SYNC_PERIOD + jitter).await;
+
+        if leader().is_none() {
+            let time = badgelink::Payload::Time(millis(), badgelink::id());
+            publisher.publish(TaskCommand::SendBadge(time)).await;
+        }
+    }
+}
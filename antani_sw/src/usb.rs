@@ -1,18 +1,30 @@
-use defmt::{panic, warn};
+use core::fmt::Write;
+
+use defmt::warn;
 use embassy_futures::join::join;
+use embassy_futures::select::{select, Either};
 use embassy_rp::bind_interrupts;
 use embassy_rp::peripherals::USB;
 use embassy_rp::usb::{Driver, Instance, InterruptHandler};
-use embassy_time::{Duration, Timer};
+use embassy_sync::blocking_mutex::raw::{CriticalSectionRawMutex, NoopRawMutex};
+use embassy_sync::channel::{Channel, TrySendError};
+use embassy_sync::mutex::Mutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
 use embassy_usb::class::cdc_acm::{CdcAcmClass, State};
-use embassy_usb::class::hid::{self, HidWriter};
-use heapless::Vec;
-use log::{error, info};
+use embassy_usb::class::hid::{HidWriter, State as HidState};
+use heapless::{String, Vec};
+use log::{debug, error, info};
+use minibadge_protocol::{Command, IrCommand, IrRaw, Message, MessageBuffer, IR_BINDINGS_SIZE};
 use static_cell::StaticCell;
-use usbd_hid::descriptor::{KeyboardReport, SerializedDescriptor};
 
+use crate::build_info;
+use crate::cdc::{self, ControlPort};
+use crate::hid;
+use crate::logging;
+use crate::midi::{self, MidiClock, MidiMessage, MidiOutMapping, SysexReceiver};
 use crate::{MegaPublisher, MegaSubscriber, TaskCommand};
-use embassy_usb::class::midi::MidiClass;
+use embassy_usb::class::midi::{MidiClass, Receiver, Sender};
 use embassy_usb::driver::EndpointError;
 use embassy_usb::{Builder, Config};
 
@@ -22,23 +34,80 @@ bind_interrupts!(struct Irqs {
     USBCTRL_IRQ => InterruptHandler<USB>;
 });
 
-static STATE: StaticCell<State> = StaticCell::new();
+static STATE: StaticCell<cdc::State> = StaticCell::new();
 static LOGGER_STATE: StaticCell<State> = StaticCell::new();
-static HID_STATE: StaticCell<hid::State> = StaticCell::new();
+static HID_STATE: StaticCell<HidState> = StaticCell::new();
 static CONFIG_DESCRIPTOR: StaticCell<[u8; 512]> = StaticCell::new();
 static BOS_DESCRIPTOR: StaticCell<[u8; 256]> = StaticCell::new();
 static CONTROL_BUF: StaticCell<[u8; 64]> = StaticCell::new();
+static SERIAL_NUMBER: StaticCell<String<16>> = StaticCell::new();
+
+// the midi messages for the host, sent apart from the subscriber of MEGA_CHANNEL:
+// a publisher waits for the slowest subscriber, the note offs and a host that does not
+// read would stall every task. the messages that don't fit are dropped
+static MIDI_OUT: Channel<CriticalSectionRawMutex, MidiMessage, 8> = Channel::new();
+
+// the hid actions to type, for the same reason: a text takes seconds to type, and the
+// subscriber would hold back MEGA_CHANNEL all the while
+static HID_OUT: Channel<CriticalSectionRawMutex, hid::HidAction, 4> = Channel::new();
+
+// a raw ir capture waits up to 10 seconds for a remote. it runs apart, so the port that asked
+// for it keeps reading the other messages, and the frame goes back on that port
+static CAPTURE_REQUEST: Signal<CriticalSectionRawMutex, ReplyPort> = Signal::new();
+static CONTROL_CAPTURED: Signal<CriticalSectionRawMutex, IrRaw> = Signal::new();
+static MIDI_CAPTURED: Signal<CriticalSectionRawMutex, IrRaw> = Signal::new();
+
+#[derive(Clone, Copy)]
+enum ReplyPort {
+    Control,
+    Midi,
+}
+
+impl ReplyPort {
+    fn captured(self) -> &'static Signal<CriticalSectionRawMutex, IrRaw> {
+        match self {
+            ReplyPort::Control => &CONTROL_CAPTURED,
+            ReplyPort::Midi => &MIDI_CAPTURED,
+        }
+    }
+}
+
+// pid.codes open source vendor id
+const PID_CODES_VID: u16 = 0x1209;
+// one of the pid.codes test product ids, the README tells why
+const PID_CODES_TEST_PID: u16 = 0x0001;
+
+// minibadge-cli looks for these, keep them in sync
+pub const USB_VID: u16 = PID_CODES_VID;
+pub const USB_PID: u16 = PID_CODES_TEST_PID;
+
+// the serial number is the unique id of the flash chip, in hex
+pub fn serial_number(uid: &[u8; 8]) -> &'static str {
+    let serial = SERIAL_NUMBER.init(String::new());
+    for byte in uid {
+        // 16 characters always fit
+        write!(serial, "{:02X}", byte).ok();
+    }
+    serial.as_str()
+}
 
 #[embassy_executor::task]
-pub async fn usb_main(usb: USB, publisher: MegaPublisher, mut subscriber: MegaSubscriber) {
+pub async fn usb_main(
+    usb: USB,
+    serial: &'static str,
+    publisher: MegaPublisher,
+    mut subscriber: MegaSubscriber,
+) {
     // Create the driver, from the HAL.
     let driver = Driver::new(usb, Irqs);
 
     // Create embassy-usb Config
-    let mut config = Config::new(0x0000, 0x0000);
+    let mut config = Config::new(USB_VID, USB_PID);
     config.manufacturer = Some("ESC");
-    config.product = Some("Mini Badge");
-    config.serial_number = Some("12345678");
+    // "Mini Badge 0.1.0-1a2b3c4d", and the version again in bcdDevice
+    config.product = Some(build_info::PRODUCT);
+    config.device_release = build_info::DEVICE_RELEASE;
+    config.serial_number = Some(serial);
     config.max_power = 100;
     config.max_packet_size_0 = 64;
 
@@ -62,95 +131,144 @@ pub async fn usb_main(usb: USB, publisher: MegaPublisher, mut subscriber: MegaSu
         control_buf,
     );
 
-    let mut midi_class = MidiClass::new(&mut builder, 1, 1, 64);
+    let midi_class = MidiClass::new(&mut builder, 1, 1, 64);
+    let (midi_sender, mut midi_receiver) = midi_class.split();
+    // the events of the badge and the replies to the sysex requests take turns
+    let midi_sender = Mutex::<NoopRawMutex, _>::new(midi_sender);
+    let mut midi_subscriber = crate::MEGA_CHANNEL.subscriber().unwrap();
 
-    let state = STATE.init(State::new());
+    let state = STATE.init(cdc::State::new());
     let logger_state = LOGGER_STATE.init(State::new());
-    let hid_state = HID_STATE.init(hid::State::new());
+    let hid_state = HID_STATE.init(HidState::new());
 
     let config = embassy_usb::class::hid::Config {
-        report_descriptor: KeyboardReport::desc(),
+        report_descriptor: hid::REPORT_DESCRIPTOR,
         request_handler: None,
-        poll_ms: 60,
+        // a short chord or text is missed by the host if it polls slower than we type
+        poll_ms: 10,
         max_packet_size: 64,
     };
-    let mut hid_writer = HidWriter::<_, 8>::new(&mut builder, hid_state, config);
+    let mut hid_writer = HidWriter::<_, { hid::REPORT_LEN }>::new(&mut builder, hid_state, config);
 
-    let mut cdc_class = CdcAcmClass::new(&mut builder, state, 64);
+    // the cli finds the control port by its name, or by its interface number (3) where the
+    // names are hidden: keep the order of the classes. the other cdc port is the logger
+    let mut cdc_class = ControlPort::new(&mut builder, state, 64);
     let logger_class = CdcAcmClass::new(&mut builder, logger_state, 64);
 
     let log_fut = embassy_usb_logger::with_custom_style!(
         1024,
-        log::LevelFilter::Info,
+        logging::DEFAULT_LEVEL,
         logger_class,
-        |record, writer| {
-            use core::fmt::Write;
-            let level = record.level().as_str();
-            write!(writer, "[{level}] {}\r\n", record.args()).unwrap();
-        }
+        |record, writer| logging::write_record(record, writer)
     );
 
     let mut usb = builder.build();
 
     let usb_fut = usb.run();
 
-    let hid_fut = async {
+    let hid_events_fut = async {
         loop {
-            if let TaskCommand::SendHidKeyboard(cmd) = subscriber.next_message_pure().await {
-                let report = KeyboardReport {
-                    keycodes: [cmd as u8, 0, 0, 0, 0, 0],
-                    leds: 0,
-                    modifier: 0,
-                    reserved: 0,
-                };
-                // Send the report.
-                match hid_writer.write_serialize(&report).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        warn!("Failed to send report: {:?}", e);
-                        publisher.publish(TaskCommand::Error).await;
-                    }
-                };
-                Timer::after(Duration::from_millis(100)).await;
+            if let TaskCommand::SendHid(action) = subscriber.next_message_pure().await {
+                if let Err(TrySendError::Full(action)) = HID_OUT.try_send(action) {
+                    debug!("HID queue full, dropped {:?}", action);
+                }
+            }
+        }
+    };
 
-                let report = KeyboardReport {
-                    keycodes: [0, 0, 0, 0, 0, 0],
-                    leds: 0,
-                    modifier: 0,
-                    reserved: 0,
-                };
-                match hid_writer.write_serialize(&report).await {
-                    Ok(()) => {}
-                    Err(e) => {
-                        warn!("Failed to send report: {:?}", e);
-                        publisher.publish(TaskCommand::Error).await;
-                    }
-                };
+    let hid_fut = async {
+        loop {
+            let action = HID_OUT.receive().await;
+            if let Err(e) = hid::send(&mut hid_writer, &action).await {
+                warn!("Failed to send report: {:?}", e);
+                publisher.publish(TaskCommand::Error).await;
             }
         }
     };
 
     let midi_fut = async {
         loop {
-            midi_class.wait_connection().await;
+            midi_receiver.wait_connection().await;
             info!("Connected");
-            let _ = midi_echo(&mut midi_class, &publisher).await;
+            let _ = midi_echo(&mut midi_receiver, &midi_sender, serial, &publisher).await;
             info!("Disconnected");
         }
     };
 
+    let midi_events_fut = async {
+        let mut mapping = MidiOutMapping::default();
+        loop {
+            let (event, offset) = match midi_subscriber.next_message_pure().await {
+                TaskCommand::SetMidiMapping(m) => {
+                    mapping = m;
+                    continue;
+                }
+                TaskCommand::ShortButtonPress => (mapping.short_press, 0),
+                TaskCommand::LongButtonPress => (mapping.long_press, 0),
+                TaskCommand::ReceivedIr(IrCommand {
+                    command,
+                    repeat: false,
+                    ..
+                }) => (mapping.ir_received, command as u8),
+                _ => continue,
+            };
+
+            if let Some(message) = event.message(offset) {
+                if MIDI_OUT.try_send(message).is_err() {
+                    debug!("MIDI output full, dropped {:?}", message);
+                }
+            }
+        }
+    };
+
+    let midi_out_fut = async {
+        loop {
+            let message = MIDI_OUT.receive().await;
+
+            // nobody is listening, don't bother with the note off
+            if midi_send(&midi_sender, &message.to_packet()).await.is_err() {
+                continue;
+            }
+
+            if let MidiMessage::NoteOn { channel, note, .. } = message {
+                Timer::after(Duration::from_millis(100)).await;
+                let note_off = MidiMessage::NoteOff { channel, note };
+                let _ = midi_send(&midi_sender, &note_off.to_packet()).await;
+            }
+        }
+    };
+
     let control_fut = async {
         loop {
             cdc_class.wait_connection().await;
             info!("Connected");
-            let _ = usb_control(&mut cdc_class, &publisher).await;
+            let _ = usb_control(&mut cdc_class, serial, &publisher).await;
             info!("Disconnected");
         }
     };
 
+    let capture_fut = async {
+        loop {
+            let port = CAPTURE_REQUEST.wait().await;
+            info!("Capturing a raw IR frame");
+            // an empty frame tells the host that no remote was used
+            let frame = crate::irprotocols::capture_raw().await.unwrap_or_default();
+            port.captured().signal(frame);
+        }
+    };
+
     join(
         usb_fut,
-        join(control_fut, join(log_fut, join(hid_fut, midi_fut))),
+        join(
+            join(control_fut, capture_fut),
+            join(
+                log_fut,
+                join(
+                    join(hid_events_fut, hid_fut),
+                    join(midi_fut, join(midi_events_fut, midi_out_fut)),
+                ),
+            ),
+        ),
     )
     .await;
 }
@@ -160,117 +278,291 @@ struct Disconnected {}
 impl From<EndpointError> for Disconnected {
     fn from(val: EndpointError) -> Self {
         match val {
-            EndpointError::BufferOverflow => panic!("Buffer overflow"),
+            // a packet larger than our buffer, start over instead of taking the badge down
+            EndpointError::BufferOverflow => {
+                error!("USB packet too large");
+                Disconnected {}
+            }
             EndpointError::Disabled => Disconnected {},
         }
     }
 }
 
+type MidiSender<'d, T> = Mutex<NoopRawMutex, Sender<'d, Driver<'d, T>>>;
+
+async fn midi_send<'d, T: Instance + 'd>(
+    sender: &MidiSender<'d, T>,
+    packet: &[u8],
+) -> Result<(), Disconnected> {
+    let mut class = sender.lock().await;
+
+    // if the host does not read the endpoint we would wait forever
+    match with_timeout(Duration::from_millis(100), class.write_packet(packet)).await {
+        Ok(result) => Ok(result?),
+        Err(_) => Err(Disconnected {}),
+    }
+}
+
+// a reply to a sysex request, in a sysex too
+async fn midi_reply<'d, T: Instance + 'd>(
+    sender: &MidiSender<'d, T>,
+    data: &[u8],
+) -> Result<(), Disconnected> {
+    let mut events = midi::sysex_events(data).peekable();
+
+    while events.peek().is_some() {
+        let mut packet = Vec::<u8, 64>::new();
+        for event in events.by_ref().take(16) {
+            // 16 events fill a packet
+            packet.extend_from_slice(&event).ok();
+        }
+        midi_send(sender, &packet).await?;
+    }
+
+    Ok(())
+}
+
 async fn midi_echo<'d, T: Instance + 'd>(
-    class: &mut MidiClass<'d, Driver<'d, T>>,
+    class: &mut Receiver<'d, Driver<'d, T>>,
+    sender: &MidiSender<'d, T>,
+    serial: &str,
     publisher: &MegaPublisher,
 ) -> Result<(), Disconnected> {
     let mut buf = [0; 64];
+    let mut clock = MidiClock::default();
+    let mut time_scale = 1.0;
+    let mut sysex = SysexReceiver::default();
+    let mut message_buf = MessageBuffer::<IR_BINDINGS_SIZE>::new();
+    // a frame captured for another connection is no reply for this one
+    MIDI_CAPTURED.reset();
+
     loop {
-        let n = class.read_packet(&mut buf).await?;
+        let n = match select(class.read_packet(&mut buf), MIDI_CAPTURED.wait()).await {
+            Either::First(n) => n?,
+            Either::Second(frame) => {
+                let mut reply_buf = [0; crate::capnp::REPLY_SIZE];
+                let len = crate::capnp::serialize_ir_raw(&frame, &mut reply_buf);
+                if let Some(len) = reply_len(len) {
+                    midi_reply(sender, &reply_buf[..len]).await?;
+                }
+                continue;
+            }
+        };
 
         // read at chunk of 4 bytes
-        for i in (0..n).step_by(4) {
-            //let data = &buf[i..i+4];
-            let buf: &[u8; 4] = match buf[i..i + 4].try_into() {
-                Ok(buf) => buf,
+        for packet in buf[..n].chunks(4) {
+            let packet: &[u8; 4] = match packet.try_into() {
+                Ok(packet) => packet,
                 Err(_) => {
                     warn!("got bad midi data");
                     continue;
                 }
             };
 
-            let [_, _, button, value] = buf;
-
-            info!("midi pixel: {}, value: {}", button, value);
-
-            // button 0 = pixel 0 red
-            // button 1 = pixel 0 green
-            // button 2 = pixel 0 blue
-            // button 3 = pixel 1 red
-            // etc etc
+            if let Some(payload) = sysex.push(packet) {
+                let e = midi::decode_7bit(payload, &mut message_buf).map(task_command);
 
-            let width = 3;
-
-            let pixel = button / 3;
-            let x = pixel % width;
-            let y = pixel / width;
-            let channel = button % 3;
-
-            if x >= width || y >= width {
+                let mut reply_buf = [0; crate::capnp::REPLY_SIZE];
+                match reply(&e, ReplyPort::Midi, serial, &mut reply_buf) {
+                    Some(len) => {
+                        midi_reply(sender, &reply_buf[..len]).await?;
+                        publisher.publish(crate::TaskCommand::UsbActivity).await;
+                    }
+                    None => handle_control_message(e, publisher).await,
+                }
                 continue;
             }
 
-            // the (0,0) should be the top left pixel
-            // TODO: x and y are probably wrong / inconsistent
-            let x = width - x - 1;
+            let Some(message) = MidiMessage::parse(packet) else {
+                continue;
+            };
 
-            // warning: midi values are 0-127, we need to double them to get 0-255
-            publisher
-                .publish(crate::TaskCommand::MidiSetPixel(x, y, channel, value * 2))
-                .await;
-        }
-    }
-}
+            let command = match message {
+                MidiMessage::NoteOn { note, velocity, .. } => TaskCommand::MidiNote(note, velocity),
+                MidiMessage::NoteOff { note, .. } => TaskCommand::MidiNote(note, 0),
+                MidiMessage::ControlChange { control, value, .. } => match control {
+                    midi::CC_BRIGHTNESS => TaskCommand::SetGain(value as f32 / 127.0),
+                    midi::CC_HUE => TaskCommand::MidiHue(value),
+                    // 64 is the normal speed
+                    midi::CC_SPEED => TaskCommand::SetTimeScale(value as f32 / 64.0),
+                    _ => continue,
+                },
+                MidiMessage::ProgramChange { program, .. } => TaskCommand::SetScene(program),
+                MidiMessage::Start => {
+                    clock.start();
+                    TaskCommand::ResetTime
+                }
+                MidiMessage::Clock => match clock.tick() {
+                    Some(bpm) => {
+                        let scale = bpm / midi::REFERENCE_BPM;
 
-struct AlignedVec {
-    x: Vec<u8, 256>,
-    _alignment: [u64; 0],
-}
+                        // do not flood the other tasks with tiny tempo jitters
+                        if (-0.01..0.01).contains(&(scale - time_scale)) {
+                            continue;
+                        }
+                        time_scale = scale;
+
+                        TaskCommand::SetTimeScale(scale)
+                    }
+                    None => continue,
+                },
+                MidiMessage::Continue | MidiMessage::Stop => continue,
+            };
 
-impl AlignedVec {
-    fn new() -> Self {
-        Self {
-            x: Vec::<u8, 256>::new(),
-            _alignment: [0; 0],
+            debug!("midi: {:?}", message);
+            publisher.publish(command).await;
         }
     }
 }
 
 async fn usb_control<'d, T: Instance + 'd>(
-    class: &mut CdcAcmClass<'d, Driver<'d, T>>,
+    class: &mut ControlPort<'d, Driver<'d, T>>,
+    serial: &str,
     publisher: &MegaPublisher,
 ) -> Result<(), Disconnected> {
     let mut buf = [0; 64];
-    let mut mega_deserialization_buf = AlignedVec::new();
+    // a whole table of ir bindings is the largest message
+    let mut message_buf = MessageBuffer::<IR_BINDINGS_SIZE>::new();
+    CONTROL_CAPTURED.reset();
     loop {
-        let n = class.read_packet(&mut buf).await?;
+        let n = match select(class.read_packet(&mut buf), CONTROL_CAPTURED.wait()).await {
+            Either::First(n) => n?,
+            Either::Second(frame) => {
+                let mut reply_buf = [0; crate::capnp::REPLY_SIZE];
+                let len = crate::capnp::serialize_ir_raw(&frame, &mut reply_buf);
+                if let Some(len) = reply_len(len) {
+                    write_message(class, &reply_buf[..len]).await?;
+                }
+                continue;
+            }
+        };
         let data = &buf[..n];
-        info!("usb cdc data: {:?}", data);
+        debug!("usb cdc data: {:?}", data);
+
+        // a packet can end a message and start the next one, or hold none
+        for message in message_buf.push(data) {
+            let e = message.map(task_command);
+
+            // the reply goes back on this port
+            let mut reply_buf = [0; crate::capnp::REPLY_SIZE];
+            match reply(&e, ReplyPort::Control, serial, &mut reply_buf) {
+                Some(len) => {
+                    write_message(class, &reply_buf[..len]).await?;
+                    publisher.publish(crate::TaskCommand::UsbActivity).await;
+                }
+                None => handle_control_message(e, publisher).await,
+            }
+        }
+    }
+}
 
-        // append to the mega deserialization buffer
-        // we don't really care if it fails, we'll just clear it later
-        mega_deserialization_buf.x.extend_from_slice(data).ok();
+// a message of the host becomes a command for the tasks, and the settings kept in shared
+// tables are stored here: the ir tables and frames are too large for the channel, and the
+// relay and the button action are for no task in particular
+fn task_command(message: Message) -> TaskCommand {
+    crate::capnp::check_protocol_version(message.protocol_version);
+
+    match message.command {
+        Command::SetIrBindings(bindings) => crate::ir::set(bindings),
+        Command::ResetIrBindings => crate::ir::reset(),
+        Command::SendIrRaw(frame, carrier) => {
+            crate::irprotocols::set_raw(frame);
+            return TaskCommand::SendIrRaw(carrier);
+        }
+        Command::SetRelay(relay) => crate::badgelink::set_relay(relay),
+        Command::SetButtonAction(action) => crate::ir::set_button(action),
+        command => return crate::capnp::task_command(command),
+    }
 
-        let e = crate::capnp::deserialize_message(&mut mega_deserialization_buf.x.as_slice());
+    TaskCommand::None
+}
+
+// answers the requests, None for the other messages and for a raw ir capture, that is
+// answered on the same port when it ends
+fn reply(
+    message: &Result<TaskCommand, capnp::Error>,
+    port: ReplyPort,
+    serial: &str,
+    out: &mut [u8],
+) -> Option<usize> {
+    let len = match message {
+        Ok(TaskCommand::GetInfo) => crate::capnp::serialize_info(serial, out),
+        Ok(TaskCommand::CaptureIrRaw) => {
+            CAPTURE_REQUEST.signal(port);
+            return None;
+        }
+        Ok(TaskCommand::GetContacts) => {
+            crate::capnp::serialize_contacts(&crate::contacts::list(), out)
+        }
+        _ => return None,
+    };
 
-        match e {
-            Ok(command) => {
-                info!("Deserialized message");
+    reply_len(len)
+}
 
-                mega_deserialization_buf.x.clear();
+// the host gives up waiting, and that's all it can do about it
+fn reply_len(len: Result<usize, capnp::Error>) -> Option<usize> {
+    len.map_err(|e| error!("Error serializing the reply: {:?}", e.kind))
+        .ok()
+}
 
-                publisher.publish(command).await;
-                publisher.publish(crate::TaskCommand::UsbActivity).await;
-            }
-            Err(e) => match e.kind {
-                capnp::ErrorKind::MessageEndsPrematurely(_, _) => {
-                    continue;
-                }
+async fn write_message<'d, T: Instance + 'd>(
+    class: &mut ControlPort<'d, Driver<'d, T>>,
+    data: &[u8],
+) -> Result<(), Disconnected> {
+    for packet in data.chunks(64) {
+        class.write_packet(packet).await?;
+    }
 
-                e => {
-                    error!("Error deserializing message: {:?}", e);
+    // a full last packet doesn't end the transfer, a zero length one does
+    if data.len() % 64 == 0 {
+        class.write_packet(&[]).await?;
+    }
 
-                    publisher.publish(crate::TaskCommand::Error).await;
+    Ok(())
+}
 
-                    mega_deserialization_buf.x.clear();
-                }
-            },
+// shared by the CDC and the MIDI SysEx control channels
+async fn handle_control_message(
+    message: Result<TaskCommand, capnp::Error>,
+    publisher: &MegaPublisher,
+) {
+    match message {
+        // the logger lives in this task, no need to bother the others
+        Ok(TaskCommand::SetLogConfig(config)) => {
+            info!("New log config: {:?}", config);
+            logging::configure(config);
+
+            publisher.publish(crate::TaskCommand::UsbActivity).await;
+        }
+        Ok(TaskCommand::RebootToBootloader) => {
+            info!("Rebooting to the USB bootloader");
+            // give the logger a moment to send the last lines
+            Timer::after(Duration::from_millis(100)).await;
+
+            // no activity led, both mass storage and picoboot interfaces
+            embassy_rp::rom_data::reset_to_usb_boot(0, 0);
+        }
+        // started by reply, the frame goes back when it's captured
+        Ok(TaskCommand::CaptureIrRaw) => {
+            publisher.publish(crate::TaskCommand::UsbActivity).await;
+        }
+        Ok(TaskCommand::Reboot) => {
+            info!("Rebooting");
+            Timer::after(Duration::from_millis(100)).await;
+
+            cortex_m::peripheral::SCB::sys_reset();
+        }
+        Ok(command) => {
+            debug!("Deserialized message");
+
+            publisher.publish(command).await;
+            publisher.publish(crate::TaskCommand::UsbActivity).await;
+        }
+        Err(e) => {
+            error!("Error deserializing message: {:?}", e.kind);
+
+            publisher.publish(crate::TaskCommand::Error).await;
         }
     }
 }
@@ -0,0 +1,286 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+
+/// Number of frequency bands, one for each led of the badge
+pub const BANDS: usize = 9;
+
+/// Samples used for each FFT
+const FFT_SIZE: usize = 1024;
+
+/// Lowest and highest frequency shown on the badge, in Hz
+const MIN_FREQUENCY: f32 = 40.0;
+const MAX_FREQUENCY: f32 = 16_000.0;
+
+/// Levels below this are shown as a dark led, in dB relative to full scale
+const FLOOR_DB: f32 = -60.0;
+
+/// A beat is detected when the bass energy is this much above its recent average
+const BEAT_THRESHOLD: f32 = 1.4;
+
+pub struct AudioLevels {
+    pub bands: [u8; BANDS],
+    pub beat: bool,
+}
+
+/// Samples between two updates, at least one whatever the sample rate of the file
+fn hop(sample_rate: u32, fps: u32) -> usize {
+    (sample_rate / fps.max(1)).max(1) as usize
+}
+
+pub struct Analyzer {
+    fft: Arc<dyn Fft<f32>>,
+    window: Vec<f32>,
+    band_edges: [usize; BANDS + 1],
+    energy_history: VecDeque<f32>,
+    history_len: usize,
+}
+
+impl Analyzer {
+    pub fn new(sample_rate: u32, fps: u32) -> Self {
+        let fft = FftPlanner::new().plan_fft_forward(FFT_SIZE);
+
+        // hann window, to avoid leaking energy between the bands
+        let window = (0..FFT_SIZE)
+            .map(|i| {
+                let x = i as f32 / FFT_SIZE as f32;
+                0.5 - 0.5 * (2.0 * std::f32::consts::PI * x).cos()
+            })
+            .collect();
+
+        // bands are spaced logarithmically, like our ears like them
+        let bin_width = sample_rate as f32 / FFT_SIZE as f32;
+        let ratio = (MAX_FREQUENCY / MIN_FREQUENCY).powf(1.0 / BANDS as f32);
+        let mut band_edges = [0; BANDS + 1];
+        for (i, edge) in band_edges.iter_mut().enumerate() {
+            let frequency = MIN_FREQUENCY * ratio.powi(i as i32);
+            *edge = ((frequency / bin_width) as usize).clamp(1, FFT_SIZE / 2);
+        }
+
+        // make sure every band has at least one bin, as far as the bins go at low sample rates
+        for i in 1..band_edges.len() {
+            band_edges[i] = band_edges[i].max(band_edges[i - 1] + 1).min(FFT_SIZE / 2);
+        }
+
+        Self {
+            fft,
+            window,
+            band_edges,
+            energy_history: VecDeque::new(),
+            // about one second of history for the beat detection
+            history_len: fps.max(1) as usize,
+        }
+    }
+
+    /// Computes the band levels of the last `FFT_SIZE` samples, in the range -1.0..1.0
+    pub fn analyze(&mut self, samples: &[f32]) -> AudioLevels {
+        let mut buffer: Vec<Complex<f32>> = samples
+            .iter()
+            .rev()
+            .take(FFT_SIZE)
+            .rev()
+            .zip(self.window.iter())
+            .map(|(s, w)| Complex::new(s * w, 0.0))
+            .collect();
+        buffer.resize(FFT_SIZE, Complex::new(0.0, 0.0));
+
+        self.fft.process(&mut buffer);
+
+        let mut bands = [0u8; BANDS];
+        let mut energies = [0f32; BANDS];
+        for (i, band) in bands.iter_mut().enumerate() {
+            let bins = &buffer[self.band_edges[i]..self.band_edges[i + 1].min(FFT_SIZE / 2)];
+
+            // the window halves the amplitude, hence the 4 instead of 2
+            let peak = bins
+                .iter()
+                .map(|c| c.norm() * 4.0 / FFT_SIZE as f32)
+                .fold(0.0, f32::max);
+
+            energies[i] = peak * peak;
+
+            let db = 20.0 * peak.max(1e-9).log10();
+            *band = ((db - FLOOR_DB) / -FLOOR_DB * 255.0).clamp(0.0, 255.0) as u8;
+        }
+
+        // the beat lives in the two lowest bands
+        let energy = energies[0] + energies[1];
+        let average = if self.energy_history.is_empty() {
+            f32::MAX
+        } else {
+            self.energy_history.iter().sum::<f32>() / self.energy_history.len() as f32
+        };
+        let beat = energy > average * BEAT_THRESHOLD && energy > 1e-4;
+
+        self.energy_history.push_back(energy);
+        if self.energy_history.len() > self.history_len {
+            self.energy_history.pop_front();
+        }
+
+        AudioLevels { bands, beat }
+    }
+}
+
+/// Streams the levels of a wav file, in real time
+pub fn stream_wav(
+    path: &str,
+    fps: u32,
+    mut send: impl FnMut(&AudioLevels) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut reader = hound::WavReader::open(path).map_err(io::Error::other)?;
+    let spec = reader.spec();
+
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader
+            .samples::<f32>()
+            .collect::<Result<_, _>>()
+            .map_err(io::Error::other)?,
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample - 1)) as f32;
+            reader
+                .samples::<i32>()
+                .map(|s| s.map(|s| s as f32 / scale))
+                .collect::<Result<_, _>>()
+                .map_err(io::Error::other)?
+        }
+    };
+
+    let mono = downmix(&samples, spec.channels as usize);
+
+    let mut analyzer = Analyzer::new(spec.sample_rate, fps);
+    let hop = hop(spec.sample_rate, fps);
+    let frame_time = Duration::from_secs_f64(1.0 / fps.max(1) as f64);
+    let start = Instant::now();
+
+    for (frame, end) in (hop..mono.len()).step_by(hop).enumerate() {
+        let begin = end.saturating_sub(FFT_SIZE);
+        send(&analyzer.analyze(&mono[begin..end]))?;
+
+        // keep in sync with the wall clock, so the lights follow the music
+        if let Some(wait) =
+            (start + frame_time * (frame as u32 + 1)).checked_duration_since(Instant::now())
+        {
+            thread::sleep(wait);
+        }
+    }
+
+    Ok(())
+}
+
+/// Streams the levels of raw signed 16 bit little endian PCM data read from stdin
+pub fn stream_stdin(
+    sample_rate: u32,
+    channels: u16,
+    fps: u32,
+    mut send: impl FnMut(&AudioLevels) -> io::Result<()>,
+) -> io::Result<()> {
+    let mut analyzer = Analyzer::new(sample_rate, fps);
+    let hop = hop(sample_rate, fps);
+    let channels = channels.max(1) as usize;
+
+    let mut stdin = io::stdin().lock();
+    let mut raw = vec![0u8; hop * channels * 2];
+    let mut history: VecDeque<f32> = VecDeque::with_capacity(FFT_SIZE + hop);
+
+    loop {
+        match stdin.read_exact(&mut raw) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(()),
+            Err(e) => return Err(e),
+        }
+
+        let samples: Vec<f32> = raw
+            .chunks_exact(2)
+            .map(|b| i16::from_le_bytes([b[0], b[1]]) as f32 / 32768.0)
+            .collect();
+
+        history.extend(downmix(&samples, channels));
+        while history.len() > FFT_SIZE {
+            history.pop_front();
+        }
+
+        send(&analyzer.analyze(history.make_contiguous()))?;
+    }
+}
+
+fn downmix(samples: &[f32], channels: usize) -> Vec<f32> {
+    let channels = channels.max(1);
+    samples
+        .chunks(channels)
+        .map(|frame| frame.iter().sum::<f32>() / frame.len() as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SAMPLE_RATE: u32 = 44_100;
+
+    fn sine(frequency: f32, amplitude: f32) -> Vec<f32> {
+        (0..FFT_SIZE)
+            .map(|i| {
+                let t = i as f32 / SAMPLE_RATE as f32;
+                amplitude * (2.0 * std::f32::consts::PI * frequency * t).sin()
+            })
+            .collect()
+    }
+
+    // the center frequency of an FFT bin, so the tone does not leak into the next band
+    fn bin(n: usize) -> f32 {
+        n as f32 * SAMPLE_RATE as f32 / FFT_SIZE as f32
+    }
+
+    fn loudest(levels: &AudioLevels) -> usize {
+        (0..BANDS).max_by_key(|&i| levels.bands[i]).unwrap()
+    }
+
+    #[test]
+    fn sine_lights_its_band() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE, 30);
+
+        // the bands go from 40 Hz to 16 kHz, each one about twice the one before:
+        // 86 Hz, 775 Hz, 3 kHz and 11 kHz
+        for (frequency, band) in [(bin(2), 1), (bin(18), 4), (bin(70), 6), (bin(255), 8)] {
+            let levels = analyzer.analyze(&sine(frequency, 0.5));
+
+            assert_eq!(loudest(&levels), band, "{} Hz", frequency);
+            // half of full scale is -6 dB, near the top of the range
+            assert!(levels.bands[band] > 200, "{} Hz", frequency);
+            assert!(levels.bands[(band + 3) % BANDS] < 100, "{} Hz", frequency);
+        }
+
+        let silence = analyzer.analyze(&[0.0; FFT_SIZE]);
+        assert_eq!(silence.bands, [0; BANDS]);
+    }
+
+    #[test]
+    fn beat_on_bass_onset() {
+        let mut analyzer = Analyzer::new(SAMPLE_RATE, 30);
+
+        for _ in 0..10 {
+            assert!(!analyzer.analyze(&[0.0; FFT_SIZE]).beat);
+        }
+        assert!(analyzer.analyze(&sine(60.0, 0.8)).beat);
+
+        // a steady bass is not a beat anymore once it fills the second of history
+        for _ in 0..30 {
+            analyzer.analyze(&sine(60.0, 0.8));
+        }
+        assert!(!analyzer.analyze(&sine(60.0, 0.8)).beat);
+        assert!(!analyzer.analyze(&[0.0; FFT_SIZE]).beat);
+    }
+
+    #[test]
+    fn hop_never_zero() {
+        assert_eq!(hop(44_100, 30), 1470);
+        assert_eq!(hop(8, 30), 1);
+        assert_eq!(hop(44_100, 0), 44_100);
+    }
+}
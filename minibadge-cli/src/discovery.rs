@@ -0,0 +1,216 @@
+use std::{collections::BTreeMap, io};
+
+use serialport::{SerialPortType, UsbPortInfo};
+
+/// The pid.codes open source vendor id
+const PID_CODES_VID: u16 = 0x1209;
+/// One of the pid.codes test product ids, until the badge has its own (see the README)
+const PID_CODES_TEST_PID: u16 = 0x0001;
+
+/// USB vendor and product id of the badge, they must match the firmware
+pub const BADGE_VID: u16 = PID_CODES_VID;
+pub const BADGE_PID: u16 = PID_CODES_TEST_PID;
+
+/// Interface string of the control port, it must match the firmware.
+/// The log port has no name, it is the other serial port of the badge
+const CONTROL_NAME: &str = "Minibadge control";
+/// Number of the first interface of the control port, for the systems that don't show the
+/// interface strings. The firmware adds its interfaces in a fixed order
+const CONTROL_INTERFACE: u8 = 3;
+
+/// Everything we know about one connected badge
+#[derive(Debug, Default)]
+pub struct Badge {
+    pub serial: String,
+    /// Management CDC port, for the capnp messages
+    pub control_port: Option<String>,
+    /// Debug CDC port, where the firmware logs
+    pub log_port: Option<String>,
+    /// MIDI device, only found on Linux
+    pub midi_device: Option<String>,
+}
+
+/// Finds all the connected badges, sorted by serial number
+pub fn discover() -> io::Result<Vec<Badge>> {
+    let mut badges: BTreeMap<String, Badge> = BTreeMap::new();
+    // the ports that can't be told apart, by serial number
+    let mut unknown: BTreeMap<String, Vec<String>> = BTreeMap::new();
+
+    for port in serialport::available_ports()? {
+        let SerialPortType::UsbPort(info) = port.port_type else {
+            continue;
+        };
+        if !is_badge(&info) || is_dialin(&port.port_name) {
+            continue;
+        }
+
+        let serial = info.serial_number.clone().unwrap_or_default();
+        let badge = badges.entry(serial.clone()).or_insert_with(|| Badge {
+            serial,
+            ..Default::default()
+        });
+
+        match is_control_port(&port.port_name, &info) {
+            Some(true) => badge.control_port = Some(port.port_name),
+            Some(false) => badge.log_port = Some(port.port_name),
+            None => unknown
+                .entry(badge.serial.clone())
+                .or_default()
+                .push(port.port_name),
+        }
+    }
+
+    // macOS numbers the ports of a device in the order of their interfaces, and the control
+    // port comes first
+    for (serial, mut ports) in unknown {
+        ports.sort();
+        let badge = badges.get_mut(&serial).unwrap();
+        let mut ports = ports.into_iter();
+        if badge.control_port.is_none() {
+            badge.control_port = ports.next();
+        }
+        if badge.log_port.is_none() {
+            badge.log_port = ports.next();
+        }
+    }
+
+    for (serial, device) in midi_devices() {
+        if let Some(badge) = badges.get_mut(&serial) {
+            badge.midi_device = Some(device);
+        }
+    }
+
+    Ok(badges.into_values().collect())
+}
+
+/// Picks the badges with the given serial numbers from the discovered ones,
+/// or the only connected badge when no serial is given
+///
+/// Every requested serial gets its own result, so a missing badge does not stop the others
+pub fn select(
+    mut badges: Vec<Badge>,
+    serials: &[String],
+) -> io::Result<Vec<(String, io::Result<Badge>)>> {
+    if serials.is_empty() {
+        return match badges.len() {
+            0 => Err(io::Error::new(io::ErrorKind::NotFound, "No badge found")),
+            1 => {
+                let badge = badges.remove(0);
+                Ok(vec![(badge.serial.clone(), Ok(badge))])
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Several badges found, choose them with --badge or use --all (see the list command)",
+            )),
+        };
+    }
+
+    let selected = serials
+        .iter()
+        .map(|serial| {
+            let badge = badges
+                .iter()
+                .position(|b| b.serial.eq_ignore_ascii_case(serial))
+                .map(|i| badges.remove(i))
+                .ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        format!("No badge with serial {}", serial),
+                    )
+                });
+            (serial.clone(), badge)
+        })
+        .collect();
+
+    Ok(selected)
+}
+
+fn is_badge(info: &UsbPortInfo) -> bool {
+    info.vid == BADGE_VID && info.pid == BADGE_PID
+}
+
+/// Whether the port is the control port of a badge or its log port, None when the system
+/// tells neither the name nor the number of its interface
+fn is_control_port(port_name: &str, info: &UsbPortInfo) -> Option<bool> {
+    match interface_name(port_name) {
+        Some(name) => Some(name.starts_with(CONTROL_NAME)),
+        None => info.interface.map(|number| number == CONTROL_INTERFACE),
+    }
+}
+
+/// The interface string of a serial port, from sysfs
+#[cfg(target_os = "linux")]
+fn interface_name(port_name: &str) -> Option<String> {
+    use std::{fs, path::Path};
+
+    // /dev/ttyACM0 is /sys/class/tty/ttyACM0, its device is the usb interface
+    let tty = Path::new(port_name).file_name()?;
+    let path = Path::new("/sys/class/tty")
+        .join(tty)
+        .join("device/interface");
+
+    fs::read_to_string(path).ok().map(|s| s.trim().to_string())
+}
+
+/// Only sysfs has the interface strings, the other systems give the interface number at most
+#[cfg(not(target_os = "linux"))]
+fn interface_name(_port_name: &str) -> Option<String> {
+    None
+}
+
+/// macOS lists every port twice, as `/dev/cu.*` and as `/dev/tty.*`. The second one waits for
+/// the carrier when opened, the first one is the one to use
+fn is_dialin(port_name: &str) -> bool {
+    cfg!(target_os = "macos") && port_name.starts_with("/dev/tty.")
+}
+
+/// Pairs of (serial number, device path) of the MIDI devices of the badges
+///
+/// Every raw midi device in sysfs is walked up to its usb device, that has the ids and the serial
+#[cfg(target_os = "linux")]
+fn midi_devices() -> Vec<(String, String)> {
+    use std::{fs, path::Path};
+
+    let read = |dir: &Path, name: &str| {
+        fs::read_to_string(dir.join(name))
+            .ok()
+            .map(|s| s.trim().to_string())
+    };
+
+    let Ok(entries) = fs::read_dir("/sys/class/sound") else {
+        return vec![];
+    };
+
+    let mut devices = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        // midiC1D0 is the alsa raw midi device, /dev/snd/midiC1D0
+        if !name.starts_with("midiC") {
+            continue;
+        }
+
+        let Ok(path) = fs::canonicalize(entry.path()) else {
+            continue;
+        };
+
+        let Some(usb_device) = path.ancestors().find(|dir| dir.join("idVendor").exists()) else {
+            continue;
+        };
+
+        let hex = |name| read(usb_device, name).and_then(|s| u16::from_str_radix(&s, 16).ok());
+        if hex("idVendor") != Some(BADGE_VID) || hex("idProduct") != Some(BADGE_PID) {
+            continue;
+        }
+
+        if let Some(serial) = read(usb_device, "serial") {
+            devices.push((serial, format!("/dev/snd/{}", name)));
+        }
+    }
+
+    devices
+}
+
+#[cfg(not(target_os = "linux"))]
+fn midi_devices() -> Vec<(String, String)> {
+    vec![]
+}
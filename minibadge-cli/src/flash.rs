@@ -0,0 +1,209 @@
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Every UF2 block is 512 bytes, with the magic numbers at the start and at the end
+const BLOCK_SIZE: usize = 512;
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+
+/// When this flag is set, the file size field holds the family id
+const FLAG_FAMILY_ID: u32 = 0x0000_2000;
+const RP2040_FAMILY_ID: u32 = 0xE48B_FF56;
+
+/// The bootloader drive has this file, with the board id inside
+const INFO_FILE: &str = "INFO_UF2.TXT";
+const BOARD_ID: &str = "RPI-RP2";
+
+/// Checks that the image is a UF2 file for the RP2040, returns the number of blocks
+pub fn check_uf2(image: &[u8]) -> io::Result<usize> {
+    let invalid = |message: &str| io::Error::new(io::ErrorKind::InvalidData, message.to_string());
+
+    if image.is_empty() || !image.chunks_exact(BLOCK_SIZE).remainder().is_empty() {
+        return Err(invalid("Not a UF2 file, the size is not a multiple of 512"));
+    }
+
+    for block in image.chunks_exact(BLOCK_SIZE) {
+        let word =
+            |offset: usize| u32::from_le_bytes(block[offset..offset + 4].try_into().unwrap());
+
+        if word(0) != MAGIC_START0 || word(4) != MAGIC_START1 || word(BLOCK_SIZE - 4) != MAGIC_END {
+            return Err(invalid("Not a UF2 file, bad magic numbers"));
+        }
+
+        if word(8) & FLAG_FAMILY_ID != 0 && word(28) != RP2040_FAMILY_ID {
+            return Err(invalid("The UF2 file is not for the RP2040"));
+        }
+    }
+
+    Ok(image.len() / BLOCK_SIZE)
+}
+
+/// Whether the directory is the root of the RP2040 bootloader drive
+fn is_bootloader_drive(dir: &Path) -> bool {
+    fs::read_to_string(dir.join(INFO_FILE)).is_ok_and(|info| info.contains(BOARD_ID))
+}
+
+/// Places where the bootloader drive could be mounted
+#[cfg(target_os = "linux")]
+fn mount_points() -> Vec<PathBuf> {
+    // spaces are written as \040 in /proc/mounts
+    fs::read_to_string("/proc/mounts")
+        .unwrap_or_default()
+        .lines()
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .map(|dir| PathBuf::from(dir.replace("\\040", " ")))
+        .collect()
+}
+
+#[cfg(target_os = "macos")]
+fn mount_points() -> Vec<PathBuf> {
+    fs::read_dir("/Volumes")
+        .map(|entries| entries.flatten().map(|e| e.path()).collect())
+        .unwrap_or_default()
+}
+
+#[cfg(windows)]
+fn mount_points() -> Vec<PathBuf> {
+    (b'A'..=b'Z')
+        .map(|letter| PathBuf::from(format!("{}:\\", letter as char)))
+        .collect()
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "macos", windows)))]
+fn mount_points() -> Vec<PathBuf> {
+    vec![]
+}
+
+/// Waits for the badge to show up as a USB drive, it must be mounted by the system
+pub fn wait_for_bootloader_drive(timeout: Duration) -> io::Result<PathBuf> {
+    let start = Instant::now();
+
+    loop {
+        if let Some(drive) = mount_points()
+            .into_iter()
+            .find(|dir| is_bootloader_drive(dir))
+        {
+            return Ok(drive);
+        }
+
+        if start.elapsed() > timeout {
+            return Err(io::Error::new(
+                io::ErrorKind::TimedOut,
+                "The bootloader drive did not show up, is it mounted?",
+            ));
+        }
+
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Copies the image in the drive, the badge starts the new firmware as soon as it's written
+///
+/// The drive is just a directory, any directory works for trying this out
+pub fn write_uf2(image: &[u8], drive: &Path) -> io::Result<PathBuf> {
+    let path = drive.join("firmware.uf2");
+
+    let mut file = fs::File::create(&path)?;
+    file.write_all(image)?;
+    // the data must really reach the badge before we say we're done
+    file.sync_all()?;
+
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    /// A block of 256 bytes of data for the given family, none when zero
+    fn block(number: u32, family: u32) -> Vec<u8> {
+        let flags = if family != 0 { FLAG_FAMILY_ID } else { 0 };
+        let mut block = vec![0; BLOCK_SIZE];
+        let words = [
+            (0, MAGIC_START0),
+            (4, MAGIC_START1),
+            (8, flags),
+            (12, 0x1000_0000 + number * 256),
+            (16, 256),
+            (20, number),
+            (24, 2),
+            (28, family),
+            (BLOCK_SIZE - 4, MAGIC_END),
+        ];
+        for (offset, word) in words {
+            block[offset..offset + 4].copy_from_slice(&word.to_le_bytes());
+        }
+
+        block
+    }
+
+    fn image(family: u32) -> Vec<u8> {
+        [block(0, family), block(1, family)].concat()
+    }
+
+    #[test]
+    fn valid_image() {
+        assert_eq!(check_uf2(&image(RP2040_FAMILY_ID)).unwrap(), 2);
+        // old images have no family id
+        assert_eq!(check_uf2(&image(0)).unwrap(), 2);
+    }
+
+    #[test]
+    fn bad_magic() {
+        for offset in [0, 4, BLOCK_SIZE - 4] {
+            let mut image = image(RP2040_FAMILY_ID);
+            image[BLOCK_SIZE + offset] ^= 0xff;
+            let error = check_uf2(&image).unwrap_err();
+            assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(error.to_string(), "Not a UF2 file, bad magic numbers");
+        }
+    }
+
+    #[test]
+    fn truncated_block() {
+        let image = image(RP2040_FAMILY_ID);
+        for len in [0, 100, BLOCK_SIZE + 100, image.len() - 1] {
+            let error = check_uf2(&image[..len]).unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                "Not a UF2 file, the size is not a multiple of 512"
+            );
+        }
+    }
+
+    #[test]
+    fn wrong_family() {
+        // the RP2350 arm family
+        let error = check_uf2(&image(0xE48B_FF59)).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(error.to_string(), "The UF2 file is not for the RP2040");
+    }
+
+    #[test]
+    fn write_to_drive() {
+        let drive = env::temp_dir().join(format!("minibadge-cli-{}-drive", process::id()));
+        fs::create_dir_all(&drive).unwrap();
+        assert!(!is_bootloader_drive(&drive));
+        fs::write(
+            drive.join(INFO_FILE),
+            "UF2 Bootloader v3.0\nBoard-ID: RPI-RP2\n",
+        )
+        .unwrap();
+        assert!(is_bootloader_drive(&drive));
+
+        let image = image(RP2040_FAMILY_ID);
+        let path = write_uf2(&image, &drive).unwrap();
+        assert_eq!(path, drive.join("firmware.uf2"));
+        assert_eq!(fs::read(&path).unwrap(), image);
+
+        fs::remove_dir_all(&drive).unwrap();
+    }
+}
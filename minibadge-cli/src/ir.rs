@@ -0,0 +1,378 @@
+use minibadge_protocol::{
+    IrAction, IrBinding, CHORD_LEN, MACRO_LEN, MAX_BADGE_TEXT, MAX_HID_TEXT, MAX_IR_BINDINGS,
+};
+
+use crate::usb_messages_capnp::{IrProtocol, RepeatPolicy};
+
+/// Names of the remote protocols, in bindings files, options and logs
+pub const PROTOCOLS: &[(&str, IrProtocol)] = &[
+    ("nec", IrProtocol::Nec),
+    ("samsung", IrProtocol::Samsung),
+    ("apple", IrProtocol::AppleNec),
+    ("rc5", IrProtocol::Rc5),
+    ("rc6", IrProtocol::Rc6),
+    ("sirc", IrProtocol::Sirc),
+];
+
+/// HID keyboard usage ids of the keys that make sense on a remote
+const KEYS: &[(&str, u8)] = &[
+    ("enter", 0x28),
+    ("escape", 0x29),
+    ("backspace", 0x2a),
+    ("tab", 0x2b),
+    ("space", 0x2c),
+    ("minus", 0x2d),
+    ("equal", 0x2e),
+    ("dot", 0x37),
+    ("slash", 0x38),
+    ("print-screen", 0x46),
+    ("pause", 0x48),
+    ("insert", 0x49),
+    ("home", 0x4a),
+    ("page-up", 0x4b),
+    ("delete", 0x4c),
+    ("end", 0x4d),
+    ("page-down", 0x4e),
+    ("right", 0x4f),
+    ("left", 0x50),
+    ("down", 0x51),
+    ("up", 0x52),
+    ("mute", 0x7f),
+    ("volume-up", 0x80),
+    ("volume-down", 0x81),
+];
+
+/// Bits of the modifier byte of a HID keyboard report
+const MODIFIERS: &[(&str, u8)] = &[
+    ("ctrl", 0x01),
+    ("shift", 0x02),
+    ("alt", 0x04),
+    ("gui", 0x08),
+    ("right-ctrl", 0x10),
+    ("right-shift", 0x20),
+    ("right-alt", 0x40),
+    ("right-gui", 0x80),
+];
+
+/// HID consumer usage ids of the media keys, understood by most systems and players
+const MEDIA_KEYS: &[(&str, u16)] = &[
+    ("play-pause", 0xcd),
+    ("next", 0xb5),
+    ("previous", 0xb6),
+    ("stop", 0xb7),
+    ("mute", 0xe2),
+    ("volume-up", 0xe9),
+    ("volume-down", 0xea),
+];
+
+/// A key name, a letter, a digit, f1 to f12, or the usage id itself like 0x2c
+fn parse_key(name: &str) -> Result<u8, String> {
+    let lower = name.to_ascii_lowercase();
+
+    if let Some((_, usage)) = KEYS.iter().find(|(key, _)| *key == lower) {
+        return Ok(*usage);
+    }
+
+    let mut chars = lower.chars();
+    if let (Some(c), None) = (chars.next(), chars.next()) {
+        match c {
+            'a'..='z' => return Ok(0x04 + (c as u8 - b'a')),
+            // the usage ids start from 1, 0 comes after 9
+            '1'..='9' => return Ok(0x1e + (c as u8 - b'1')),
+            '0' => return Ok(0x27),
+            _ => {}
+        }
+    }
+
+    if let Some(n) = lower.strip_prefix('f').and_then(|n| n.parse::<u8>().ok()) {
+        if (1..=12).contains(&n) {
+            return Ok(0x3a + n - 1);
+        }
+    }
+
+    if let Some(hex) = lower.strip_prefix("0x") {
+        return u8::from_str_radix(hex, 16).map_err(|_| format!("bad key usage id {}", name));
+    }
+
+    Err(format!("unknown key {}", name))
+}
+
+/// A key with its modifiers, like `ctrl+shift+t`, a single key is a plain key press
+fn parse_chord(chord: &str) -> Result<IrAction, String> {
+    let mut modifiers = 0;
+    let mut keys = heapless::Vec::<u8, CHORD_LEN>::new();
+
+    for name in chord.split('+') {
+        let lower = name.to_ascii_lowercase();
+        match MODIFIERS.iter().find(|(modifier, _)| *modifier == lower) {
+            Some((_, bit)) => modifiers |= bit,
+            None => keys
+                .push(parse_key(name)?)
+                .map_err(|_| format!("at most {} keys can be held together", CHORD_LEN))?,
+        }
+    }
+
+    match keys.as_slice() {
+        [key] if modifiers == 0 => Ok(IrAction::HidKey(*key)),
+        _ => Ok(IrAction::Chord(modifiers, keys)),
+    }
+}
+
+/// A media key name, or the consumer usage id itself like 0xcd
+fn parse_media_key(name: &str) -> Result<u16, String> {
+    let lower = name.to_ascii_lowercase();
+
+    if let Some((_, usage)) = MEDIA_KEYS.iter().find(|(key, _)| *key == lower) {
+        return Ok(*usage);
+    }
+
+    match lower.strip_prefix("0x") {
+        Some(hex) => {
+            u16::from_str_radix(hex, 16).map_err(|_| format!("bad media usage id {}", name))
+        }
+        None => Err(format!("unknown media key {}", name)),
+    }
+}
+
+pub fn parse_protocol(name: &str) -> Result<IrProtocol, String> {
+    PROTOCOLS
+        .iter()
+        .find(|(protocol, _)| *protocol == name)
+        .map(|(_, protocol)| *protocol)
+        .ok_or_else(|| format!("unknown protocol {}", name))
+}
+
+fn parse_number<T: TryFrom<u32>>(what: &str, value: Option<&str>) -> Result<T, String> {
+    let value = value.ok_or_else(|| format!("missing {}", what))?;
+
+    let number = match value.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => value.parse(),
+    };
+
+    number
+        .ok()
+        .and_then(|n| T::try_from(n).ok())
+        .ok_or_else(|| format!("bad {} {}", what, value))
+}
+
+/// An action like `next-scene`, `scene 3`, `key ctrl+shift+t`, `media play-pause`,
+/// `macro h e l l o` or `type hello camp`
+pub fn parse_action<'a>(mut words: impl Iterator<Item = &'a str>) -> Result<IrAction, String> {
+    let action = match words.next() {
+        Some("none") => IrAction::None,
+        Some("next-scene") => IrAction::NextScene,
+        Some("brightness-up") => IrAction::IncreaseBrightness,
+        Some("brightness-down") => IrAction::DecreaseBrightness,
+        Some("scene") => IrAction::SelectScene(parse_number("scene", words.next())?),
+        Some("reset-time") => IrAction::ResetTime,
+        Some("greet") => IrAction::GreetBadge,
+        Some("share-scene") => IrAction::ShareScene,
+        Some("key") => parse_chord(words.next().ok_or("missing key")?)?,
+        Some("media") => {
+            IrAction::MediaKey(parse_media_key(words.next().ok_or("missing media key")?)?)
+        }
+        Some("type") => {
+            let text = words.by_ref().collect::<Vec<_>>().join(" ");
+            if text.is_empty() {
+                return Err("missing text".to_string());
+            }
+            if !text.is_ascii() {
+                return Err("only ascii characters can be typed".to_string());
+            }
+
+            IrAction::TypeText(
+                heapless::String::try_from(text.as_str())
+                    .map_err(|_| format!("at most {} characters can be typed", MAX_HID_TEXT))?,
+            )
+        }
+        Some("macro") => {
+            let mut keys = heapless::Vec::new();
+            for word in words.by_ref() {
+                keys.push(parse_key(word)?)
+                    .map_err(|_| format!("a macro has at most {} keys", MACRO_LEN))?;
+            }
+            if keys.is_empty() {
+                return Err("missing macro keys".to_string());
+            }
+
+            IrAction::Macro(keys)
+        }
+        Some(action) => return Err(format!("unknown action {}", action)),
+        None => return Err("missing action".to_string()),
+    };
+
+    match words.next() {
+        Some(word) => Err(format!("unexpected {}", word)),
+        None => Ok(action),
+    }
+}
+
+/// A text for the other badges, ascii and short enough for one infrared frame
+pub fn parse_badge_text(text: &str) -> Result<heapless::String<MAX_BADGE_TEXT>, String> {
+    if !text.is_ascii() {
+        return Err("only ascii characters can be sent".to_string());
+    }
+
+    heapless::String::try_from(text)
+        .map_err(|_| format!("at most {} characters can be sent", MAX_BADGE_TEXT))
+}
+
+fn parse_binding(line: &str) -> Result<Option<IrBinding>, String> {
+    let line = line.split('#').next().unwrap_or_default();
+    let mut words = line.split_whitespace().peekable();
+
+    let protocol = match words.next() {
+        Some(protocol) => parse_protocol(protocol)?,
+        None => return Ok(None),
+    };
+    let address = parse_number("address", words.next())?;
+    let command = parse_number("command", words.next())?;
+    let repeat = match words.next_if_eq(&"repeat") {
+        Some(_) => RepeatPolicy::Repeat,
+        None => RepeatPolicy::Ignore,
+    };
+
+    Ok(Some(IrBinding {
+        protocol,
+        address,
+        command,
+        repeat,
+        action: parse_action(words)?,
+    }))
+}
+
+/// One binding per line, `#` starts a comment:
+///
+/// ```text
+/// nec 0 68 next-scene
+/// samsung 7 7 repeat media volume-up
+/// rc5 0 16 key ctrl+shift+t
+/// nec 0 73 macro h e l l o enter
+/// nec 0 74 type see you at the camp
+/// ```
+///
+/// Protocol, address and command of the button, then `repeat` if the action runs again
+/// while the button is held, then the action
+pub fn parse_bindings(text: &str) -> Result<Vec<IrBinding>, String> {
+    let mut bindings = vec![];
+
+    for (i, line) in text.lines().enumerate() {
+        if let Some(binding) = parse_binding(line).map_err(|e| format!("line {}: {}", i + 1, e))? {
+            bindings.push(binding);
+        }
+    }
+
+    if bindings.len() > MAX_IR_BINDINGS {
+        return Err(format!(
+            "{} bindings, the badge keeps at most {}",
+            bindings.len(),
+            MAX_IR_BINDINGS
+        ));
+    }
+
+    Ok(bindings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn action(action: &str) -> Result<IrAction, String> {
+        parse_action(action.split_whitespace())
+    }
+
+    fn chord(modifiers: u8, keys: &[u8]) -> IrAction {
+        IrAction::Chord(modifiers, heapless::Vec::from_slice(keys).unwrap())
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(action("key a"), Ok(IrAction::HidKey(0x04)));
+        assert_eq!(action("key Z"), Ok(IrAction::HidKey(0x1d)));
+        assert_eq!(action("key 1"), Ok(IrAction::HidKey(0x1e)));
+        assert_eq!(action("key 0"), Ok(IrAction::HidKey(0x27)));
+        assert_eq!(action("key f1"), Ok(IrAction::HidKey(0x3a)));
+        assert_eq!(action("key F12"), Ok(IrAction::HidKey(0x45)));
+        assert_eq!(action("key page-down"), Ok(IrAction::HidKey(0x4e)));
+        assert_eq!(action("key 0x2c"), Ok(IrAction::HidKey(0x2c)));
+    }
+
+    #[test]
+    fn unknown_keys() {
+        assert_eq!(action("key f13"), Err("unknown key f13".to_string()));
+        assert_eq!(action("key ab"), Err("unknown key ab".to_string()));
+        assert_eq!(action("key !"), Err("unknown key !".to_string()));
+        assert_eq!(
+            action("key 0x100"),
+            Err("bad key usage id 0x100".to_string())
+        );
+        assert_eq!(
+            action("key ctrl+hyper"),
+            Err("unknown key hyper".to_string())
+        );
+        assert_eq!(action("key"), Err("missing key".to_string()));
+        assert_eq!(
+            action("macro h e y hyper"),
+            Err("unknown key hyper".to_string())
+        );
+    }
+
+    #[test]
+    fn chords() {
+        assert_eq!(action("key ctrl+shift+t"), Ok(chord(0x03, &[0x17])));
+        assert_eq!(action("key Right-Alt+gui+tab"), Ok(chord(0x48, &[0x2b])));
+        // keys held together, with no modifier
+        assert_eq!(action("key a+b"), Ok(chord(0, &[0x04, 0x05])));
+        // a modifier alone is a chord with no key
+        assert_eq!(action("key shift"), Ok(chord(0x02, &[])));
+
+        assert_eq!(
+            action("key ctrl+a+b+c+d+e+f"),
+            Ok(chord(0x01, &[0x04, 0x05, 0x06, 0x07, 0x08, 0x09]))
+        );
+        assert_eq!(
+            action("key ctrl+a+b+c+d+e+f+g"),
+            Err(format!("at most {} keys can be held together", CHORD_LEN))
+        );
+    }
+
+    #[test]
+    fn media_keys() {
+        assert_eq!(action("media play-pause"), Ok(IrAction::MediaKey(0xcd)));
+        assert_eq!(action("media Volume-Up"), Ok(IrAction::MediaKey(0xe9)));
+        assert_eq!(action("media 0x223"), Ok(IrAction::MediaKey(0x223)));
+        assert_eq!(
+            action("media eject"),
+            Err("unknown media key eject".to_string())
+        );
+        assert_eq!(
+            action("media 0x10000"),
+            Err("bad media usage id 0x10000".to_string())
+        );
+    }
+
+    #[test]
+    fn texts() {
+        let longest = "x".repeat(MAX_HID_TEXT);
+        assert_eq!(
+            action(&format!("type {}", longest)),
+            Ok(IrAction::TypeText(longest.as_str().try_into().unwrap()))
+        );
+        // the words are typed with one space between them
+        assert_eq!(
+            action("type see  you"),
+            Ok(IrAction::TypeText("see you".try_into().unwrap()))
+        );
+
+        assert_eq!(
+            action(&format!("type {}x", longest)),
+            Err(format!("at most {} characters can be typed", MAX_HID_TEXT))
+        );
+        assert_eq!(
+            action("type caffè"),
+            Err("only ascii characters can be typed".to_string())
+        );
+        assert_eq!(action("type"), Err("missing text".to_string()));
+    }
+}
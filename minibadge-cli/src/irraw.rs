@@ -0,0 +1,290 @@
+use clap::ValueEnum;
+use minibadge_protocol::{MAX_CARRIER, MAX_IR_RAW, MIN_CARRIER};
+
+/// Written in the Pronto codes, the badge can't tell the carrier of what it receives
+const CARRIER: f64 = 38_000.0;
+
+/// Microseconds per unit of the Pronto frequency word
+const PRONTO_CLOCK: f64 = 0.241246;
+
+/// Silence after a frame, where a format wants one
+const GAP: u32 = 20_000;
+
+/// Text formats of the raw captures
+#[derive(Clone, Copy, Debug, ValueEnum)]
+pub enum Format {
+    /// A lircd.conf remote with raw codes
+    Lirc,
+    /// Pronto hex, as used by many universal remotes
+    Pronto,
+}
+
+pub fn format(format: Format, name: &str, durations: &[u16]) -> String {
+    match format {
+        Format::Lirc => to_lirc(name, durations),
+        Format::Pronto => to_pronto(durations),
+    }
+}
+
+fn to_lirc(name: &str, durations: &[u16]) -> String {
+    let mut text = format!(
+        "begin remote\n\
+         \x20 name  minibadge\n\
+         \x20 flags RAW_CODES\n\
+         \x20 eps   30\n\
+         \x20 aeps  100\n\
+         \x20 gap   {}\n\
+         \x20 begin raw_codes\n\
+         \x20   name {}\n",
+        GAP, name
+    );
+
+    for line in durations.chunks(8) {
+        let line: Vec<String> = line.iter().map(|d| format!("{:>6}", d)).collect();
+        text += &format!("     {}\n", line.join(""));
+    }
+
+    text + "  end raw_codes\nend remote\n"
+}
+
+fn to_pronto(durations: &[u16]) -> String {
+    let frequency = (1_000_000.0 / (CARRIER * PRONTO_CLOCK)).round() as u16;
+    let period = f64::from(frequency) * PRONTO_CLOCK;
+
+    let mut cycles: Vec<u16> = durations
+        .iter()
+        .map(|d| (f64::from(*d) / period).round().max(1.0) as u16)
+        .collect();
+    // pronto has pairs of carrier and silence, the last silence is the gap after the frame
+    if cycles.len() % 2 == 1 {
+        cycles.push((f64::from(GAP) / period).round() as u16);
+    }
+
+    let mut words = vec![0, frequency, (cycles.len() / 2) as u16, 0];
+    words.extend(cycles);
+
+    let words: Vec<String> = words.iter().map(|w| format!("{:04X}", w)).collect();
+    words.join(" ") + "\n"
+}
+
+/// A frame read from a file
+pub struct Capture {
+    pub durations: Vec<u16>,
+    /// Hz, when the file tells
+    pub carrier: Option<u32>,
+}
+
+/// Reads a capture: a lircd.conf with raw codes (the first one, or the one called name),
+/// Pronto hex, or just the durations in microseconds
+pub fn parse(text: &str, name: Option<&str>) -> Result<Capture, String> {
+    let (durations, carrier) = if text.contains("begin raw_codes") {
+        parse_lirc(text, name)?
+    } else if text.split_whitespace().next() == Some("0000") {
+        parse_pronto(text)?
+    } else {
+        (parse_numbers(text.split_whitespace())?, None)
+    };
+
+    if let Some(carrier) = carrier.filter(|c| !(MIN_CARRIER..=MAX_CARRIER).contains(c)) {
+        return Err(format!("the badge can't send a {} Hz carrier", carrier));
+    }
+    if durations.is_empty() {
+        return Err("no durations".to_string());
+    }
+    if durations.len() > MAX_IR_RAW {
+        return Err(format!(
+            "{} durations, the badge sends at most {}",
+            durations.len(),
+            MAX_IR_RAW
+        ));
+    }
+
+    Ok(Capture { durations, carrier })
+}
+
+fn parse_numbers<'a>(words: impl Iterator<Item = &'a str>) -> Result<Vec<u16>, String> {
+    words
+        .map(|word| word.parse().map_err(|_| format!("bad duration {}", word)))
+        .collect()
+}
+
+fn parse_lirc(text: &str, name: Option<&str>) -> Result<(Vec<u16>, Option<u32>), String> {
+    let mut codes: Vec<(&str, Vec<&str>)> = vec![];
+    let mut in_codes = false;
+    let mut carrier = None;
+
+    for line in text.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut words = line.split_whitespace().peekable();
+
+        match (words.next(), words.peek()) {
+            (Some("begin"), Some(&"raw_codes")) => in_codes = true,
+            (Some("end"), Some(&"raw_codes")) => in_codes = false,
+            (Some("frequency"), Some(frequency)) if !in_codes => {
+                let frequency = frequency
+                    .parse()
+                    .map_err(|_| format!("bad frequency {}", frequency))?;
+                carrier = Some(frequency)
+            }
+            (Some("name"), Some(code)) if in_codes => codes.push((*code, vec![])),
+            (Some(word), _) if in_codes => match codes.last_mut() {
+                Some((_, durations)) => durations.extend([word].into_iter().chain(words)),
+                None => return Err(format!("duration {} before the name of a code", word)),
+            },
+            _ => {}
+        }
+    }
+
+    let code = match name {
+        Some(name) => codes.into_iter().find(|(code, _)| *code == name),
+        None => codes.into_iter().next(),
+    };
+    let (_, durations) = code.ok_or_else(|| format!("no code {}", name.unwrap_or_default()))?;
+
+    Ok((parse_numbers(durations.into_iter())?, carrier))
+}
+
+fn parse_pronto(text: &str) -> Result<(Vec<u16>, Option<u32>), String> {
+    let words = text
+        .split_whitespace()
+        .map(|word| u16::from_str_radix(word, 16).map_err(|_| format!("bad pronto word {}", word)))
+        .collect::<Result<Vec<u16>, String>>()?;
+
+    let [kind, frequency, once, repeat, cycles @ ..] = words.as_slice() else {
+        return Err("the pronto code is too short".to_string());
+    };
+    if *kind != 0 {
+        return Err("only learned pronto codes, starting with 0000, are supported".to_string());
+    }
+
+    let (once, repeat) = (usize::from(*once) * 2, usize::from(*repeat) * 2);
+    if cycles.len() < once + repeat {
+        return Err("the pronto code is shorter than its sequences".to_string());
+    }
+
+    // a frame sent once, or else the one sent while the button is held
+    let mut sequence = match once {
+        0 => &cycles[..repeat],
+        _ => &cycles[..once],
+    };
+    // the gap after the frame is not sent
+    if let [frame @ .., _] = sequence {
+        sequence = frame;
+    }
+
+    if *frequency == 0 {
+        return Err("the pronto code has no carrier".to_string());
+    }
+    let period = f64::from(*frequency) * PRONTO_CLOCK;
+    let durations = sequence
+        .iter()
+        .map(|c| (f64::from(*c) * period).round().min(f64::from(u16::MAX)) as u16)
+        .collect();
+
+    Ok((durations, Some((1_000_000.0 / period).round() as u32)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pronto counts whole periods of the carrier, the durations come back within one
+    fn assert_close(durations: &[u16], expected: &[u16]) {
+        assert_eq!(durations.len(), expected.len(), "{:?}", durations);
+        for (duration, expected) in durations.iter().zip(expected) {
+            assert!(duration.abs_diff(*expected) <= 27, "{:?}", durations);
+        }
+    }
+
+    #[test]
+    fn pronto_round_trip() {
+        let frame = [9000, 4500, 560, 560, 560, 1690, 560];
+        let pronto = to_pronto(&frame);
+        let words: Vec<&str> = pronto.split_whitespace().collect();
+
+        // 38 kHz, four pairs once and none repeated
+        assert_eq!(words[..4], ["0000", "006D", "0004", "0000"]);
+        // the frame ends with a pulse, the gap after it makes the last pair: 20 ms, 761 periods
+        assert_eq!(words.len(), 4 + 8);
+        assert_eq!(words[11], "02F9");
+
+        let capture = parse(&pronto, None).unwrap();
+        assert_close(&capture.durations, &frame);
+        let carrier = capture.carrier.unwrap();
+        assert!((37_900..=38_100).contains(&carrier), "{}", carrier);
+    }
+
+    #[test]
+    fn pronto_sequences() {
+        let period = 109.0 * PRONTO_CLOCK;
+        let cycles = |cycles: &[u16]| -> Vec<u16> {
+            cycles
+                .iter()
+                .map(|c| (f64::from(*c) * period).round() as u16)
+                .collect()
+        };
+
+        // the frame sent once wins over the repeated one
+        let capture = parse("0000 006D 0001 0001 0010 0020 0030 0040", None).unwrap();
+        assert_eq!(capture.durations, cycles(&[0x10]));
+
+        // with no frame sent once, the repeated one
+        let capture = parse("0000 006D 0000 0002 0010 0020 0030 0040", None).unwrap();
+        assert_eq!(capture.durations, cycles(&[0x10, 0x20, 0x30]));
+    }
+
+    #[test]
+    fn bad_pronto() {
+        assert_eq!(
+            parse("0000 006D 0001", None).err().unwrap(),
+            "the pronto code is too short"
+        );
+        assert_eq!(
+            parse("0000 006D 0002 0000 0010 0020", None).err().unwrap(),
+            "the pronto code is shorter than its sequences"
+        );
+        assert_eq!(
+            parse("0000 0000 0001 0000 0010 0020", None).err().unwrap(),
+            "the pronto code has no carrier"
+        );
+    }
+
+    #[test]
+    fn lirc_code_by_name() {
+        let conf = "\
+begin remote
+  name  tv
+  flags RAW_CODES
+  frequency 36000  # a philips remote
+  begin raw_codes
+    name KEY_POWER
+       889    889   1778
+    name KEY_MUTE
+       889   1778    889
+       889    889
+  end raw_codes
+end remote
+";
+
+        let capture = parse(conf, Some("KEY_MUTE")).unwrap();
+        assert_eq!(capture.durations, [889, 1778, 889, 889, 889]);
+        assert_eq!(capture.carrier, Some(36_000));
+
+        // the first code, when no name is given
+        let capture = parse(conf, None).unwrap();
+        assert_eq!(capture.durations, [889, 889, 1778]);
+
+        assert_eq!(
+            parse(conf, Some("KEY_EJECT")).err().unwrap(),
+            "no code KEY_EJECT"
+        );
+    }
+
+    #[test]
+    fn lirc_round_trip() {
+        let frame = [9000, 4500, 560, 560, 560, 1690, 560, 1690, 560];
+        let capture = parse(&to_lirc("KEY_UP", &frame), Some("KEY_UP")).unwrap();
+        assert_eq!(capture.durations, frame);
+        assert_eq!(capture.carrier, None);
+    }
+}
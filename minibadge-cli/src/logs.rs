@@ -0,0 +1,303 @@
+use std::{
+    io::{self, IsTerminal, Read},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::ValueEnum;
+use regex::Regex;
+
+use crate::ir;
+
+/// Log levels of the firmware, from the most to the least important
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum Level {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl Level {
+    const ALL: [Level; 6] = [
+        Level::Off,
+        Level::Error,
+        Level::Warn,
+        Level::Info,
+        Level::Debug,
+        Level::Trace,
+    ];
+
+    /// The name used by the `log` crate
+    fn name(&self) -> &'static str {
+        match self {
+            Level::Off => "OFF",
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        }
+    }
+
+    fn parse(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|level| level.name() == name)
+    }
+
+    /// ANSI escape code of the color of the level
+    fn color(&self) -> &'static str {
+        match self {
+            Level::Off => "",
+            Level::Error => "\x1b[31m",
+            Level::Warn => "\x1b[33m",
+            Level::Info => "\x1b[32m",
+            Level::Debug => "\x1b[36m",
+            Level::Trace => "\x1b[90m",
+        }
+    }
+}
+
+/// What to show of the log stream
+pub struct LogOptions {
+    /// Lines less important than this are hidden
+    pub level: Level,
+    /// Only lines matching this are shown
+    pub filter: Option<Regex>,
+    /// Only show the received infrared commands, one JSON object per line.
+    /// They come from the structured records of the JSON log format
+    pub ir_only: bool,
+    pub color: bool,
+}
+
+/// The level of a module of the firmware, written as module=level
+#[derive(Clone, Debug)]
+pub struct ModuleLevel {
+    pub module: String,
+    pub level: Level,
+}
+
+impl FromStr for ModuleLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (module, level) = s
+            .split_once('=')
+            .ok_or("Expected module=level, like antani_sw::usb=debug")?;
+
+        Ok(ModuleLevel {
+            module: module.to_string(),
+            level: Level::from_str(level, true)?,
+        })
+    }
+}
+
+/// The target of the records of the received infrared commands, it must match the firmware
+const IR_TARGET: &str = "ir";
+
+/// The fields of a JSON record that are not key/value fields of its own
+const RECORD_FIELDS: [&str; 5] = ["t", "level", "module", "target", "msg"];
+
+/// A line sent by the firmware, like "[INFO] Program start",
+/// or a JSON object when the badge uses the json log format
+struct LogLine {
+    level: Option<Level>,
+    message: String,
+}
+
+impl LogLine {
+    fn parse(line: &str) -> Self {
+        if line.starts_with('{') {
+            if let Some(log_line) = Self::parse_json(line) {
+                return log_line;
+            }
+        }
+
+        let parsed = line
+            .strip_prefix('[')
+            .and_then(|rest| rest.split_once("] "))
+            .and_then(|(level, message)| Some((Level::parse(level)?, message)));
+
+        match parsed {
+            Some((level, message)) => LogLine {
+                level: Some(level),
+                message: message.to_string(),
+            },
+            // half a line after connecting, or a panic message
+            None => LogLine {
+                level: None,
+                message: line.to_string(),
+            },
+        }
+    }
+
+    // {"t":1234,"level":"INFO","module":"antani_sw::usb","msg":"Connected"},
+    // the key/value fields of the record follow the message like in the text format
+    fn parse_json(line: &str) -> Option<Self> {
+        let record: serde_json::Value = serde_json::from_str(line).ok()?;
+
+        let level = Level::parse(record["level"].as_str()?)?;
+        let module = record["module"].as_str().unwrap_or_default();
+        let time = record["t"].as_u64().unwrap_or_default();
+        let mut message = record["msg"].as_str()?.to_string();
+
+        for (key, value) in record.as_object()? {
+            if RECORD_FIELDS.contains(&key.as_str()) {
+                continue;
+            }
+            match value {
+                serde_json::Value::String(value) => message += &format!(" {}={}", key, value),
+                value => message += &format!(" {}={}", key, value),
+            }
+        }
+
+        Some(LogLine {
+            level: Some(level),
+            message: format!(
+                "[{}.{:03}] {}: {}",
+                time / 1000,
+                time % 1000,
+                module,
+                message
+            ),
+        })
+    }
+}
+
+/// An infrared command received by the badge
+struct IrCommand {
+    protocol: &'static str,
+    address: u16,
+    command: u16,
+    repeat: bool,
+}
+
+impl IrCommand {
+    /// Reads the command from its record in the JSON log, like
+    /// {"t":1234,"level":"INFO","module":"antani_sw","target":"ir","msg":"Received",
+    /// "protocol":"Nec","address":0,"command":70,"repeat":false}
+    fn parse(line: &str) -> Option<Self> {
+        let record: serde_json::Value = serde_json::from_str(line).ok()?;
+        if record["target"] != IR_TARGET {
+            return None;
+        }
+
+        Some(IrCommand {
+            // the firmware logs the protocol as the enum of minibadge-protocol
+            protocol: ir::PROTOCOLS
+                .iter()
+                .find(|(_, protocol)| record["protocol"] == format!("{:?}", protocol))
+                .map(|(name, _)| *name)?,
+            address: record["address"].as_u64()?.try_into().ok()?,
+            command: record["command"].as_u64()?.try_into().ok()?,
+            repeat: record["repeat"].as_bool()?,
+        })
+    }
+
+    fn to_json(&self, time: &str) -> String {
+        format!(
+            r#"{{"time":"{}","protocol":"{}","address":{},"command":{},"repeat":{}}}"#,
+            time, self.protocol, self.address, self.command, self.repeat
+        )
+    }
+}
+
+/// Prints the log of the badge until the port is closed
+pub fn follow(port: &str, options: &LogOptions) -> io::Result<()> {
+    // the logger sends data only when there is something to say
+    let mut port = serialport::new(port, 115_200)
+        .timeout(Duration::from_millis(500))
+        .open()?;
+
+    let color = options.color && io::stdout().is_terminal();
+
+    let mut buf = [0; 256];
+    let mut pending: Vec<u8> = vec![];
+    let mut told_about_text = false;
+
+    loop {
+        let n = match port.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+            Err(e) => return Err(e),
+        };
+        pending.extend_from_slice(&buf[..n]);
+
+        while let Some(end) = pending.iter().position(|b| *b == b'\n') {
+            let raw: Vec<u8> = pending.drain(..=end).collect();
+            let line = String::from_utf8_lossy(&raw);
+            let line = line.trim_end_matches(['\r', '\n']);
+            if line.is_empty() {
+                continue;
+            }
+
+            let time = chrono::Local::now().format("%H:%M:%S%.3f").to_string();
+            if options.ir_only {
+                if let Some(ir) = IrCommand::parse(line) {
+                    println!("{}", ir.to_json(&time));
+                } else if !line.starts_with('{') && !told_about_text {
+                    eprintln!("The badge logs plain text, switch it to JSON with log-level --json");
+                    told_about_text = true;
+                }
+                continue;
+            }
+
+            let log_line = LogLine::parse(line);
+            if log_line.level.is_some_and(|level| level > options.level) {
+                continue;
+            }
+
+            if let Some(filter) = &options.filter {
+                if !filter.is_match(&log_line.message) {
+                    continue;
+                }
+            }
+
+            match (log_line.level, color) {
+                (Some(level), true) => println!(
+                    "\x1b[90m{}\x1b[0m {}{:5}\x1b[0m {}",
+                    time,
+                    level.color(),
+                    level.name(),
+                    log_line.message
+                ),
+                (Some(level), false) => {
+                    println!("{} {:5} {}", time, level.name(), log_line.message)
+                }
+                (None, _) => println!("{} {}", time, log_line.message),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IR_RECORD: &str = r#"{"t":61234,"level":"INFO","module":"antani_sw","target":"ir","msg":"Received","protocol":"Rc5","address":20,"command":80,"repeat":true}"#;
+
+    #[test]
+    fn ir_record() {
+        let ir = IrCommand::parse(IR_RECORD).unwrap();
+        assert_eq!(
+            ir.to_json("12:00:00.000"),
+            r#"{"time":"12:00:00.000","protocol":"rc5","address":20,"command":80,"repeat":true}"#
+        );
+
+        let line = LogLine::parse(IR_RECORD);
+        assert_eq!(line.level, Some(Level::Info));
+        assert_eq!(
+            line.message,
+            "[61.234] antani_sw: Received address=20 command=80 protocol=Rc5 repeat=true"
+        );
+    }
+
+    #[test]
+    fn not_ir_records() {
+        let connected = r#"{"t":1,"level":"INFO","module":"antani_sw::usb","msg":"Connected"}"#;
+        assert!(IrCommand::parse(connected).is_none());
+        assert!(IrCommand::parse(&IR_RECORD.replace("Rc5", "Morse")).is_none());
+        assert!(IrCommand::parse("[INFO] Received protocol=Rc5 address=20").is_none());
+    }
+}
@@ -1,17 +1,31 @@
-use std::{io::Write, time::Duration};
+use std::{fs, io, ops::RangeInclusive, path::PathBuf, process::ExitCode, time::Duration};
 
+mod audio;
+mod discovery;
+mod flash;
+mod ir;
+mod irraw;
+mod logs;
+mod messages;
 mod midi;
+mod transport;
 
 use clap::{Args, Parser, Subcommand};
 
-use capnp::message::Builder;
-use capnp::serialize;
-use midi::MidiColors;
+use audio::AudioLevels;
+use logs::{Level, ModuleLevel};
+use midi::{MidiColors, MidiOutEvent};
+use minibadge_protocol::{
+    BadgeMessage, IrCarrier, IrCommand, MAX_BADGE_TEXT, MAX_CARRIER, MIN_CARRIER,
+};
+use regex::Regex;
 use smart_leds::RGB8;
+use transport::{
+    FleetTransport, LoopbackTransport, MidiTransport, SerialTransport, Target, Transport,
+};
 
-pub mod usb_messages_capnp {
-    include!(concat!(env!("OUT_DIR"), "/usb_messages_capnp.rs"));
-}
+pub use minibadge_protocol::usb_messages_capnp;
+use usb_messages_capnp::IrProtocol;
 
 #[derive(Parser)]
 struct Cli {
@@ -19,9 +33,36 @@ struct Cli {
     ///
     /// This is the management interface with capnp, not the debug interface
     ///
-    /// Defaults to /dev/ttyACM0
-    #[arg(short, long)]
-    serial_port: Option<String>,
+    /// Defaults to the management port of the connected badge
+    ///
+    /// Can be given more than once, or as a comma separated list, to talk to many badges
+    #[arg(short, long, value_delimiter = ',')]
+    serial_port: Vec<String>,
+
+    /// Serial number of the badge to talk to, when more than one is connected
+    ///
+    /// The serial numbers are shown by the list command
+    ///
+    /// Can be given more than once, or as a comma separated list, to talk to many badges
+    #[arg(short, long, value_delimiter = ',', conflicts_with = "serial_port")]
+    badge: Vec<String>,
+
+    /// Talk to all the connected badges at once
+    #[arg(short, long, conflicts_with_all = ["serial_port", "badge"])]
+    all: bool,
+
+    /// Talk to the badge with MIDI SysEx messages instead of the serial port
+    ///
+    /// The argument is the path to the midi device of the badge
+    /// For example: /dev/snd/midiC1D0
+    ///
+    /// Without it, the midi device of the connected badges is used (Linux only)
+    #[arg(short = 'x', long, conflicts_with = "serial_port")]
+    sysex: Option<Option<String>>,
+
+    /// Do not talk to the badge, print the messages that would be sent
+    #[arg(long)]
+    dry_run: bool,
 
     /// Set the badge to a solid color, the color should be written in hex format
     /// like "#ff0000" for red, etc.
@@ -50,8 +91,80 @@ struct Cli {
 
 #[derive(Subcommand)]
 enum Subcommands {
+    /// List the connected badges, with their serial ports and midi devices
+    List,
     /// Use the badge to send an infrared NEC command
     SendNec(SendNec),
+    /// Use the badge to send an infrared command of any protocol it knows
+    SendIr(SendIr),
+    /// Stream spectrum levels to the badge for the audio reactive scenes
+    ///
+    /// Select one of the audio scenes on the badge to see the result
+    Audio(Audio),
+    /// Choose the MIDI messages the badge sends to the computer
+    ///
+    /// Events are written as kind:channel:number:value, where kind is one of
+    /// none, note, cc or program. For example "note:1:60:100" or "cc:2:20:127"
+    MidiMap(MidiMap),
+    /// Select one of the built-in scenes of the badge
+    Scene(Scene),
+    /// Choose what the buttons of infrared remotes do
+    ///
+    /// The bindings are kept by the badge across restarts
+    IrBindings(IrBindings),
+    /// Bind the next button of a remote the badge receives to an action
+    ///
+    /// Holding the button of the badge for 4 seconds does the same,
+    /// the remote button then selects the current scene
+    IrLearn(IrLearn),
+    /// Choose what a short press of the button of the badge does
+    ///
+    /// The next scene out of the box, the action is kept by the badge across restarts
+    Button(Button),
+    /// Record the next frame of a remote the badge receives, whatever its protocol
+    ///
+    /// The timings are printed, or written to a file for ir-replay
+    IrCapture(IrCapture),
+    /// Send a frame recorded by ir-capture, or found in a LIRC or Pronto file
+    IrReplay(IrReplay),
+    /// Have the badge tell something to the other badges in sight, over infrared
+    #[command(subcommand)]
+    Beam(Beam),
+    /// List the badges this one has met over infrared
+    ///
+    /// The badges greet each other every minute or so, a badge heard again
+    /// after 10 minutes counts as a new meeting
+    Contacts,
+    /// Have the badge send again the scenes, times and texts of the other badges
+    ///
+    /// So they reach the badges out of sight, across a crowd: a message crosses
+    /// up to three relays. The setting is kept by the badge across restarts
+    Relay(Relay),
+    /// Show the log of the badge, from its debug serial port
+    ///
+    /// Lines are timestamped and colored by level
+    Logs(Logs),
+    /// Change what the badge logs, until it restarts
+    LogLevel(LogLevel),
+    /// Show the firmware version and build of the badge
+    Info,
+    /// Restart the badge
+    Reboot(Reboot),
+    /// Install a new firmware on the badge
+    ///
+    /// The badge restarts in its USB bootloader, and the UF2 file is copied
+    /// to the drive it shows up as, when the system mounts it
+    Flash(Flash),
+}
+
+impl Subcommands {
+    /// The subcommands that wait for a reply, a fleet can't give one
+    fn is_request(&self) -> bool {
+        matches!(
+            self,
+            Subcommands::Info | Subcommands::Contacts | Subcommands::IrCapture(_)
+        )
+    }
 }
 
 #[derive(Args, Debug)]
@@ -67,114 +180,988 @@ struct SendNec {
     repeat: bool,
 }
 
-fn hex_color_to_rgb(color: String) -> RGB8 {
-    let color = color.trim_start_matches("#");
-    let r = u8::from_str_radix(&color[0..2], 16).unwrap();
-    let g = u8::from_str_radix(&color[2..4], 16).unwrap();
-    let b = u8::from_str_radix(&color[4..6], 16).unwrap();
-    RGB8 { r, g, b }
+#[derive(Args, Debug)]
+struct Audio {
+    /// WAV file to analyze
+    ///
+    /// If not given, raw signed 16 bit little endian PCM is read from stdin
+    #[arg(short, long)]
+    wav: Option<String>,
+    /// Sample rate of the PCM data read from stdin
+    #[arg(long, default_value_t = 44_100, value_parser = clap::value_parser!(u32).range(1000..))]
+    sample_rate: u32,
+    /// Number of interleaved channels of the PCM data read from stdin
+    #[arg(long, default_value_t = 1)]
+    channels: u16,
+    /// Updates sent to the badge every second
+    #[arg(short, long, default_value_t = 30, value_parser = clap::value_parser!(u32).range(1..=240))]
+    fps: u32,
+}
+
+#[derive(Args, Debug)]
+struct MidiMap {
+    /// Sent when the button is pressed
+    #[arg(short, long, default_value = "note:1:60:100")]
+    short_press: MidiOutEvent,
+    /// Sent when the button is held down
+    #[arg(short, long, default_value = "note:1:62:100")]
+    long_press: MidiOutEvent,
+    /// Sent when an infrared command is received, the command is added to the number
+    #[arg(short, long, default_value = "note:2:0:100")]
+    ir_received: MidiOutEvent,
+}
+
+#[derive(Args, Debug)]
+struct Scene {
+    /// Scene number, starting from 0
+    scene: u8,
+}
+
+#[derive(Args, Debug)]
+struct SendIr {
+    /// nec, samsung, apple, rc5, rc6 or sirc
+    #[arg(short, long, value_parser = ir::parse_protocol)]
+    protocol: IrProtocol,
+    /// Address, the device id for apple
+    #[arg(short, long)]
+    address: u16,
+    /// Command, bit 6 is the RC5X extension for rc5 and bits 7-11 the command page for apple
+    #[arg(short, long)]
+    command: u16,
+    /// The button is held down
+    #[arg(short, long)]
+    repeat: bool,
+    #[command(flatten)]
+    carrier: Carrier,
+}
+
+const CARRIER_RANGE: RangeInclusive<i64> = MIN_CARRIER as i64..=MAX_CARRIER as i64;
+
+#[derive(Args, Debug)]
+struct Carrier {
+    /// Carrier frequency in Hz, like 36000, 38000, 40000 or 56000, instead of the usual one
+    #[arg(long, value_parser = clap::value_parser!(u32).range(CARRIER_RANGE))]
+    carrier: Option<u32>,
+    /// Percent of each carrier period the led is on [default: 50]
+    #[arg(long, requires = "carrier", value_parser = clap::value_parser!(u8).range(1..=99))]
+    duty_cycle: Option<u8>,
+}
+
+impl Carrier {
+    /// None leaves the carrier to the badge, or to the file being replayed
+    fn carrier(&self, frequency: Option<u32>) -> Option<IrCarrier> {
+        Some(IrCarrier {
+            frequency: self.carrier.or(frequency)?,
+            duty_cycle: self.duty_cycle.unwrap_or(50),
+        })
+    }
+}
+
+#[derive(Args, Debug)]
+struct IrBindings {
+    /// File with one binding per line, like "nec 0 68 next-scene", see the README
+    #[arg(required_unless_present = "reset")]
+    file: Option<PathBuf>,
+    /// Go back to the built-in bindings
+    #[arg(long, conflicts_with = "file")]
+    reset: bool,
+}
+
+#[derive(Args, Debug)]
+struct IrLearn {
+    /// What the button does, like next-scene, "scene 3", "key ctrl+shift+t", "media play-pause",
+    /// "macro h e l l o" or "type hello camp"
+    #[arg(required = true, num_args = 1..)]
+    action: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct Button {
+    /// What the button does, like next-scene, "key right" or "media play-pause"
+    #[arg(required = true, num_args = 1..)]
+    action: Vec<String>,
+}
+
+#[derive(Args, Debug)]
+struct IrCapture {
+    /// Where to write the capture, instead of printing it
+    file: Option<PathBuf>,
+    /// Text format of the capture
+    #[arg(short, long, value_enum, default_value = "lirc")]
+    format: irraw::Format,
+    /// Name of the code in the LIRC file
+    #[arg(short, long, default_value = "capture")]
+    name: String,
+}
+
+#[derive(Args, Debug)]
+struct IrReplay {
+    /// A LIRC file with raw codes, a Pronto hex code or a list of microseconds
+    file: PathBuf,
+    /// Code to send from the LIRC file, the first one by default
+    #[arg(short, long)]
+    name: Option<String>,
+    #[command(flatten)]
+    carrier: Carrier,
+}
+
+#[derive(Subcommand, Debug)]
+enum Beam {
+    /// The other badges play the boot animation
+    Hello,
+    /// The other badges switch to the current scene of this one
+    Scene,
+    /// The other badges scroll a text
+    Text {
+        /// Up to 16 letters, the badges have no font for the other characters
+        #[arg(value_parser = ir::parse_badge_text)]
+        text: heapless::String<MAX_BADGE_TEXT>,
+    },
+    /// The other badges take the animation time of this one, so they run in step
+    Time,
+}
+
+#[derive(Args, Debug)]
+struct Relay {
+    /// Stop relaying
+    #[arg(long)]
+    off: bool,
+}
+
+#[derive(Args, Debug)]
+struct LogLevel {
+    /// Level of every module without its own level
+    #[arg(value_enum)]
+    level: Level,
+    /// Level of a module of the firmware, like antani_sw::usb=debug
+    ///
+    /// Can be given up to 4 times, the longest matching module wins
+    #[arg(short, long)]
+    module: Vec<ModuleLevel>,
+    /// Send one JSON object per line instead of plain text, the logs subcommand understands both
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Args, Debug)]
+struct Reboot {
+    /// Restart in the USB bootloader, to copy a new firmware by hand
+    #[arg(short, long)]
+    bootloader: bool,
+}
+
+#[derive(Args, Debug)]
+struct Flash {
+    /// Firmware to install, as built by elf2uf2-rs
+    uf2: PathBuf,
+    /// The badge is already in the bootloader, don't ask it to restart
+    #[arg(long)]
+    no_reboot: bool,
+    /// Directory where the bootloader drive is mounted, found by itself when not given
+    ///
+    /// Nothing is checked, any directory works
+    #[arg(long)]
+    mount: Option<PathBuf>,
+    /// Seconds to wait for the bootloader drive
+    #[arg(long, default_value_t = 15)]
+    timeout: u64,
+}
+
+#[derive(Args, Debug)]
+struct Logs {
+    /// Debug serial port of the badge, found by itself when not given
+    #[arg(short, long)]
+    port: Option<String>,
+    /// Hide the lines less important than this
+    #[arg(short, long, value_enum, default_value_t = Level::Trace)]
+    level: Level,
+    /// Only show the lines matching this regular expression
+    #[arg(short, long)]
+    grep: Option<Regex>,
+    /// Only show the received infrared commands, as JSON objects, one per line
+    ///
+    /// They are read from the JSON log, switch the badge to it with log-level --json
+    #[arg(long)]
+    ir: bool,
+    /// Do not color the output
+    #[arg(long)]
+    no_color: bool,
+}
+
+fn hex_color_to_rgb(color: &str) -> io::Result<RGB8> {
+    let invalid = || {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("Invalid color {:?}, expected #rrggbb", color),
+        )
+    };
+
+    let hex = color.trim_start_matches("#");
+    if hex.len() != 6 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(invalid());
+    }
+
+    let channel = |i: usize| u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid());
+    Ok(RGB8 {
+        r: channel(0)?,
+        g: channel(2)?,
+        b: channel(4)?,
+    })
 }
 
 fn midi_demo(file: String) {
     let mut lp = MidiColors::new(&file).expect("Failed to open device");
+
+    // green
+    lp.control_change(MidiColors::CC_HUE, 42)
+        .expect("Failed to set hue");
+    lp.control_change(MidiColors::CC_BRIGHTNESS, 127)
+        .expect("Failed to set brightness");
+
     for x in 0..3 {
         for y in 0..3 {
-            lp.led_ctrl_rgb(x, y, x * 127 / 3, y * 127 / 3, 0)
+            lp.led_ctrl(x, y, (x * 3 + y + 1) * 127 / 9)
                 .expect("Failed to set LED color");
         }
     }
 
-    lp.led_ctrl_rgb(1, 1, 0, 0, 127)
-        .expect("Failed to set LED color");
+    // speed up the animations of the normal scenes, too
+    lp.control_change(MidiColors::CC_SPEED, 96)
+        .expect("Failed to set speed");
 }
 
-fn main() {
+// the badge gives up after 10 seconds without a remote
+const IR_CAPTURE_TIMEOUT: Duration = Duration::from_secs(12);
+
+fn run(args: &Cli, transport: &mut dyn Transport) -> io::Result<()> {
+    match &args.subcommand {
+        Some(Subcommands::SendNec(send_nec)) => {
+            transport.send(&messages::send_nec(
+                send_nec.address,
+                send_nec.command,
+                send_nec.repeat,
+            ))?;
+        }
+        Some(Subcommands::SendIr(send_ir)) => {
+            transport.send(&messages::send_ir(
+                &IrCommand {
+                    protocol: send_ir.protocol,
+                    address: send_ir.address,
+                    command: send_ir.command,
+                    repeat: send_ir.repeat,
+                },
+                send_ir.carrier.carrier(None).as_ref(),
+            ))?;
+        }
+        Some(Subcommands::Audio(audio)) => {
+            let send = |levels: &AudioLevels| transport.send(&messages::audio_levels(levels));
+
+            match &audio.wav {
+                Some(wav) => audio::stream_wav(wav, audio.fps, send),
+                None => audio::stream_stdin(audio.sample_rate, audio.channels, audio.fps, send),
+            }?;
+        }
+        Some(Subcommands::Scene(scene)) => {
+            transport.send(&messages::scene(scene.scene))?;
+        }
+        Some(Subcommands::MidiMap(midi_map)) => {
+            transport.send(&messages::midi_mapping(
+                &midi_map.short_press,
+                &midi_map.long_press,
+                &midi_map.ir_received,
+            ))?;
+        }
+        Some(Subcommands::IrBindings(ir_bindings)) => match &ir_bindings.file {
+            Some(file) => {
+                let bindings = ir::parse_bindings(&fs::read_to_string(file)?)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+                transport.send(&messages::ir_bindings(&bindings))?;
+            }
+            None => transport.send(&messages::reset_ir_bindings())?,
+        },
+        Some(Subcommands::IrLearn(ir_learn)) => {
+            let words = ir_learn.action.iter().flat_map(|a| a.split_whitespace());
+            let action = ir::parse_action(words)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            transport.send(&messages::learn_ir_binding(&action))?;
+        }
+        Some(Subcommands::Button(button)) => {
+            let words = button.action.iter().flat_map(|a| a.split_whitespace());
+            let action = ir::parse_action(words)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+            transport.send(&messages::set_button_action(&action))?;
+        }
+        Some(Subcommands::IrCapture(ir_capture)) => {
+            println!("Point the remote at the badge and press a button");
+            let reply = transport.request(&messages::capture_ir_raw(), IR_CAPTURE_TIMEOUT)?;
+            let durations = messages::ir_raw(&reply).map_err(io::Error::other)?;
+            if durations.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "The badge received nothing",
+                ));
+            }
+
+            let text = irraw::format(ir_capture.format, &ir_capture.name, &durations);
+            match &ir_capture.file {
+                Some(file) => fs::write(file, text)?,
+                None => print!("{}", text),
+            }
+        }
+        Some(Subcommands::IrReplay(ir_replay)) => {
+            let capture = irraw::parse(
+                &fs::read_to_string(&ir_replay.file)?,
+                ir_replay.name.as_deref(),
+            )
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            transport.send(&messages::send_ir_raw(
+                &capture.durations,
+                ir_replay.carrier.carrier(capture.carrier).as_ref(),
+            ))?;
+        }
+        Some(Subcommands::Beam(beam)) => {
+            let message = match beam {
+                Beam::Hello => BadgeMessage::Hello,
+                Beam::Scene => BadgeMessage::ShareScene,
+                Beam::Text { text } => BadgeMessage::Text(text.clone()),
+                Beam::Time => BadgeMessage::SyncTime,
+            };
+            transport.send(&messages::send_badge_message(&message))?;
+        }
+        Some(Subcommands::Contacts) => {
+            let reply = transport.request(&messages::get_contacts(), Duration::from_secs(1))?;
+            let contacts = messages::contacts(&reply).map_err(io::Error::other)?;
+            if contacts.is_empty() {
+                println!("The badge has met no other badge yet");
+            }
+
+            for contact in contacts {
+                let seen = contact.first_seen;
+                println!(
+                    "{:08x}  first met {}:{:02}:{:02} after starting  met {} times",
+                    contact.badge,
+                    seen / 3600,
+                    seen / 60 % 60,
+                    seen % 60,
+                    contact.count
+                );
+            }
+        }
+        Some(Subcommands::Relay(relay)) => {
+            transport.send(&messages::set_relay(!relay.off))?;
+        }
+        Some(Subcommands::LogLevel(log_level)) => {
+            transport.send(&messages::log_config(
+                log_level.level,
+                &log_level.module,
+                log_level.json,
+            ))?;
+        }
+        Some(Subcommands::Reboot(reboot)) => {
+            transport.send(&messages::reboot(reboot.bootloader))?;
+        }
+        Some(Subcommands::Info) => show_info(transport)?,
+        Some(Subcommands::List)
+        | Some(Subcommands::Logs(_))
+        | Some(Subcommands::Flash(_))
+        | None => {}
+    }
+
+    if let Some(fb) = &args.frame_buffer {
+        let colors = fb
+            .split(" ")
+            .map(hex_color_to_rgb)
+            .collect::<io::Result<Vec<RGB8>>>()?;
+
+        if colors.len() != 9 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Frame buffer must be 9 elements long",
+            ));
+        }
+
+        transport.send(&messages::frame_buffer(&colors))?;
+
+        return Ok(());
+    }
+
+    if let Some(color) = &args.solid_color {
+        transport.send(&messages::solid_color(hex_color_to_rgb(color)?))?;
+    }
+
+    Ok(())
+}
+
+fn main() -> ExitCode {
     let args = Cli::parse();
 
     // we don't need serial for the midi demo
     // let it f*ck off before everything else
-    // ideally, this whole tool would support both backends,
-    // for now, this is only here as a reference
     if let Some(file) = args.midi_demo {
         midi_demo(file);
-        return;
+        return ExitCode::SUCCESS;
     }
 
-    let serial_port = args.serial_port.unwrap_or("/dev/ttyACM0".to_string());
+    if args.dry_run {
+        let mut loopback = LoopbackTransport::default();
+        let result = run(&args, &mut loopback);
 
-    let mut port = serialport::new(serial_port, 115_200)
-        .timeout(Duration::from_millis(10))
-        .open()
-        .expect("Failed to open port");
+        // what was built before a failure, like the request of ir-capture
+        for message in loopback.sent {
+            let hex: Vec<String> = message.iter().map(|b| format!("{:02x}", b)).collect();
+            println!("{}", hex.join(" "));
+        }
 
-    #[allow(clippy::single_match)]
-    match args.subcommand {
-        Some(Subcommands::SendNec(send_nec)) => {
-            let mut message = Builder::new_default();
+        return exit_code(result);
+    }
 
-            let badgebound = message.init_root::<usb_messages_capnp::badge_bound::Builder>();
+    if let Some(Subcommands::List) = args.subcommand {
+        return exit_code(list());
+    }
 
-            let mut nec = badgebound.init_send_nec_command();
-            nec.set_address(send_nec.address);
-            nec.set_command(send_nec.command);
-            nec.set_repeat(send_nec.repeat);
+    if let Some(Subcommands::Flash(flash)) = &args.subcommand {
+        return exit_code(flash_firmware(&args, flash));
+    }
 
-            let data = serialize::write_message_to_words(&message);
+    if let Some(Subcommands::Logs(logs)) = &args.subcommand {
+        return exit_code(show_logs(logs, &args.badge));
+    }
 
-            port.write_all(&data).expect("Failed to write to port");
+    let targets = match open_targets(&args) {
+        Ok(targets) => targets,
+        Err(e) => return exit_code(Err(e)),
+    };
+
+    // a single badge, no need for a report
+    if targets.len() == 1 {
+        let (_, transport) = targets.into_iter().next().unwrap();
+        return exit_code(transport.and_then(|mut transport| run(&args, transport.as_mut())));
+    }
+
+    if let Some(Subcommands::IrCapture(_)) = args.subcommand {
+        return exit_code(Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Capture from one badge at a time",
+        )));
+    }
+
+    // every badge answers on its own port, the requests go to one badge after the other
+    if args
+        .subcommand
+        .as_ref()
+        .is_some_and(Subcommands::is_request)
+    {
+        let mut failed = false;
+        for (name, transport) in targets {
+            // the info starts with the serial already
+            if !matches!(args.subcommand, Some(Subcommands::Info)) {
+                println!("{}:", name);
+            }
+            if let Err(e) = transport.and_then(|mut t| run(&args, t.as_mut())) {
+                eprintln!("{}: {}", name, e);
+                failed = true;
+            }
         }
-        None => {}
+        return if failed {
+            ExitCode::FAILURE
+        } else {
+            ExitCode::SUCCESS
+        };
     }
 
-    if let Some(fb) = args.frame_buffer {
-        let split = fb
-            .split(" ")
-            .map(|s| s.to_string())
-            .collect::<Vec<String>>();
+    let mut fleet = FleetTransport::default();
+    for (name, transport) in targets {
+        fleet.add(name, transport);
+    }
+
+    let result = run(&args, &mut fleet);
+    let mut failed = result.is_err();
+    if let Err(e) = result {
+        eprintln!("{}", e);
+    }
+
+    for (name, result) in fleet.report() {
+        match result {
+            Ok(()) => println!("{}: ok", name),
+            Err(e) => {
+                eprintln!("{}: {}", name, e);
+                failed = true;
+            }
+        }
+    }
+
+    if failed {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
 
-        if split.len() != 9 {
-            println!("Frame buffer must be 9 elements long");
-            return;
+// scripts and the fleet mode tell a failure by the exit status
+fn exit_code(result: io::Result<()>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("{}", e);
+            ExitCode::FAILURE
         }
+    }
+}
 
-        let mut message = Builder::new_default();
+fn list() -> io::Result<()> {
+    let badges = discovery::discover()?;
+    if badges.is_empty() {
+        println!("No badge found");
+    }
 
-        let badgebound = message.init_root::<usb_messages_capnp::badge_bound::Builder>();
+    let show = |path: &Option<String>| path.clone().unwrap_or("-".to_string());
+    for badge in badges {
+        println!(
+            "{}  control: {}  log: {}  midi: {}",
+            badge.serial,
+            show(&badge.control_port),
+            show(&badge.log_port),
+            show(&badge.midi_device)
+        );
+    }
 
-        let mut set_fb = badgebound.init_set_frame_buffer();
-        set_fb.reborrow().init_pixels(9);
+    Ok(())
+}
+
+fn show_info(transport: &mut dyn Transport) -> io::Result<()> {
+    let reply = transport.request(&messages::get_info(), Duration::from_secs(1))?;
+    let info = messages::badge_info(&reply).map_err(io::Error::other)?;
+
+    println!(
+        "{}  version: {}-{}{}  profile: {}  features: {}  protocol: {}  ir echoes: {}  ir dropped: {}",
+        info.serial,
+        info.version,
+        info.git_hash,
+        if info.dirty { " (dirty)" } else { "" },
+        info.profile,
+        if info.features.is_empty() {
+            "-"
+        } else {
+            &info.features
+        },
+        info.protocol_version,
+        info.ir_echoes,
+        info.ir_dropped
+    );
+
+    if info.protocol_version != minibadge_protocol::PROTOCOL_VERSION {
+        println!(
+            "Warning: the badge speaks protocol version {}, this tool {}, update the older one",
+            info.protocol_version,
+            minibadge_protocol::PROTOCOL_VERSION
+        );
+    }
+
+    Ok(())
+}
 
-        let mut pixels = set_fb.reborrow().get_pixels().unwrap();
+fn flash_firmware(args: &Cli, options: &Flash) -> io::Result<()> {
+    let image = fs::read(&options.uf2)?;
+    let blocks = flash::check_uf2(&image)?;
 
-        for i in 0..9 {
-            let mut pixel = pixels.reborrow().get(i);
-            let color = hex_color_to_rgb(split[i as usize].clone());
-            pixel.set_r(color.r);
-            pixel.set_g(color.g);
-            pixel.set_b(color.b);
+    if !options.no_reboot {
+        let mut targets = open_targets(args)?;
+        if targets.len() != 1 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Flash one badge at a time",
+            ));
         }
 
-        let data = serialize::write_message_to_words(&message);
+        let (name, transport) = targets.remove(0);
+        transport?.send(&messages::reboot(true))?;
+        println!("{}: restarting in the bootloader", name);
+    }
+
+    let drive = match &options.mount {
+        Some(mount) => mount.clone(),
+        None => {
+            println!("Waiting for the bootloader drive");
+            flash::wait_for_bootloader_drive(Duration::from_secs(options.timeout))?
+        }
+    };
+
+    let path = flash::write_uf2(&image, &drive)?;
+    println!("Wrote {} blocks to {}", blocks, path.display());
+
+    Ok(())
+}
+
+fn show_logs(logs: &Logs, serials: &[String]) -> io::Result<()> {
+    let port = match &logs.port {
+        Some(port) => port.clone(),
+        None => {
+            // one badge at a time, the first one if more are given
+            let badges = discovery::discover()?;
+            let (_, badge) = discovery::select(badges, &serials[..serials.len().min(1)])?
+                .into_iter()
+                .next()
+                .unwrap();
+
+            badge?
+                .log_port
+                .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No debug port found"))?
+        }
+    };
+
+    let options = logs::LogOptions {
+        level: logs.level,
+        filter: logs.grep.clone(),
+        ir_only: logs.ir,
+        color: !logs.no_color,
+    };
+
+    logs::follow(&port, &options)
+}
+
+// every command can go through the serial port or through midi sysex,
+// the badges are looked up only when no path is given
+fn open_targets(args: &Cli) -> io::Result<Vec<Target>> {
+    if let Some(Some(midi_device)) = &args.sysex {
+        let transport = MidiTransport::open(midi_device).map(|t| Box::new(t) as Box<dyn Transport>);
+        return Ok(vec![(midi_device.clone(), transport)]);
+    }
+
+    if !args.serial_port.is_empty() {
+        return Ok(args
+            .serial_port
+            .iter()
+            .map(|port| {
+                let transport =
+                    SerialTransport::open(port).map(|t| Box::new(t) as Box<dyn Transport>);
+                (port.clone(), transport)
+            })
+            .collect());
+    }
+
+    let badges = discovery::discover()?;
+    let badges = if args.all {
+        if badges.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "No badge found"));
+        }
+        badges
+            .into_iter()
+            .map(|b| (b.serial.clone(), Ok(b)))
+            .collect()
+    } else {
+        discovery::select(badges, &args.badge)?
+    };
+
+    let use_midi = args.sysex.is_some();
+    let open = |badge: discovery::Badge| -> io::Result<Box<dyn Transport>> {
+        let not_found =
+            |what: &str| io::Error::new(io::ErrorKind::NotFound, format!("No {} found", what));
+
+        if use_midi {
+            let midi_device = badge.midi_device.ok_or_else(|| not_found("midi device"))?;
+            Ok(Box::new(MidiTransport::open(&midi_device)?))
+        } else {
+            let port = badge
+                .control_port
+                .ok_or_else(|| not_found("management port"))?;
+            Ok(Box::new(SerialTransport::open(&port)?))
+        }
+    };
+
+    Ok(badges
+        .into_iter()
+        .map(|(serial, badge)| (serial, badge.and_then(open)))
+        .collect())
+}
+
+/// The messages `run` sends for a command line, through the loopback transport
+#[cfg(test)]
+mod tests {
+    use std::{env, process, slice};
+
+    use capnp::{message::Builder, serialize};
+    use minibadge_protocol::{deserialize_unaligned, Command, Contact, IrAction};
+
+    use super::*;
 
-        port.write_all(&data).expect("Failed to write to port");
+    fn args(args: &[&str]) -> Cli {
+        Cli::try_parse_from(["minibadge-cli"].iter().chain(args)).unwrap()
+    }
+
+    fn sent(command_line: &[&str]) -> Vec<Vec<u8>> {
+        let mut loopback = LoopbackTransport::default();
+        run(&args(command_line), &mut loopback).unwrap();
+
+        loopback.sent
+    }
+
+    /// What the badge reads in the messages sent
+    fn commands(sent: &[Vec<u8>]) -> Vec<Command> {
+        sent.iter()
+            .map(|message| deserialize_unaligned(message).unwrap().command)
+            .collect()
+    }
+
+    fn temp_file(name: &str, contents: &[u8]) -> PathBuf {
+        let path = env::temp_dir().join(format!("minibadge-cli-{}-{}", process::id(), name));
+        fs::write(&path, contents).unwrap();
+
+        path
+    }
+
+    fn event(event: &str) -> MidiOutEvent {
+        event.parse().unwrap()
+    }
+
+    /// Only the wiring from the command line to the message builders, the builders are checked
+    /// against the decoder of the badge in `messages`. The tests of new subcommands look at the
+    /// decoded commands instead, like `requests`
+    #[test]
+    fn one_message_each() {
+        let rc5 = IrCommand {
+            protocol: IrProtocol::Rc5,
+            address: 20,
+            command: 80,
+            repeat: true,
+        };
+        let carrier = IrCarrier {
+            frequency: 36_000,
+            duty_cycle: 50,
+        };
+        let red = RGB8 { r: 255, g: 0, b: 0 };
+        let text = ir::parse_badge_text("hi camp").unwrap();
+        let module = "antani_sw::usb=debug".parse().unwrap();
+
+        let cases: &[(&[&str], Vec<u8>)] = &[
+            (
+                &["send-nec", "-a", "1", "-c", "2", "-r"],
+                messages::send_nec(1, 2, true),
+            ),
+            (
+                &["send-ir", "-p", "rc5", "-a", "20", "-c", "80", "-r"],
+                messages::send_ir(&rc5, None),
+            ),
+            (
+                &[
+                    "send-ir",
+                    "-p",
+                    "rc5",
+                    "-a",
+                    "20",
+                    "-c",
+                    "80",
+                    "-r",
+                    "--carrier",
+                    "36000",
+                ],
+                messages::send_ir(&rc5, Some(&carrier)),
+            ),
+            (&["scene", "3"], messages::scene(3)),
+            (
+                &["midi-map", "-l", "cc:2:20:127"],
+                messages::midi_mapping(
+                    &event("note:1:60:100"),
+                    &event("cc:2:20:127"),
+                    &event("note:2:0:100"),
+                ),
+            ),
+            (&["ir-bindings", "--reset"], messages::reset_ir_bindings()),
+            (
+                &["ir-learn", "scene", "3"],
+                messages::learn_ir_binding(&IrAction::SelectScene(3)),
+            ),
+            (
+                &["button", "media play-pause"],
+                messages::set_button_action(&IrAction::MediaKey(0xcd)),
+            ),
+            (
+                &["beam", "hello"],
+                messages::send_badge_message(&BadgeMessage::Hello),
+            ),
+            (
+                &["beam", "scene"],
+                messages::send_badge_message(&BadgeMessage::ShareScene),
+            ),
+            (
+                &["beam", "text", "hi camp"],
+                messages::send_badge_message(&BadgeMessage::Text(text)),
+            ),
+            (
+                &["beam", "time"],
+                messages::send_badge_message(&BadgeMessage::SyncTime),
+            ),
+            (&["relay"], messages::set_relay(true)),
+            (&["relay", "--off"], messages::set_relay(false)),
+            (
+                &["log-level", "warn", "-m", "antani_sw::usb=debug", "--json"],
+                messages::log_config(Level::Warn, &[module], true),
+            ),
+            (&["reboot"], messages::reboot(false)),
+            (&["reboot", "-b"], messages::reboot(true)),
+            (&["-c", "#ff0000"], messages::solid_color(red)),
+            (
+                &["-f", &["#ff0000"; 9].join(" ")],
+                messages::frame_buffer(&[red; 9]),
+            ),
+        ];
+
+        for (command_line, message) in cases {
+            assert_eq!(
+                sent(command_line),
+                slice::from_ref(message),
+                "{:?}",
+                command_line
+            );
+        }
+
+        let frame = ["#ff0000"; 8].join(" ");
+        let bad_colors: &[&[&str]] = &[
+            &["-c", "#ff"],
+            &["-c", "#ff00001"],
+            &["-c", "#gg0000"],
+            &["-c", "#+f0000"],
+            &["-c", "#ffé000"],
+            &["-f", &format!("{} #ff", frame)],
+            &["-f", &frame],
+        ];
+        for command_line in bad_colors {
+            let mut loopback = LoopbackTransport::default();
+            let error = run(&args(command_line), &mut loopback).unwrap_err();
+            assert_eq!(
+                error.kind(),
+                io::ErrorKind::InvalidInput,
+                "{:?}",
+                command_line
+            );
+            assert!(loopback.sent.is_empty());
+        }
+    }
+
+    #[test]
+    fn files() {
+        let bindings = "nec 0 68 next-scene\nsamsung 7 7 repeat media volume-up\n";
+        let path = temp_file("bindings", bindings.as_bytes());
+        let messages = sent(&["ir-bindings", path.to_str().unwrap()]);
+        let [Command::SetIrBindings(decoded)] = &commands(&messages)[..] else {
+            panic!("not ir bindings");
+        };
+        assert_eq!(decoded.as_slice(), ir::parse_bindings(bindings).unwrap());
+
+        fs::remove_file(&path).unwrap();
+
+        let path = temp_file("bad-bindings", b"nec 0 68 dance\n");
+        let mut loopback = LoopbackTransport::default();
+        assert!(run(
+            &args(&["ir-bindings", path.to_str().unwrap()]),
+            &mut loopback
+        )
+        .is_err());
+        assert!(loopback.sent.is_empty());
+
+        fs::remove_file(&path).unwrap();
+
+        let path = temp_file("frame", b"9000 4500 560 560 560");
+        let messages = sent(&["ir-replay", path.to_str().unwrap(), "--carrier", "40000"]);
+        assert_eq!(
+            commands(&messages),
+            [Command::SendIrRaw(
+                [9000, 4500, 560, 560, 560].into_iter().collect(),
+                Some(IrCarrier {
+                    frequency: 40_000,
+                    duty_cycle: 50
+                })
+            )]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn audio_from_wav() {
+        let path = env::temp_dir().join(format!("minibadge-cli-{}-audio.wav", process::id()));
+        let spec = hound::WavSpec {
+            channels: 1,
+            sample_rate: 8000,
+            bits_per_sample: 16,
+            sample_format: hound::SampleFormat::Int,
+        };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        // a tenth of a second of 440 Hz
+        for i in 0..800 {
+            let t = i as f32 / 8000.0;
+            let sample = (2.0 * std::f32::consts::PI * 440.0 * t).sin() * 16_000.0;
+            writer.write_sample(sample as i16).unwrap();
+        }
+        writer.finalize().unwrap();
 
-        return;
+        // an update every 80 samples, after the first one
+        let sent = sent(&["audio", "-w", path.to_str().unwrap(), "-f", "100"]);
+        let levels = messages::audio_levels(&AudioLevels {
+            bands: [0; audio::BANDS],
+            beat: false,
+        });
+        fs::remove_file(&path).unwrap();
+        assert_eq!(sent.len(), 9);
+        assert!(sent.iter().all(|message| message.len() == levels.len()));
     }
 
-    if let Some(color) = args.solid_color {
-        let mut message = Builder::new_default();
+    #[test]
+    fn requests() {
+        let mut reply = Builder::new_default();
+        let hostbound = reply.init_root::<usb_messages_capnp::host_bound::Builder>();
+        minibadge_protocol::write_ir_raw(hostbound.init_ir_raw(), &[9000, 4500, 560]);
+        let frame = serialize::write_message_to_words(&reply);
 
-        let badgebound = message.init_root::<usb_messages_capnp::badge_bound::Builder>();
+        let path = env::temp_dir().join(format!("minibadge-cli-{}-capture", process::id()));
+        let mut loopback = LoopbackTransport {
+            replies: [frame].into(),
+            ..Default::default()
+        };
+        let command_line = ["ir-capture", path.to_str().unwrap()];
+        run(&args(&command_line), &mut loopback).unwrap();
+        assert_eq!(commands(&loopback.sent), [Command::CaptureIrRaw]);
+        let capture = irraw::parse(&fs::read_to_string(&path).unwrap(), None).unwrap();
+        assert_eq!(capture.durations, [9000, 4500, 560]);
+        fs::remove_file(&path).unwrap();
 
-        let mut set_color = badgebound.init_set_solid_color();
-        let color = hex_color_to_rgb(color);
+        // no reply at all, and a reply with no frame
+        let mut loopback = LoopbackTransport::default();
+        assert!(run(&args(&command_line), &mut loopback).is_err());
 
-        set_color.set_r(color.r);
-        set_color.set_g(color.g);
-        set_color.set_b(color.b);
+        let mut reply = Builder::new_default();
+        let hostbound = reply.init_root::<usb_messages_capnp::host_bound::Builder>();
+        minibadge_protocol::write_ir_raw(hostbound.init_ir_raw(), &[]);
+        let mut loopback = LoopbackTransport {
+            replies: [serialize::write_message_to_words(&reply)].into(),
+            ..Default::default()
+        };
+        let error = run(&args(&command_line), &mut loopback).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::TimedOut);
 
-        let data = serialize::write_message_to_words(&message);
+        let mut reply = Builder::new_default();
+        let hostbound = reply.init_root::<usb_messages_capnp::host_bound::Builder>();
+        let contact = Contact {
+            badge: 0xdead_beef,
+            first_seen: 42,
+            count: 3,
+        };
+        minibadge_protocol::write_contacts(hostbound.init_contacts(), &[contact]);
+        let mut loopback = LoopbackTransport {
+            replies: [serialize::write_message_to_words(&reply)].into(),
+            ..Default::default()
+        };
+        run(&args(&["contacts"]), &mut loopback).unwrap();
+        assert_eq!(commands(&loopback.sent), [Command::GetContacts]);
 
-        port.write_all(&data).expect("Failed to write to port");
+        let mut reply = Builder::new_default();
+        let hostbound = reply.init_root::<usb_messages_capnp::host_bound::Builder>();
+        let mut info = hostbound.init_info();
+        info.set_serial("E6614103E7452D2F");
+        info.set_protocol_version(minibadge_protocol::PROTOCOL_VERSION);
+        let mut loopback = LoopbackTransport {
+            replies: [serialize::write_message_to_words(&reply)].into(),
+            ..Default::default()
+        };
+        run(&args(&["info"]), &mut loopback).unwrap();
+        assert_eq!(commands(&loopback.sent), [Command::GetInfo]);
     }
 }
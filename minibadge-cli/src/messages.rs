@@ -0,0 +1,654 @@
+use capnp::message::{Builder, HeapAllocator, ReaderOptions};
+use capnp::serialize;
+use minibadge_protocol::{BadgeMessage, Contact, IrAction, IrBinding, IrCarrier, IrCommand};
+use smart_leds::RGB8;
+
+use crate::audio::AudioLevels;
+use crate::logs::{Level, ModuleLevel};
+use crate::midi::{MidiOutEvent, MidiOutKind};
+use crate::usb_messages_capnp;
+
+/// Every message carries the protocol version of the CLI
+fn badge_bound(
+    message: &mut Builder<HeapAllocator>,
+) -> usb_messages_capnp::badge_bound::Builder<'_> {
+    let mut badgebound = message.init_root::<usb_messages_capnp::badge_bound::Builder>();
+    badgebound.set_protocol_version(minibadge_protocol::PROTOCOL_VERSION);
+    badgebound
+}
+
+pub fn solid_color(color: RGB8) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+
+    let mut set_color = badgebound.init_set_solid_color();
+    set_color.set_r(color.r);
+    set_color.set_g(color.g);
+    set_color.set_b(color.b);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn frame_buffer(colors: &[RGB8]) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+
+    let set_fb = badgebound.init_set_frame_buffer();
+    let mut pixels = set_fb.init_pixels(colors.len() as u32);
+
+    for (i, color) in colors.iter().enumerate() {
+        let mut pixel = pixels.reborrow().get(i as u32);
+        pixel.set_r(color.r);
+        pixel.set_g(color.g);
+        pixel.set_b(color.b);
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn send_nec(address: u8, command: u8, repeat: bool) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+
+    let mut nec = badgebound.init_send_nec_command();
+    nec.set_address(address);
+    nec.set_command(command);
+    nec.set_repeat(repeat);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn send_ir(command: &IrCommand, carrier: Option<&IrCarrier>) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+    let mut send = badgebound.init_send_ir_command();
+    minibadge_protocol::write_ir_command(send.reborrow(), command);
+    if let Some(carrier) = carrier {
+        minibadge_protocol::write_ir_carrier(send.init_carrier(), carrier);
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn audio_levels(levels: &AudioLevels) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+
+    let mut audio_levels = badgebound.init_set_audio_levels();
+    audio_levels.set_beat(levels.beat);
+
+    let mut bands = audio_levels.init_bands(levels.bands.len() as u32);
+    for (i, band) in levels.bands.iter().enumerate() {
+        bands.set(i as u32, *band);
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn scene(scene: u8) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    badgebound.set_set_scene(scene);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn reboot(bootloader: bool) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    if bootloader {
+        badgebound.set_reboot_to_bootloader(());
+    } else {
+        badgebound.set_reboot(());
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn midi_mapping(
+    short_press: &MidiOutEvent,
+    long_press: &MidiOutEvent,
+    ir_received: &MidiOutEvent,
+) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+
+    let mut mapping = badgebound.init_set_midi_mapping();
+    set_midi_event(mapping.reborrow().init_short_press(), short_press);
+    set_midi_event(mapping.reborrow().init_long_press(), long_press);
+    set_midi_event(mapping.reborrow().init_ir_received(), ir_received);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn log_config(level: Level, modules: &[ModuleLevel], json: bool) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+
+    let mut config = badgebound.init_set_log_config();
+    config.set_level(log_level(level));
+    config.set_format(if json {
+        usb_messages_capnp::LogFormat::Json
+    } else {
+        usb_messages_capnp::LogFormat::Text
+    });
+
+    let mut list = config.init_modules(modules.len() as u32);
+    for (i, module) in modules.iter().enumerate() {
+        let mut entry = list.reborrow().get(i as u32);
+        entry.set_module(module.module.as_str());
+        entry.set_level(log_level(module.level));
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn get_info() -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    badgebound.set_get_info(());
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn ir_bindings(bindings: &[IrBinding]) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+    minibadge_protocol::write_ir_bindings(badgebound.init_set_ir_bindings(), bindings);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn reset_ir_bindings() -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    badgebound.set_reset_ir_bindings(());
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn learn_ir_binding(action: &IrAction) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+    minibadge_protocol::write_ir_action(badgebound.init_learn_ir_binding(), action);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn capture_ir_raw() -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    badgebound.set_capture_ir_raw(());
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn send_ir_raw(durations: &[u16], carrier: Option<&IrCarrier>) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+    let mut send = badgebound.init_send_ir_raw();
+    minibadge_protocol::write_ir_raw(send.reborrow(), durations);
+    if let Some(carrier) = carrier {
+        minibadge_protocol::write_ir_carrier(send.init_carrier(), carrier);
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn send_badge_message(badge_message: &BadgeMessage) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+    minibadge_protocol::write_badge_message(badgebound.init_send_badge_message(), badge_message);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn get_contacts() -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    badgebound.set_get_contacts(());
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn set_relay(relay: bool) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let mut badgebound = badge_bound(&mut message);
+    badgebound.set_set_relay(relay);
+
+    serialize::write_message_to_words(&message)
+}
+
+pub fn set_button_action(action: &IrAction) -> Vec<u8> {
+    let mut message = Builder::new_default();
+
+    let badgebound = badge_bound(&mut message);
+    minibadge_protocol::write_ir_action(badgebound.init_set_button_action(), action);
+
+    serialize::write_message_to_words(&message)
+}
+
+/// Build and schema of the firmware running on a badge
+#[derive(Debug)]
+pub struct BadgeInfo {
+    pub version: String,
+    pub git_hash: String,
+    pub dirty: bool,
+    pub profile: String,
+    pub features: String,
+    pub protocol_version: u16,
+    pub serial: String,
+    pub ir_echoes: u32,
+    pub ir_dropped: u32,
+}
+
+/// Reads the reply to `get_info`
+pub fn badge_info(mut reply: &[u8]) -> capnp::Result<BadgeInfo> {
+    let reader = serialize::read_message_from_flat_slice(&mut reply, ReaderOptions::new())?;
+    let hostbound = reader.get_root::<usb_messages_capnp::host_bound::Reader>()?;
+
+    let usb_messages_capnp::host_bound::Info(info) = hostbound.which()? else {
+        return Err(capnp::Error::failed("Not an info message".to_string()));
+    };
+    let info = info?;
+
+    Ok(BadgeInfo {
+        version: info.get_version()?.to_string()?,
+        git_hash: info.get_git_hash()?.to_string()?,
+        dirty: info.get_dirty(),
+        profile: info.get_profile()?.to_string()?,
+        features: info.get_features()?.to_string()?,
+        protocol_version: info.get_protocol_version(),
+        serial: info.get_serial()?.to_string()?,
+        ir_echoes: info.get_ir_echoes(),
+        ir_dropped: info.get_ir_dropped(),
+    })
+}
+
+/// Reads the reply to `capture_ir_raw`, empty when no remote was used
+pub fn ir_raw(mut reply: &[u8]) -> capnp::Result<Vec<u16>> {
+    let reader = serialize::read_message_from_flat_slice(&mut reply, ReaderOptions::new())?;
+    let hostbound = reader.get_root::<usb_messages_capnp::host_bound::Reader>()?;
+
+    let usb_messages_capnp::host_bound::IrRaw(raw) = hostbound.which()? else {
+        return Err(capnp::Error::failed("Not a raw IR message".to_string()));
+    };
+
+    Ok(raw?.get_durations()?.iter().collect())
+}
+
+/// Reads the reply to `get_contacts`
+pub fn contacts(mut reply: &[u8]) -> capnp::Result<Vec<Contact>> {
+    let reader = serialize::read_message_from_flat_slice(&mut reply, ReaderOptions::new())?;
+    let hostbound = reader.get_root::<usb_messages_capnp::host_bound::Reader>()?;
+
+    let usb_messages_capnp::host_bound::Contacts(contacts) = hostbound.which()? else {
+        return Err(capnp::Error::failed("Not a contacts message".to_string()));
+    };
+
+    Ok(contacts?
+        .get_contacts()?
+        .iter()
+        .map(|contact| Contact {
+            badge: contact.get_badge(),
+            first_seen: contact.get_first_seen(),
+            count: contact.get_count(),
+        })
+        .collect())
+}
+
+fn log_level(level: Level) -> usb_messages_capnp::LogLevel {
+    use usb_messages_capnp::LogLevel;
+
+    match level {
+        Level::Off => LogLevel::Off,
+        Level::Error => LogLevel::Error,
+        Level::Warn => LogLevel::Warn,
+        Level::Info => LogLevel::Info,
+        Level::Debug => LogLevel::Debug,
+        Level::Trace => LogLevel::Trace,
+    }
+}
+
+fn set_midi_event(mut builder: usb_messages_capnp::midi_event::Builder, event: &MidiOutEvent) {
+    use usb_messages_capnp::midi_event::Kind;
+
+    builder.set_kind(match event.kind {
+        MidiOutKind::None => Kind::None,
+        MidiOutKind::Note => Kind::Note,
+        MidiOutKind::ControlChange => Kind::ControlChange,
+        MidiOutKind::ProgramChange => Kind::ProgramChange,
+    });
+    builder.set_channel(event.channel);
+    builder.set_number(event.number);
+    builder.set_value(event.value);
+}
+
+/// Round trips: encoded here, decoded by the same code as the firmware
+#[cfg(test)]
+mod tests {
+    use minibadge_protocol::{
+        deserialize_unaligned, Command, MidiEvent, Rgb, FRAME_BUFFER_SIZE, PROTOCOL_VERSION,
+    };
+    use usb_messages_capnp::{midi_event::Kind, IrProtocol, LogFormat, LogLevel};
+
+    use super::*;
+
+    /// The reader rejects unaligned messages, and a Vec<u8> makes no promise
+    fn decode(message: &[u8]) -> Command {
+        let decoded = deserialize_unaligned(message).expect("decoding");
+        assert_eq!(decoded.protocol_version, PROTOCOL_VERSION);
+
+        decoded.command
+    }
+
+    #[test]
+    fn solid_color() {
+        let message = super::solid_color(RGB8 { r: 1, g: 2, b: 3 });
+
+        assert_eq!(
+            decode(&message),
+            Command::SetSolidColor(Rgb { r: 1, g: 2, b: 3 })
+        );
+    }
+
+    #[test]
+    fn frame_buffer() {
+        let colors: Vec<RGB8> = (0..FRAME_BUFFER_SIZE as u8)
+            .map(|i| RGB8 {
+                r: i,
+                g: 10 + i,
+                b: 20 + i,
+            })
+            .collect();
+
+        let Command::SetFrameBuffer(pixels) = decode(&super::frame_buffer(&colors)) else {
+            panic!("not a frame buffer");
+        };
+        for (pixel, color) in pixels.iter().zip(&colors) {
+            assert_eq!((pixel.r, pixel.g, pixel.b), (color.r, color.g, color.b));
+        }
+    }
+
+    #[test]
+    fn send_nec() {
+        assert_eq!(
+            decode(&super::send_nec(0x12, 0x34, true)),
+            Command::SendNec {
+                address: 0x12,
+                command: 0x34,
+                repeat: true
+            }
+        );
+    }
+
+    #[test]
+    fn send_ir() {
+        let command = IrCommand {
+            protocol: IrProtocol::Rc5,
+            address: 0x14,
+            command: 0x50,
+            repeat: false,
+        };
+
+        assert_eq!(
+            decode(&super::send_ir(&command, None)),
+            Command::SendIr(command, None)
+        );
+
+        let carrier = IrCarrier {
+            frequency: 56_000,
+            duty_cycle: 33,
+        };
+        assert_eq!(
+            decode(&super::send_ir(&command, Some(&carrier))),
+            Command::SendIr(command, Some(carrier))
+        );
+
+        // the badge can't make a carrier this slow
+        let slow = IrCarrier {
+            frequency: 1000,
+            duty_cycle: 50,
+        };
+        let message = super::send_ir(&command, Some(&slow));
+        assert!(deserialize_unaligned(&message).is_err());
+    }
+
+    #[test]
+    fn audio_levels() {
+        let levels = AudioLevels {
+            bands: [0, 10, 20, 30, 40, 50, 60, 70, 255],
+            beat: true,
+        };
+
+        assert_eq!(
+            decode(&super::audio_levels(&levels)),
+            Command::SetAudioLevels {
+                bands: levels.bands,
+                beat: true
+            }
+        );
+    }
+
+    #[test]
+    fn scene() {
+        assert_eq!(decode(&super::scene(7)), Command::SetScene(7));
+    }
+
+    #[test]
+    fn midi_mapping() {
+        let message = super::midi_mapping(
+            &"note:1:60:100".parse().unwrap(),
+            &"cc:2:20:127".parse().unwrap(),
+            &"none:1:0:0".parse().unwrap(),
+        );
+
+        let event = |kind, channel, number, value| MidiEvent {
+            kind,
+            channel,
+            number,
+            value,
+        };
+        // channels are counted from 1 on the command line, from 0 on the wire
+        assert_eq!(
+            decode(&message),
+            Command::SetMidiMapping {
+                short_press: event(Kind::Note, 0, 60, 100),
+                long_press: event(Kind::ControlChange, 1, 20, 127),
+                ir_received: event(Kind::None, 0, 0, 0),
+            }
+        );
+    }
+
+    #[test]
+    fn log_config() {
+        let modules = ["antani_sw::usb=debug".parse().unwrap()];
+
+        let Command::SetLogConfig(config) = decode(&super::log_config(Level::Warn, &modules, true))
+        else {
+            panic!("not a log config");
+        };
+        assert_eq!(config.level, LogLevel::Warn);
+        assert_eq!(config.format, LogFormat::Json);
+        assert_eq!(config.modules.len(), 1);
+        assert_eq!(config.modules[0].0.as_str(), "antani_sw::usb");
+        assert_eq!(config.modules[0].1, LogLevel::Debug);
+    }
+
+    #[test]
+    fn reboot_and_info() {
+        assert_eq!(decode(&super::reboot(true)), Command::RebootToBootloader);
+        assert_eq!(decode(&super::reboot(false)), Command::Reboot);
+        assert_eq!(decode(&super::get_info()), Command::GetInfo);
+    }
+
+    #[test]
+    fn ir_bindings() {
+        let bindings = crate::ir::parse_bindings(
+            "nec 0 68 next-scene\n\
+             samsung 7 7 repeat key volume-up\n\
+             sirc 0x1a 0x12 key enter\n\
+             nec 0 73 macro h e l l o\n\
+             rc5 0 16 key ctrl+shift+t\n\
+             samsung 7 11 repeat media 0xea\n\
+             nec 0 74 type Hi, camp!",
+        )
+        .unwrap();
+
+        let Command::SetIrBindings(decoded) = decode(&super::ir_bindings(&bindings)) else {
+            panic!("not ir bindings");
+        };
+        assert_eq!(decoded.as_slice(), bindings.as_slice());
+        assert_eq!(
+            decode(&super::reset_ir_bindings()),
+            Command::ResetIrBindings
+        );
+    }
+
+    #[test]
+    fn button_action() {
+        let parse = |action: &str| crate::ir::parse_action(action.split_whitespace()).unwrap();
+
+        assert_eq!(parse("key right"), IrAction::HidKey(0x4f));
+        assert_eq!(
+            parse("key ctrl+shift+t"),
+            IrAction::Chord(0x03, heapless::Vec::from_slice(&[0x17]).unwrap())
+        );
+        assert_eq!(parse("media play-pause"), IrAction::MediaKey(0xcd));
+
+        for action in [
+            parse("next-scene"),
+            parse("key gui+right-alt+f4+x"),
+            parse("media volume-up"),
+            parse("type git push --force"),
+        ] {
+            assert_eq!(
+                decode(&super::set_button_action(&action)),
+                Command::SetButtonAction(action.clone())
+            );
+            assert_eq!(
+                decode(&super::learn_ir_binding(&action)),
+                Command::LearnIrBinding(action)
+            );
+        }
+
+        let text = "x".repeat(33);
+        assert!(crate::ir::parse_action(["type", text.as_str()].into_iter()).is_err());
+        assert!(crate::ir::parse_action(["type", "caffè"].into_iter()).is_err());
+        assert!(crate::ir::parse_action(["key", "ctrl+a+b+c+d+e+f+g"].into_iter()).is_err());
+    }
+
+    #[test]
+    fn badge_message() {
+        let text = crate::ir::parse_badge_text("hello camp").unwrap();
+
+        for message in [
+            BadgeMessage::Hello,
+            BadgeMessage::ShareScene,
+            BadgeMessage::Text(text),
+            BadgeMessage::SyncTime,
+        ] {
+            assert_eq!(
+                decode(&super::send_badge_message(&message)),
+                Command::SendBadgeMessage(message)
+            );
+        }
+        assert!(crate::ir::parse_badge_text("more than sixteen letters").is_err());
+        assert!(crate::ir::parse_badge_text("caffè").is_err());
+
+        assert_eq!(decode(&super::set_relay(true)), Command::SetRelay(true));
+        assert_eq!(decode(&super::set_relay(false)), Command::SetRelay(false));
+    }
+
+    #[test]
+    fn contacts() {
+        assert_eq!(decode(&super::get_contacts()), Command::GetContacts);
+
+        let contacts = [
+            Contact {
+                badge: 0xdead_beef,
+                first_seen: 42,
+                count: 1,
+            },
+            Contact {
+                badge: 0x0102_0304,
+                first_seen: 3600,
+                count: 7,
+            },
+        ];
+        let mut reply = Builder::new_default();
+        let hostbound = reply.init_root::<usb_messages_capnp::host_bound::Builder>();
+        minibadge_protocol::write_contacts(hostbound.init_contacts(), &contacts);
+
+        let reply = serialize::write_message_to_words(&reply);
+        assert_eq!(super::contacts(&reply).unwrap(), contacts);
+        assert!(super::ir_raw(&reply).is_err());
+    }
+
+    #[test]
+    fn ir_raw() {
+        let durations = [9000, 4500, 560, 1690, 560, 560, 560];
+
+        assert_eq!(
+            decode(&super::send_ir_raw(&durations, None)),
+            Command::SendIrRaw(durations.iter().copied().collect(), None)
+        );
+        let carrier = IrCarrier {
+            frequency: 40_000,
+            duty_cycle: 25,
+        };
+        assert_eq!(
+            decode(&super::send_ir_raw(&durations, Some(&carrier))),
+            Command::SendIrRaw(durations.iter().copied().collect(), Some(carrier))
+        );
+        assert_eq!(decode(&super::capture_ir_raw()), Command::CaptureIrRaw);
+
+        // the text formats read back what they write, pronto within a carrier cycle
+        let lirc = crate::irraw::format(crate::irraw::Format::Lirc, "power", &durations);
+        let parsed = crate::irraw::parse(&lirc, Some("power")).unwrap();
+        assert_eq!(parsed.durations, durations);
+        assert_eq!(parsed.carrier, None);
+
+        let pronto = crate::irraw::format(crate::irraw::Format::Pronto, "power", &durations);
+        let parsed = crate::irraw::parse(&pronto, None).unwrap();
+        assert_eq!(parsed.durations.len(), durations.len());
+        for (parsed, duration) in parsed.durations.iter().zip(durations) {
+            assert!(
+                parsed.abs_diff(duration) < 27,
+                "{} for {}",
+                parsed,
+                duration
+            );
+        }
+        assert!(parsed.carrier.unwrap().abs_diff(38_000) < 100);
+    }
+
+    #[test]
+    fn incomplete_message() {
+        let message = super::scene(7);
+
+        let error = deserialize_unaligned(&message[..message.len() - 8]).unwrap_err();
+        assert!(matches!(
+            error.kind,
+            capnp::ErrorKind::MessageEndsPrematurely(_, _)
+        ));
+    }
+}
@@ -1,6 +1,9 @@
 use std::{
     fs::OpenOptions,
     io::{self, Read, Write},
+    sync::mpsc,
+    thread,
+    time::Duration,
 };
 
 #[allow(dead_code)]
@@ -37,40 +40,93 @@ impl MidiColors {
         Ok(Self { fd: file })
     }
 
-    pub fn led_ctrl_raw(&mut self, button: u8, color: u8) -> io::Result<()> {
-        let data = [0x90, button, color];
-        self.fd.write_all(&data)?;
-        self.fd.flush()?;
-        Ok(())
-    }
-
-    pub fn led_ctrl_rgb(&mut self, x: u8, y: u8, red: u8, green: u8, blue: u8) -> io::Result<()> {
-        // button 0 = pixel 0 red
-        // button 1 = pixel 0 green
-        // button 2 = pixel 0 blue
-        // button 3 = pixel 1 red
-        // etc etc
+    // control change numbers understood by the badge
+    pub const CC_BRIGHTNESS: u8 = 7;
+    pub const CC_HUE: u8 = 16;
+    pub const CC_SPEED: u8 = 17;
 
-        if red > 127 || green > 127 || blue > 127 {
+    fn write(&mut self, data: &[u8]) -> io::Result<()> {
+        if data[1..].iter().any(|b| *b > 127) {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidInput,
-                "Color values must be less than 128",
+                "MIDI values must be less than 128",
             ));
         }
 
-        let button = (y + 3 * x) * 3;
+        self.fd.write_all(data)?;
+        self.fd.flush()?;
+        Ok(())
+    }
 
-        println!(
-            "Setting button {} to rgb {},{},{}",
-            button, red, green, blue
-        );
-        self.led_ctrl_raw(button, red)?;
-        self.led_ctrl_raw(button + 1, green)?;
-        self.led_ctrl_raw(button + 2, blue)?;
+    pub fn note_on(&mut self, note: u8, velocity: u8) -> io::Result<()> {
+        self.write(&[0x90, note, velocity])
+    }
+
+    #[allow(dead_code)]
+    pub fn note_off(&mut self, note: u8) -> io::Result<()> {
+        self.write(&[0x80, note, 0])
+    }
+
+    pub fn control_change(&mut self, control: u8, value: u8) -> io::Result<()> {
+        self.write(&[0xb0, control, value])
+    }
+
+    #[allow(dead_code)]
+    pub fn program_change(&mut self, program: u8) -> io::Result<()> {
+        self.write(&[0xc0, program])
+    }
 
+    /// Sends data to the badge in a SysEx message, packed in 7 bit bytes
+    pub fn sysex(&mut self, data: &[u8]) -> io::Result<()> {
+        let mut sysex = vec![0xf0, SYSEX_MANUFACTURER_ID];
+        sysex.extend(encode_7bit(data));
+        sysex.push(0xf7);
+
+        self.fd.write_all(&sysex)?;
+        self.fd.flush()?;
         Ok(())
     }
 
+    /// Waits for a SysEx of the badge, returns its data unpacked from the 7 bit bytes
+    ///
+    /// The notes and the clock of the badge in between are skipped
+    pub fn read_sysex(&mut self, timeout: Duration) -> io::Result<Vec<u8>> {
+        // a MIDI device has no read timeout, the thread is left behind when the badge is silent
+        let mut fd = self.fd.try_clone()?;
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            let mut parser = SysexParser::default();
+            let mut buf = [0; 256];
+
+            while let Ok(n @ 1..) = fd.read(&mut buf) {
+                if let Some(data) = buf[..n].iter().find_map(|byte| parser.push(*byte)) {
+                    let _ = sender.send(data);
+                    return;
+                }
+            }
+        });
+
+        receiver.recv_timeout(timeout).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::TimedOut,
+                "No reply from the badge, is the firmware too old?",
+            )
+        })
+    }
+
+    pub fn led_ctrl(&mut self, x: u8, y: u8, brightness: u8) -> io::Result<()> {
+        // note 0 = top right pixel
+        // note 1 = top center pixel
+        // note 2 = top left pixel
+        // note 3 = middle right pixel
+        // etc etc
+
+        let note = y * 3 + (2 - x);
+
+        println!("Setting note {} to velocity {}", note, brightness);
+        self.note_on(note, brightness)
+    }
+
     #[allow(dead_code)]
     pub fn wait_event(&mut self) -> io::Result<MidiEvent> {
         let mut data = [0u8; 3];
@@ -78,3 +134,176 @@ impl MidiColors {
         Ok(MidiEvent::new(data[1], data[2] != 0))
     }
 }
+
+/// What the badge sends over MIDI when something happens
+///
+/// Written as `kind:channel:number:value`, for example `note:1:60:100`,
+/// `cc:1:20:127`, `program:1:5` or `none`. Channels go from 1 to 16.
+#[derive(Clone, Copy, Debug)]
+pub struct MidiOutEvent {
+    pub kind: MidiOutKind,
+    pub channel: u8,
+    pub number: u8,
+    pub value: u8,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum MidiOutKind {
+    None,
+    Note,
+    ControlChange,
+    ProgramChange,
+}
+
+impl std::str::FromStr for MidiOutEvent {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+
+        let kind = match parts.next() {
+            Some("none") => MidiOutKind::None,
+            Some("note") => MidiOutKind::Note,
+            Some("cc") => MidiOutKind::ControlChange,
+            Some("program") => MidiOutKind::ProgramChange,
+            _ => return Err(format!("unknown MIDI event kind in \"{}\"", s)),
+        };
+
+        let mut next_number = |name: &str, max: u8| -> Result<u8, String> {
+            match parts.next() {
+                None => Ok(0),
+                Some(part) => match part.parse::<u8>() {
+                    Ok(n) if n <= max => Ok(n),
+                    _ => Err(format!("invalid {} \"{}\" in \"{}\"", name, part, s)),
+                },
+            }
+        };
+
+        let channel = next_number("channel", 16)?.saturating_sub(1);
+        let number = next_number("number", 127)?;
+        let value = next_number("value", 127)?;
+
+        Ok(Self {
+            kind,
+            channel,
+            number,
+            value,
+        })
+    }
+}
+
+/// Manufacturer ID for non-commercial use, the badge only listens to this one
+pub const SYSEX_MANUFACTURER_ID: u8 = 0x7d;
+
+/// Packs 8 bit data in 7 bit MIDI bytes
+///
+/// Every group of 7 bytes is sent as 8 bytes, the first one holds the
+/// most significant bits of the other 7
+pub fn encode_7bit(data: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(data.len() + data.len().div_ceil(7));
+
+    for group in data.chunks(7) {
+        let msbs = group
+            .iter()
+            .enumerate()
+            .fold(0, |msbs, (i, byte)| msbs | ((byte >> 7) << i));
+
+        out.push(msbs);
+        out.extend(group.iter().map(|byte| byte & 0x7f));
+    }
+
+    out
+}
+
+/// Unpacks what `encode_7bit` packed
+pub fn decode_7bit(data: &[u8]) -> Vec<u8> {
+    data.chunks(8)
+        .filter_map(|group| group.split_first())
+        .flat_map(|(msbs, bytes)| {
+            bytes
+                .iter()
+                .enumerate()
+                .map(move |(i, byte)| byte | (((msbs >> i) & 1) << 7))
+        })
+        .collect()
+}
+
+/// Picks the SysEx of the badge out of a raw MIDI byte stream
+#[derive(Default)]
+pub struct SysexParser {
+    // the packed data of the SysEx being received, None outside of one
+    data: Option<Vec<u8>>,
+}
+
+impl SysexParser {
+    /// Feeds a byte, returns the unpacked data when a SysEx of the badge ends
+    pub fn push(&mut self, byte: u8) -> Option<Vec<u8>> {
+        match byte {
+            // real time messages like the clock can show up anywhere
+            0xf8..=0xff => None,
+            0xf0 => {
+                self.data = Some(vec![]);
+                None
+            }
+            0xf7 => match self.data.take()?.split_first() {
+                Some((&SYSEX_MANUFACTURER_ID, packed)) => Some(decode_7bit(packed)),
+                _ => None,
+            },
+            // any other status byte ends the SysEx without its end
+            0x80..=0xf6 => {
+                self.data = None;
+                None
+            }
+            _ => {
+                if let Some(data) = &mut self.data {
+                    data.push(byte);
+                }
+                None
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sysex_round_trip() {
+        let data: Vec<u8> = (0..=255).chain([0x80, 0x7f, 0xff]).collect();
+
+        let mut stream = vec![0x90, 60, 100, 0xf0, SYSEX_MANUFACTURER_ID];
+        let packed = encode_7bit(&data);
+        assert!(packed.iter().all(|byte| *byte < 0x80));
+        // a clock in the middle of the SysEx is not part of it
+        stream.extend(&packed[..5]);
+        stream.push(0xf8);
+        stream.extend(&packed[5..]);
+        stream.extend([0xf7, 0x80, 60, 0]);
+
+        let mut parser = SysexParser::default();
+        let received: Vec<_> = stream.iter().filter_map(|b| parser.push(*b)).collect();
+        assert_eq!(received, [data]);
+    }
+
+    #[test]
+    fn sysex_of_others_skipped() {
+        let mut parser = SysexParser::default();
+
+        // another manufacturer, and a SysEx cut by a note
+        for byte in [
+            0xf0,
+            0x41,
+            1,
+            2,
+            0xf7,
+            0xf0,
+            SYSEX_MANUFACTURER_ID,
+            1,
+            0x90,
+            0xf7,
+        ] {
+            assert_eq!(parser.push(byte), None);
+        }
+    }
+}
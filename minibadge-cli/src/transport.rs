@@ -0,0 +1,174 @@
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    thread,
+    time::{Duration, Instant},
+};
+
+use capnp::{message::ReaderOptions, serialize};
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::midi::MidiColors;
+
+/// A way to deliver capnp messages to the badge
+pub trait Transport: Send {
+    /// Sends one serialized `BadgeBound` message
+    fn send(&mut self, message: &[u8]) -> io::Result<()>;
+
+    /// Sends a message and waits for the serialized `HostBound` reply
+    fn request(&mut self, _message: &[u8], _timeout: Duration) -> io::Result<Vec<u8>> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "No reply can come back this way",
+        ))
+    }
+}
+
+/// The management CDC serial port of the badge
+pub struct SerialTransport {
+    port: Box<dyn SerialPort>,
+}
+
+impl SerialTransport {
+    pub fn open(path: &str) -> io::Result<Self> {
+        let port = serialport::new(path, 115_200)
+            .timeout(Duration::from_millis(10))
+            .open()?;
+
+        Ok(Self { port })
+    }
+}
+
+impl Transport for SerialTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.port.write_all(message)
+    }
+
+    fn request(&mut self, message: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        // whatever was left by an earlier request is not our reply
+        self.port.clear(ClearBuffer::Input)?;
+        self.port.write_all(message)?;
+
+        let start = Instant::now();
+        let mut reply = vec![];
+        let mut buf = [0; 64];
+
+        while start.elapsed() < timeout {
+            match self.port.read(&mut buf) {
+                Ok(n) => reply.extend_from_slice(&buf[..n]),
+                Err(e) if e.kind() == io::ErrorKind::TimedOut => continue,
+                Err(e) => return Err(e),
+            }
+
+            // the reply is complete as soon as it parses
+            if serialize::read_message_from_flat_slice(&mut reply.as_slice(), ReaderOptions::new())
+                .is_ok()
+            {
+                return Ok(reply);
+            }
+        }
+
+        Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "No reply from the badge, is the firmware too old?",
+        ))
+    }
+}
+
+/// The MIDI interface of the badge, every message and reply is tunneled in a SysEx
+pub struct MidiTransport {
+    midi: MidiColors,
+}
+
+impl MidiTransport {
+    pub fn open(path: &str) -> io::Result<Self> {
+        Ok(Self {
+            midi: MidiColors::new(path)?,
+        })
+    }
+}
+
+impl Transport for MidiTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.midi.sysex(message)
+    }
+
+    fn request(&mut self, message: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        self.midi.sysex(message)?;
+        self.midi.read_sysex(timeout)
+    }
+}
+
+/// Keeps the messages instead of sending them, no badge needed
+///
+/// The requests get the replies queued in `replies`, in order
+#[derive(Default)]
+pub struct LoopbackTransport {
+    pub sent: Vec<Vec<u8>>,
+    pub replies: VecDeque<Vec<u8>>,
+}
+
+impl Transport for LoopbackTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        self.sent.push(message.to_vec());
+        Ok(())
+    }
+
+    fn request(&mut self, message: &[u8], _timeout: Duration) -> io::Result<Vec<u8>> {
+        self.send(message)?;
+        self.replies.pop_front().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                "No reply can come back this way",
+            )
+        })
+    }
+}
+
+/// A badge to talk to, by name, or the reason it could not be opened
+pub type Target = (String, io::Result<Box<dyn Transport>>);
+
+/// Sends every message to many badges at once, each one from its own thread
+///
+/// A badge that fails is left out of the following messages, the error is kept for the report
+#[derive(Default)]
+pub struct FleetTransport {
+    badges: Vec<Target>,
+}
+
+impl FleetTransport {
+    /// Adds a badge, if it could not be opened it only shows up in the report
+    pub fn add(&mut self, name: String, transport: io::Result<Box<dyn Transport>>) {
+        self.badges.push((name, transport));
+    }
+
+    /// The name of every badge, with the first error it got
+    pub fn report(self) -> Vec<(String, io::Result<()>)> {
+        self.badges
+            .into_iter()
+            .map(|(name, transport)| (name, transport.map(|_| ())))
+            .collect()
+    }
+}
+
+impl Transport for FleetTransport {
+    fn send(&mut self, message: &[u8]) -> io::Result<()> {
+        thread::scope(|scope| {
+            for (_, badge) in self.badges.iter_mut().filter(|(_, t)| t.is_ok()) {
+                scope.spawn(move || {
+                    if let Ok(transport) = badge {
+                        if let Err(e) = transport.send(message) {
+                            *badge = Err(e);
+                        }
+                    }
+                });
+            }
+        });
+
+        if self.badges.iter().all(|(_, t)| t.is_err()) {
+            return Err(io::Error::other("No badge left to talk to"));
+        }
+
+        Ok(())
+    }
+}
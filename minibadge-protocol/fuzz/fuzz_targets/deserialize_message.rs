@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minibadge_protocol::deserialize_unaligned;
+
+fuzz_target!(|data: &[u8]| {
+    // the reader rejects unaligned messages, this copies them to words like the firmware buffers
+    let _ = deserialize_unaligned(data);
+});
@@ -0,0 +1,16 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use minibadge_protocol::MessageBuffer;
+
+// the control serial port of the badge: packets of up to 64 bytes in a 256 bytes buffer
+fuzz_target!(|data: &[u8]| {
+    let Some((size, stream)) = data.split_first() else {
+        return;
+    };
+
+    let mut buffer = MessageBuffer::<256>::new();
+    for packet in stream.chunks(1 + *size as usize % 64) {
+        for _ in buffer.push(packet) {}
+    }
+});
@@ -0,0 +1,137 @@
+use crate::{deserialize_message, Message, IR_BINDINGS_SIZE};
+
+/// The reader wants the message aligned to 8 bytes
+#[repr(C, align(8))]
+struct AlignedBytes<const N: usize>([u8; N]);
+
+/// Collects the packets of a stream, like the control serial port, until they hold a whole message
+pub struct MessageBuffer<const N: usize> {
+    data: AlignedBytes<N>,
+    len: usize,
+    /// What is still to come of a message too large for the buffer, dropped as it comes
+    skip: usize,
+}
+
+impl<const N: usize> MessageBuffer<N> {
+    pub const fn new() -> Self {
+        Self {
+            data: AlignedBytes([0; N]),
+            len: 0,
+            skip: 0,
+        }
+    }
+
+    /// Forgets the data of an incomplete message
+    pub fn clear(&mut self) {
+        self.len = 0;
+        self.skip = 0;
+    }
+
+    /// Whether the buffer holds nothing of a message
+    pub fn is_empty(&self) -> bool {
+        self.len == 0 && self.skip == 0
+    }
+
+    /// Appends a packet, the messages it completes come out of the iterator
+    ///
+    /// A packet can end a message and start the next one, the bytes after a message stay for the
+    /// next one. The packet is taken in as the iterator goes, so it must be run to the end.
+    ///
+    /// The stream stays in step by the length of each message in its segment table: a message
+    /// that fails to decode is skipped whole, and one that doesn't fit fails with
+    /// `MessageTooLarge` while the rest of it is dropped as it comes.
+    pub fn push<'a>(&'a mut self, packet: &'a [u8]) -> Messages<'a, N> {
+        Messages {
+            buffer: self,
+            packet,
+        }
+    }
+
+    fn next_message(&mut self, packet: &mut &[u8]) -> Option<Result<Message, capnp::Error>> {
+        let skipped = self.skip.min(packet.len());
+        self.skip -= skipped;
+        *packet = &packet[skipped..];
+
+        let taken = (N - self.len).min(packet.len());
+        self.data.0[self.len..self.len + taken].copy_from_slice(&packet[..taken]);
+        self.len += taken;
+        *packet = &packet[taken..];
+
+        // the packet is all taken in whenever this waits for more
+        let len = self.message_len()?;
+        if len > N {
+            self.skip = len - self.len;
+            self.len = 0;
+            return Some(Err(capnp::Error::from_kind(
+                capnp::ErrorKind::MessageTooLarge(N),
+            )));
+        }
+        if len > self.len {
+            return None;
+        }
+
+        let result = deserialize_message(&mut &self.data.0[..len]);
+        self.data.0.copy_within(len..self.len, 0);
+        self.len -= len;
+
+        Some(result)
+    }
+
+    /// The length of the message at the start of the buffer, from its segment table: the number
+    /// of segments less one and the words of each, padded to a whole word. None until the table
+    /// is all there, a table too large for the buffer gives its own length.
+    fn message_len(&self) -> Option<usize> {
+        let bytes = &self.data.0[..self.len];
+        let word = |i: u64| {
+            let i = usize::try_from(i).ok()? * 4;
+            Some(u32::from_le_bytes(bytes.get(i..i + 4)?.try_into().unwrap()) as u64)
+        };
+
+        let segments = word(0)? + 1;
+        let table = (segments + 1).div_ceil(2) * 8;
+        if table > N as u64 {
+            return Some(usize::try_from(table).unwrap_or(usize::MAX));
+        }
+
+        let words = (1..=segments).map(word).sum::<Option<u64>>()?;
+        Some(usize::try_from(table + words * 8).unwrap_or(usize::MAX))
+    }
+}
+
+/// The messages completed by a packet, see [`MessageBuffer::push`]
+pub struct Messages<'a, const N: usize> {
+    buffer: &'a mut MessageBuffer<N>,
+    packet: &'a [u8],
+}
+
+impl<const N: usize> Iterator for Messages<'_, N> {
+    type Item = Result<Message, capnp::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.buffer.next_message(&mut self.packet)
+    }
+}
+
+impl<const N: usize> Default for MessageBuffer<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decodes one message from bytes that may not be aligned, like a `Vec<u8>`, by copying them
+/// to an aligned buffer first
+///
+/// For the hosts and the tests, the firmware gets its messages aligned from `MessageBuffer`.
+/// Up to the largest message fits, a table of IR bindings, longer data fails with
+/// `MessageTooLarge`.
+pub fn deserialize_unaligned(data: &[u8]) -> Result<Message, capnp::Error> {
+    let mut aligned = AlignedBytes([0; IR_BINDINGS_SIZE]);
+    let Some(bytes) = aligned.0.get_mut(..data.len()) else {
+        return Err(capnp::Error::from_kind(capnp::ErrorKind::MessageTooLarge(
+            IR_BINDINGS_SIZE,
+        )));
+    };
+    bytes.copy_from_slice(data);
+
+    deserialize_message(&mut &bytes[..])
+}
@@ -0,0 +1,235 @@
+use capnp::{message::ReaderOptions, serialize};
+use heapless::{String, Vec};
+
+use crate::ir::{
+    read_badge_message, read_ir_action, read_ir_bindings, read_ir_carrier, read_ir_command,
+    read_ir_raw, BadgeMessage, IrAction, IrBindings, IrCarrier, IrCommand, IrRaw,
+};
+use crate::usb_messages_capnp::{self, badge_bound, midi_event, LogFormat, LogLevel};
+
+/// The badge has a 3x3 led matrix
+pub const FRAME_BUFFER_SIZE: usize = 9;
+/// One audio band per led
+pub const AUDIO_BANDS: usize = 9;
+
+/// Enough for "antani_sw::rgbeffects"
+pub const MODULE_NAME_LEN: usize = 24;
+pub const MAX_MODULE_LEVELS: usize = 4;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct MidiEvent {
+    pub kind: midi_event::Kind,
+    pub channel: u8,
+    pub number: u8,
+    pub value: u8,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct LogConfig {
+    pub level: LogLevel,
+    pub modules: Vec<(String<MODULE_NAME_LEN>, LogLevel), MAX_MODULE_LEVELS>,
+    pub format: LogFormat,
+}
+
+/// What a `BadgeBound` message asks the badge to do
+// no allocator to box the bindings, the command only lives on the stack for a moment
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    None,
+    /// Pixels in row order, shorter lists are an error and extra pixels are dropped
+    SetFrameBuffer([Rgb; FRAME_BUFFER_SIZE]),
+    SetSolidColor(Rgb),
+    SendNec {
+        address: u8,
+        command: u8,
+        repeat: bool,
+    },
+    /// Missing bands are zero, extra bands are dropped
+    SetAudioLevels {
+        bands: [u8; AUDIO_BANDS],
+        beat: bool,
+    },
+    SetMidiMapping {
+        short_press: MidiEvent,
+        long_press: MidiEvent,
+        ir_received: MidiEvent,
+    },
+    SetScene(u8),
+    SetLogConfig(LogConfig),
+    RebootToBootloader,
+    Reboot,
+    GetInfo,
+    SetIrBindings(IrBindings),
+    ResetIrBindings,
+    LearnIrBinding(IrAction),
+    /// None for the usual carrier of the protocol
+    SendIr(IrCommand, Option<IrCarrier>),
+    CaptureIrRaw,
+    /// None for 38 kHz
+    SendIrRaw(IrRaw, Option<IrCarrier>),
+    SendBadgeMessage(BadgeMessage),
+    GetContacts,
+    SetRelay(bool),
+    SetButtonAction(IrAction),
+}
+
+/// A decoded `BadgeBound` message
+#[derive(Clone, Debug, PartialEq)]
+pub struct Message {
+    /// `PROTOCOL_VERSION` of the host, 0 for hosts older than the field
+    pub protocol_version: u16,
+    pub command: Command,
+}
+
+/// Decodes one `BadgeBound` message, `data` is moved past it
+///
+/// An incomplete message fails with `MessageEndsPrematurely`, the caller can wait for more bytes.
+/// Nothing in `data` can make it panic, it's fuzzed on the host (see `fuzz/`). There are no
+/// error messages without an allocator: a frame buffer too short or what does not fit in the
+/// fixed size buffers fails with a plain `Failed` error.
+pub fn deserialize_message(data: &mut &[u8]) -> Result<Message, capnp::Error> {
+    let reader = serialize::read_message_from_flat_slice_no_alloc(data, ReaderOptions::new())?;
+    let badgebound = reader.get_root::<badge_bound::Reader>()?;
+
+    let command = match badgebound.which()? {
+        badge_bound::SetFrameBuffer(set_fb) => {
+            let pixels = set_fb?.get_pixels()?;
+            // get() panics past the end of the list
+            if pixels.len() < FRAME_BUFFER_SIZE as u32 {
+                return Err(failed());
+            }
+
+            let mut ret = [Rgb::default(); FRAME_BUFFER_SIZE];
+            for (i, pixel) in ret.iter_mut().enumerate() {
+                *pixel = read_rgb(pixels.get(i as u32));
+            }
+
+            Command::SetFrameBuffer(ret)
+        }
+        badge_bound::SetSolidColor(color) => Command::SetSolidColor(read_rgb(color?)),
+        badge_bound::SendNecCommand(nec) => {
+            let nec = nec?;
+
+            Command::SendNec {
+                address: nec.get_address(),
+                command: nec.get_command(),
+                repeat: nec.get_repeat(),
+            }
+        }
+        badge_bound::SetAudioLevels(levels) => {
+            let levels = levels?;
+
+            let mut bands = [0; AUDIO_BANDS];
+            for (i, band) in levels.get_bands()?.iter().take(AUDIO_BANDS).enumerate() {
+                bands[i] = band;
+            }
+
+            Command::SetAudioLevels {
+                bands,
+                beat: levels.get_beat(),
+            }
+        }
+        badge_bound::SetMidiMapping(mapping) => {
+            let mapping = mapping?;
+
+            Command::SetMidiMapping {
+                short_press: read_midi_event(mapping.get_short_press()?)?,
+                long_press: read_midi_event(mapping.get_long_press()?)?,
+                ir_received: read_midi_event(mapping.get_ir_received()?)?,
+            }
+        }
+        badge_bound::SetScene(scene) => Command::SetScene(scene),
+        badge_bound::SetLogConfig(config) => {
+            let config = config?;
+
+            let mut modules = Vec::new();
+            for module in config.get_modules()?.iter() {
+                let mut name = String::new();
+                name.push_str(module.get_module()?.to_str()?)
+                    .map_err(|_| failed())?;
+
+                modules
+                    .push((name, module.get_level()?))
+                    .map_err(|_| failed())?;
+            }
+
+            Command::SetLogConfig(LogConfig {
+                level: config.get_level()?,
+                modules,
+                format: config.get_format()?,
+            })
+        }
+        badge_bound::RebootToBootloader(()) => Command::RebootToBootloader,
+        badge_bound::Reboot(()) => Command::Reboot,
+        badge_bound::GetInfo(()) => Command::GetInfo,
+        badge_bound::SetIrBindings(bindings) => {
+            Command::SetIrBindings(read_ir_bindings(bindings?)?)
+        }
+        badge_bound::ResetIrBindings(()) => Command::ResetIrBindings,
+        badge_bound::LearnIrBinding(action) => Command::LearnIrBinding(read_ir_action(action?)?),
+        badge_bound::SendIrCommand(command) => {
+            let command = command?;
+            let carrier = if command.has_carrier() {
+                Some(read_ir_carrier(command.get_carrier()?)?)
+            } else {
+                None
+            };
+
+            Command::SendIr(read_ir_command(command)?, carrier)
+        }
+        badge_bound::CaptureIrRaw(()) => Command::CaptureIrRaw,
+        badge_bound::SendIrRaw(raw) => {
+            let raw = raw?;
+            let carrier = if raw.has_carrier() {
+                Some(read_ir_carrier(raw.get_carrier()?)?)
+            } else {
+                None
+            };
+
+            Command::SendIrRaw(read_ir_raw(raw)?, carrier)
+        }
+        badge_bound::SendBadgeMessage(message) => {
+            Command::SendBadgeMessage(read_badge_message(message?)?)
+        }
+        badge_bound::GetContacts(()) => Command::GetContacts,
+        badge_bound::SetRelay(relay) => Command::SetRelay(relay),
+        badge_bound::SetButtonAction(action) => {
+            Command::SetButtonAction(read_ir_action(action?)?)
+        }
+        badge_bound::Null(()) => Command::None,
+    };
+
+    Ok(Message {
+        protocol_version: badgebound.get_protocol_version(),
+        command,
+    })
+}
+
+fn read_rgb(color: usb_messages_capnp::r_g_b8::Reader) -> Rgb {
+    Rgb {
+        r: color.get_r(),
+        g: color.get_g(),
+        b: color.get_b(),
+    }
+}
+
+fn read_midi_event(event: midi_event::Reader) -> Result<MidiEvent, capnp::Error> {
+    Ok(MidiEvent {
+        kind: event.get_kind()?,
+        channel: event.get_channel(),
+        number: event.get_number(),
+        value: event.get_value(),
+    })
+}
+
+pub(crate) fn failed() -> capnp::Error {
+    capnp::Error::from_kind(capnp::ErrorKind::Failed)
+}
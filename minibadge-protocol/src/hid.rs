@@ -0,0 +1,52 @@
+//! The keys of a HID keyboard, for the texts the badge types
+
+/// Left shift in the modifier bits of a keyboard report
+pub const LEFT_SHIFT: u8 = 0x02;
+
+/// Modifier bits and usage id of a character on a US keyboard, None for the characters it has
+/// no key for
+pub fn ascii_key(c: u8) -> Option<(u8, u8)> {
+    let key = match c {
+        b'a'..=b'z' => (0, 0x04 + c - b'a'),
+        b'A'..=b'Z' => (LEFT_SHIFT, 0x04 + c - b'A'),
+        b'1'..=b'9' => (0, 0x1e + c - b'1'),
+        b'0' => (0, 0x27),
+        b'\n' => (0, 0x28),
+        b'\t' => (0, 0x2b),
+        b' ' => (0, 0x2c),
+        b'-' => (0, 0x2d),
+        b'=' => (0, 0x2e),
+        b'[' => (0, 0x2f),
+        b']' => (0, 0x30),
+        b'\\' => (0, 0x31),
+        b';' => (0, 0x33),
+        b'\'' => (0, 0x34),
+        b'`' => (0, 0x35),
+        b',' => (0, 0x36),
+        b'.' => (0, 0x37),
+        b'/' => (0, 0x38),
+        b'!' => (LEFT_SHIFT, 0x1e),
+        b'@' => (LEFT_SHIFT, 0x1f),
+        b'#' => (LEFT_SHIFT, 0x20),
+        b'$' => (LEFT_SHIFT, 0x21),
+        b'%' => (LEFT_SHIFT, 0x22),
+        b'^' => (LEFT_SHIFT, 0x23),
+        b'&' => (LEFT_SHIFT, 0x24),
+        b'*' => (LEFT_SHIFT, 0x25),
+        b'(' => (LEFT_SHIFT, 0x26),
+        b')' => (LEFT_SHIFT, 0x27),
+        b'_' => (LEFT_SHIFT, 0x2d),
+        b'+' => (LEFT_SHIFT, 0x2e),
+        b'{' => (LEFT_SHIFT, 0x2f),
+        b'}' => (LEFT_SHIFT, 0x30),
+        b'|' => (LEFT_SHIFT, 0x31),
+        b':' => (LEFT_SHIFT, 0x33),
+        b'"' => (LEFT_SHIFT, 0x34),
+        b'~' => (LEFT_SHIFT, 0x35),
+        b'<' => (LEFT_SHIFT, 0x36),
+        b'>' => (LEFT_SHIFT, 0x37),
+        b'?' => (LEFT_SHIFT, 0x38),
+        _ => return None,
+    };
+    Some(key)
+}
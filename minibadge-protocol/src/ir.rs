@@ -0,0 +1,373 @@
+use capnp::{
+    message::{self, ReaderOptions, SingleSegmentAllocator},
+    serialize,
+};
+use heapless::{String, Vec};
+
+use crate::decode::failed;
+use crate::usb_messages_capnp::{
+    badge_message, contacts, ir_action, ir_binding, ir_bindings, ir_carrier, ir_command, ir_raw,
+    IrProtocol, RepeatPolicy,
+};
+
+pub const MAX_IR_BINDINGS: usize = 32;
+pub const MACRO_LEN: usize = 8;
+/// Keys held together besides the modifiers, as many as a HID keyboard report has
+pub const CHORD_LEN: usize = 6;
+/// Longest text typed by an action
+pub const MAX_HID_TEXT: usize = 32;
+pub const MAX_IR_RAW: usize = 256;
+pub const MIN_CARRIER: u32 = 10_000;
+pub const MAX_CARRIER: u32 = 500_000;
+/// Longest text a badge beams to the others, the frame is already long at this size
+pub const MAX_BADGE_TEXT: usize = 16;
+/// Badges a badge remembers, they fit in one sector of flash and in one reply
+pub const MAX_CONTACTS: usize = 128;
+
+/// Room for a whole table of bindings, macros and texts included
+pub const IR_BINDINGS_SIZE: usize = 3072;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum IrAction {
+    None,
+    NextScene,
+    IncreaseBrightness,
+    DecreaseBrightness,
+    SelectScene(u8),
+    ResetTime,
+    GreetBadge,
+    /// HID keyboard usage id
+    HidKey(u8),
+    Macro(Vec<u8, MACRO_LEN>),
+    ShareScene,
+    /// modifier bits of the HID keyboard report, and the usage ids of the keys held with them
+    Chord(u8, Vec<u8, CHORD_LEN>),
+    /// HID consumer usage id
+    MediaKey(u16),
+    /// ascii only, typed with a US layout
+    TypeText(String<MAX_HID_TEXT>),
+}
+
+/// What the host asks the badge to tell the badges in sight
+#[derive(Clone, Debug, PartialEq)]
+pub enum BadgeMessage {
+    Hello,
+    ShareScene,
+    /// ascii only
+    Text(String<MAX_BADGE_TEXT>),
+    SyncTime,
+}
+
+/// A command of a remote, received or to send
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IrCommand {
+    pub protocol: IrProtocol,
+    pub address: u16,
+    pub command: u16,
+    pub repeat: bool,
+}
+
+/// What the led is modulated with while sending
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct IrCarrier {
+    /// Hz
+    pub frequency: u32,
+    /// percent of each period the led is on
+    pub duty_cycle: u8,
+}
+
+/// Microseconds of carrier and of silence in turn, starting with carrier
+pub type IrRaw = Vec<u16, MAX_IR_RAW>;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct IrBinding {
+    pub protocol: IrProtocol,
+    pub address: u16,
+    pub command: u16,
+    pub repeat: RepeatPolicy,
+    pub action: IrAction,
+}
+
+impl IrBinding {
+    /// The same button, whatever the repeat policy
+    pub fn button(&self, command: &IrCommand) -> bool {
+        self.protocol == command.protocol
+            && self.address == command.address
+            && self.command == command.command
+    }
+
+    pub fn matches(&self, command: &IrCommand) -> bool {
+        self.button(command) && (!command.repeat || self.repeat == RepeatPolicy::Repeat)
+    }
+}
+
+pub type IrBindings = Vec<IrBinding, MAX_IR_BINDINGS>;
+
+pub(crate) fn read_ir_bindings(reader: ir_bindings::Reader) -> Result<IrBindings, capnp::Error> {
+    let mut bindings = IrBindings::new();
+
+    for binding in reader.get_bindings()?.iter() {
+        let binding = IrBinding {
+            protocol: binding.get_protocol()?,
+            address: binding.get_address(),
+            command: binding.get_command(),
+            repeat: binding.get_repeat()?,
+            action: read_ir_action(binding.get_action()?)?,
+        };
+
+        bindings.push(binding).map_err(|_| failed())?;
+    }
+
+    Ok(bindings)
+}
+
+pub(crate) fn read_ir_action(reader: ir_action::Reader) -> Result<IrAction, capnp::Error> {
+    let action = match reader.which()? {
+        ir_action::None(()) => IrAction::None,
+        ir_action::NextScene(()) => IrAction::NextScene,
+        ir_action::IncreaseBrightness(()) => IrAction::IncreaseBrightness,
+        ir_action::DecreaseBrightness(()) => IrAction::DecreaseBrightness,
+        ir_action::SelectScene(scene) => IrAction::SelectScene(scene),
+        ir_action::ResetTime(()) => IrAction::ResetTime,
+        ir_action::GreetBadge(()) => IrAction::GreetBadge,
+        ir_action::HidKey(key) => IrAction::HidKey(key),
+        ir_action::Macro(keys) => {
+            let mut ret = Vec::new();
+            for key in keys?.iter() {
+                ret.push(key).map_err(|_| failed())?;
+            }
+
+            IrAction::Macro(ret)
+        }
+        ir_action::ShareScene(()) => IrAction::ShareScene,
+        ir_action::Chord(chord) => {
+            let chord = chord?;
+            let mut keys = Vec::new();
+            for key in chord.get_keys()?.iter() {
+                keys.push(key).map_err(|_| failed())?;
+            }
+
+            IrAction::Chord(chord.get_modifiers(), keys)
+        }
+        ir_action::MediaKey(usage) => IrAction::MediaKey(usage),
+        ir_action::TypeText(text) => {
+            let text = text?.to_str().map_err(|_| failed())?;
+            if !text.is_ascii() {
+                return Err(failed());
+            }
+
+            IrAction::TypeText(String::try_from(text).map_err(|_| failed())?)
+        }
+    };
+
+    Ok(action)
+}
+
+/// A badge met over infrared
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Contact {
+    pub badge: u32,
+    /// seconds of uptime
+    pub first_seen: u32,
+    pub count: u16,
+}
+
+pub(crate) fn read_badge_message(
+    reader: badge_message::Reader,
+) -> Result<BadgeMessage, capnp::Error> {
+    let message = match reader.which()? {
+        badge_message::Hello(()) => BadgeMessage::Hello,
+        badge_message::ShareScene(()) => BadgeMessage::ShareScene,
+        badge_message::Text(text) => {
+            let text = text?.to_str()?;
+            if !text.is_ascii() {
+                return Err(failed());
+            }
+
+            let mut ret = String::new();
+            ret.push_str(text).map_err(|_| failed())?;
+            BadgeMessage::Text(ret)
+        }
+        badge_message::SyncTime(()) => BadgeMessage::SyncTime,
+    };
+
+    Ok(message)
+}
+
+pub(crate) fn read_ir_command(reader: ir_command::Reader) -> Result<IrCommand, capnp::Error> {
+    Ok(IrCommand {
+        protocol: reader.get_protocol()?,
+        address: reader.get_address(),
+        command: reader.get_command(),
+        repeat: reader.get_repeat(),
+    })
+}
+
+pub(crate) fn read_ir_raw(reader: ir_raw::Reader) -> Result<IrRaw, capnp::Error> {
+    let mut durations = IrRaw::new();
+
+    for duration in reader.get_durations()?.iter() {
+        durations.push(duration).map_err(|_| failed())?;
+    }
+
+    Ok(durations)
+}
+
+pub(crate) fn read_ir_carrier(reader: ir_carrier::Reader) -> Result<IrCarrier, capnp::Error> {
+    let carrier = IrCarrier {
+        frequency: reader.get_frequency(),
+        duty_cycle: reader.get_duty_cycle(),
+    };
+
+    if !(MIN_CARRIER..=MAX_CARRIER).contains(&carrier.frequency)
+        || !(1..=99).contains(&carrier.duty_cycle)
+    {
+        return Err(failed());
+    }
+
+    Ok(carrier)
+}
+
+/// Fills the builder with the carrier of `sendIrCommand` or `sendIrRaw`
+pub fn write_ir_carrier(mut builder: ir_carrier::Builder, carrier: &IrCarrier) {
+    builder.set_frequency(carrier.frequency);
+    builder.set_duty_cycle(carrier.duty_cycle);
+}
+
+/// Fills the builder with the timings, for `sendIrRaw` and the `irRaw` reply
+pub fn write_ir_raw(builder: ir_raw::Builder, durations: &[u16]) {
+    let mut list = builder.init_durations(durations.len() as u32);
+    for (i, duration) in durations.iter().enumerate() {
+        list.set(i as u32, *duration);
+    }
+}
+
+/// Fills the builder with the command, the host uses it for `sendIrCommand`
+pub fn write_ir_command(mut builder: ir_command::Builder, command: &IrCommand) {
+    builder.set_protocol(command.protocol);
+    builder.set_address(command.address);
+    builder.set_command(command.command);
+    builder.set_repeat(command.repeat);
+}
+
+/// Fills the builder with the bindings, the host uses it for `setIrBindings`
+pub fn write_ir_bindings(builder: ir_bindings::Builder, bindings: &[IrBinding]) {
+    let mut list = builder.init_bindings(bindings.len() as u32);
+
+    for (i, binding) in bindings.iter().enumerate() {
+        write_ir_binding(list.reborrow().get(i as u32), binding);
+    }
+}
+
+fn write_ir_binding(mut builder: ir_binding::Builder, binding: &IrBinding) {
+    builder.set_protocol(binding.protocol);
+    builder.set_address(binding.address);
+    builder.set_command(binding.command);
+    builder.set_repeat(binding.repeat);
+
+    write_ir_action(builder.init_action(), &binding.action);
+}
+
+/// Fills the builder with the action, the host uses it for `learnIrBinding`
+pub fn write_ir_action(mut builder: ir_action::Builder, action: &IrAction) {
+    match action {
+        IrAction::None => builder.set_none(()),
+        IrAction::NextScene => builder.set_next_scene(()),
+        IrAction::IncreaseBrightness => builder.set_increase_brightness(()),
+        IrAction::DecreaseBrightness => builder.set_decrease_brightness(()),
+        IrAction::SelectScene(scene) => builder.set_select_scene(*scene),
+        IrAction::ResetTime => builder.set_reset_time(()),
+        IrAction::GreetBadge => builder.set_greet_badge(()),
+        IrAction::HidKey(key) => builder.set_hid_key(*key),
+        IrAction::Macro(keys) => {
+            let mut list = builder.init_macro(keys.len() as u32);
+            for (i, key) in keys.iter().enumerate() {
+                list.set(i as u32, *key);
+            }
+        }
+        IrAction::ShareScene => builder.set_share_scene(()),
+        IrAction::Chord(modifiers, keys) => {
+            let mut chord = builder.init_chord();
+            chord.set_modifiers(*modifiers);
+            let mut list = chord.init_keys(keys.len() as u32);
+            for (i, key) in keys.iter().enumerate() {
+                list.set(i as u32, *key);
+            }
+        }
+        IrAction::MediaKey(usage) => builder.set_media_key(*usage),
+        IrAction::TypeText(text) => builder.set_type_text(text.as_str()),
+    }
+}
+
+/// Fills the builder with the contacts, for the `contacts` reply
+pub fn write_contacts(builder: contacts::Builder, contacts: &[Contact]) {
+    let mut list = builder.init_contacts(contacts.len() as u32);
+
+    for (i, contact) in contacts.iter().enumerate() {
+        let mut entry = list.reborrow().get(i as u32);
+        entry.set_badge(contact.badge);
+        entry.set_first_seen(contact.first_seen);
+        entry.set_count(contact.count);
+    }
+}
+
+/// Fills the builder with the message, the host uses it for `sendBadgeMessage`
+pub fn write_badge_message(mut builder: badge_message::Builder, message: &BadgeMessage) {
+    match message {
+        BadgeMessage::Hello => builder.set_hello(()),
+        BadgeMessage::ShareScene => builder.set_share_scene(()),
+        BadgeMessage::Text(text) => builder.set_text(text.as_str()),
+        BadgeMessage::SyncTime => builder.set_sync_time(()),
+    }
+}
+
+/// The builder wants its segment aligned to words
+#[repr(C, align(8))]
+struct Segment([u8; IR_BINDINGS_SIZE]);
+
+/// Writes the bindings as an `IrBindings` message, the badge keeps it in flash
+///
+/// Returns the length of the message, `out` should be `IR_BINDINGS_SIZE` long
+pub fn serialize_ir_bindings(
+    bindings: &[IrBinding],
+    out: &mut [u8],
+) -> Result<usize, capnp::Error> {
+    let mut segment = Segment([0; IR_BINDINGS_SIZE]);
+    let mut builder = message::Builder::new(SingleSegmentAllocator::new(&mut segment.0));
+
+    write_ir_bindings(builder.init_root::<ir_bindings::Builder>(), bindings);
+
+    let size = out.len();
+    let mut writer = &mut out[..];
+    serialize::write_message(&mut writer, &builder)?;
+
+    Ok(size - writer.len())
+}
+
+/// Reads what `serialize_ir_bindings` wrote, `data` must be aligned to 8 bytes
+pub fn deserialize_ir_bindings(data: &mut &[u8]) -> Result<IrBindings, capnp::Error> {
+    let reader = serialize::read_message_from_flat_slice_no_alloc(data, ReaderOptions::new())?;
+
+    read_ir_bindings(reader.get_root::<ir_bindings::Reader>()?)
+}
+
+/// Writes a single action as an `IrAction` message, like the one of the button of the badge
+pub fn serialize_ir_action(action: &IrAction, out: &mut [u8]) -> Result<usize, capnp::Error> {
+    let mut segment = Segment([0; IR_BINDINGS_SIZE]);
+    let mut builder = message::Builder::new(SingleSegmentAllocator::new(&mut segment.0));
+
+    write_ir_action(builder.init_root::<ir_action::Builder>(), action);
+
+    let size = out.len();
+    let mut writer = &mut out[..];
+    serialize::write_message(&mut writer, &builder)?;
+
+    Ok(size - writer.len())
+}
+
+/// Reads what `serialize_ir_action` wrote, `data` must be aligned to 8 bytes
+pub fn deserialize_ir_action(data: &mut &[u8]) -> Result<IrAction, capnp::Error> {
+    let reader = serialize::read_message_from_flat_slice_no_alloc(data, ReaderOptions::new())?;
+
+    read_ir_action(reader.get_root::<ir_action::Reader>()?)
+}
@@ -0,0 +1,22 @@
+//! The messages between the badge and the host, shared by the firmware and `minibadge-cli`
+//!
+//! The schema lives only here, both sides use the same generated code and the same
+//! `PROTOCOL_VERSION`. The decoder is `no_std` and needs no allocator, so it runs on the badge
+//! and on the host alike.
+#![no_std]
+
+mod buffer;
+mod decode;
+mod hid;
+mod ir;
+
+pub use buffer::{deserialize_unaligned, MessageBuffer, Messages};
+pub use decode::*;
+pub use hid::*;
+pub use ir::*;
+
+pub mod usb_messages_capnp {
+    include!(concat!(env!("OUT_DIR"), "/usb_messages_capnp.rs"));
+}
+
+pub use usb_messages_capnp::PROTOCOL_VERSION;
@@ -0,0 +1,152 @@
+//! The badge decodes untrusted bytes from USB: whatever they are, the decoder must return
+//! an error instead of panicking
+
+use capnp::{message::Builder, serialize};
+// the reader rejects unaligned messages, and a Vec<u8> makes no promise
+use minibadge_protocol::{
+    deserialize_unaligned as decode, usb_messages_capnp::badge_bound, Command, Message,
+    MessageBuffer, Rgb, FRAME_BUFFER_SIZE,
+};
+use proptest::prelude::*;
+
+fn frame_buffer(len: usize) -> Vec<u8> {
+    let mut message = Builder::new_default();
+    let badgebound = message.init_root::<badge_bound::Builder>();
+
+    let mut pixels = badgebound.init_set_frame_buffer().init_pixels(len as u32);
+    for i in 0..len {
+        pixels.reborrow().get(i as u32).set_r(i as u8);
+    }
+
+    serialize::write_message_to_words(&message)
+}
+
+#[test]
+fn short_frame_buffer() {
+    for len in 0..FRAME_BUFFER_SIZE {
+        assert!(decode(&frame_buffer(len)).is_err(), "{} pixels", len);
+    }
+
+    let Command::SetFrameBuffer(pixels) = decode(&frame_buffer(12)).unwrap().command else {
+        panic!("not a frame buffer");
+    };
+    assert_eq!(pixels[8], Rgb { r: 8, g: 0, b: 0 });
+}
+
+#[test]
+fn too_large() {
+    let mut buffer = MessageBuffer::<16>::new();
+
+    let decoded: Vec<_> = buffer.push(&frame_buffer(FRAME_BUFFER_SIZE)).collect();
+    assert!(matches!(
+        decoded[..],
+        [Err(capnp::Error {
+            kind: capnp::ErrorKind::MessageTooLarge(16),
+            ..
+        })]
+    ));
+    assert!(buffer.is_empty());
+}
+
+fn scene(scene: u8) -> Vec<u8> {
+    let mut message = Builder::new_default();
+    let mut badgebound = message.init_root::<badge_bound::Builder>();
+    badgebound.set_set_scene(scene);
+
+    serialize::write_message_to_words(&message)
+}
+
+/// Splits the stream in packets of the given sizes, over and over, and decodes it
+fn decode_stream<const N: usize>(
+    stream: &[u8],
+    packet_sizes: &[usize],
+) -> Vec<Result<Message, capnp::Error>> {
+    let mut buffer = MessageBuffer::<N>::new();
+    let mut decoded = vec![];
+
+    let mut rest = stream;
+    for size in packet_sizes.iter().cycle() {
+        let (packet, next) = rest.split_at((*size).min(rest.len()));
+        decoded.extend(buffer.push(packet));
+        rest = next;
+        if rest.is_empty() {
+            break;
+        }
+    }
+    assert!(buffer.is_empty());
+
+    decoded
+}
+
+proptest! {
+    #[test]
+    fn random_bytes(bytes in prop::collection::vec(any::<u8>(), 0..512)) {
+        let _ = decode(&bytes);
+    }
+
+    /// Valid messages with some bytes changed go further than random ones
+    #[test]
+    fn corrupted_message(
+        len in 0..16usize,
+        changes in prop::collection::vec((any::<prop::sample::Index>(), any::<u8>()), 1..8),
+    ) {
+        let mut message = frame_buffer(len);
+        for (index, value) in changes {
+            *index.get_mut(&mut message) = value;
+        }
+
+        let _ = decode(&message);
+    }
+
+    #[test]
+    fn truncated_message(len in 0..16usize, cut in any::<prop::sample::Index>()) {
+        let message = frame_buffer(len);
+        let cut = cut.index(message.len());
+
+        prop_assert!(decode(&message[..cut]).is_err());
+    }
+
+    /// The message is the same, however the packets are split
+    #[test]
+    fn split_in_packets(packet_size in 1..=64usize) {
+        let message = frame_buffer(FRAME_BUFFER_SIZE);
+        let mut decoded = decode_stream::<256>(&message, &[packet_size]);
+
+        prop_assert_eq!(decoded.len(), 1);
+        prop_assert_eq!(decoded.remove(0).unwrap(), decode(&message).unwrap());
+    }
+
+    /// Two messages back to back come out in order, even when a packet ends one and starts the
+    /// other or holds both
+    #[test]
+    fn two_messages_in_packets(
+        first in 0..=FRAME_BUFFER_SIZE,
+        second in any::<u8>(),
+        packet_sizes in prop::collection::vec(1..=200usize, 1..8),
+    ) {
+        let messages = [frame_buffer(first), scene(second)];
+        let decoded = decode_stream::<256>(&messages.concat(), &packet_sizes);
+
+        prop_assert_eq!(decoded.len(), 2);
+        for (decoded, message) in decoded.into_iter().zip(&messages) {
+            prop_assert_eq!(decoded.ok(), decode(message).ok());
+        }
+    }
+
+    /// The rest of a message too large for the buffer is dropped, and the next one decodes.
+    /// The buffer has room for a scene, 120 bytes, and not for a frame buffer
+    #[test]
+    fn after_too_large(packet_sizes in prop::collection::vec(1..=64usize, 1..8)) {
+        let message = scene(3);
+        let stream = [frame_buffer(FRAME_BUFFER_SIZE), message.clone()].concat();
+        let decoded = decode_stream::<128>(&stream, &packet_sizes);
+
+        prop_assert_eq!(decoded.len(), 2);
+        let too_large = matches!(
+            decoded[0],
+            Err(capnp::Error { kind: capnp::ErrorKind::MessageTooLarge(128), .. })
+        );
+        prop_assert!(too_large);
+        prop_assert_eq!(decoded[1].as_ref().unwrap(), &decode(&message).unwrap());
+    }
+}
@@ -0,0 +1,64 @@
+//! The badge types texts as on a US keyboard, one key and its modifiers per character
+
+use minibadge_protocol::{ascii_key, LEFT_SHIFT};
+
+#[test]
+fn letters() {
+    assert_eq!(ascii_key(b'a'), Some((0, 0x04)));
+    assert_eq!(ascii_key(b'z'), Some((0, 0x1d)));
+    assert_eq!(ascii_key(b'A'), Some((LEFT_SHIFT, 0x04)));
+    assert_eq!(ascii_key(b'Z'), Some((LEFT_SHIFT, 0x1d)));
+}
+
+#[test]
+fn digits() {
+    assert_eq!(ascii_key(b'1'), Some((0, 0x1e)));
+    assert_eq!(ascii_key(b'9'), Some((0, 0x26)));
+    // the 0 comes after the 9 on the keyboard
+    assert_eq!(ascii_key(b'0'), Some((0, 0x27)));
+}
+
+/// The shifted characters share the key of the one below them
+#[test]
+fn shifted() {
+    let pairs = [
+        (b'!', b'1'),
+        (b'@', b'2'),
+        (b')', b'0'),
+        (b'_', b'-'),
+        (b'+', b'='),
+        (b'{', b'['),
+        (b'|', b'\\'),
+        (b':', b';'),
+        (b'"', b'\''),
+        (b'~', b'`'),
+        (b'<', b','),
+        (b'>', b'.'),
+        (b'?', b'/'),
+    ];
+    for (shifted, plain) in pairs {
+        let (modifiers, key) = ascii_key(plain).unwrap();
+        assert_eq!(modifiers, 0, "{}", plain as char);
+        assert_eq!(
+            ascii_key(shifted),
+            Some((LEFT_SHIFT, key)),
+            "{}",
+            shifted as char
+        );
+    }
+}
+
+#[test]
+fn no_key() {
+    for c in [b'\0', b'\r', 0x1b, 0x7f, 0x80, 0xff] {
+        assert_eq!(ascii_key(c), None, "{:#04x}", c);
+    }
+}
+
+/// Every printable character has a key
+#[test]
+fn printable() {
+    for c in b' '..=b'~' {
+        assert!(ascii_key(c).is_some(), "{}", c as char);
+    }
+}